@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use mc5_core::{
+    config::MangoChainsawConfig, label::Label, mango::MangoChainsaw, mclabel, mclabels,
+};
+
+/// Insert cost for one more document carrying a label that is already on `n` documents.
+/// With one posting key per (label, document) this should stay flat as `n` grows.
+fn insert_hot_label(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_hot_label");
+    for n in [100, 1_000, 10_000, 50_000] {
+        let db = MangoChainsaw::new(MangoChainsawConfig {
+            reaper_interval: 0,
+            ..Default::default()
+        })
+        .expect("open db");
+        let bucket = db.get_or_create_bucket("bench").expect("open bucket");
+        let batch = (0..n)
+            .map(|i: usize| (i.to_le_bytes().to_vec(), mclabels!("code_file" => "true")))
+            .collect();
+        bucket.insert_many(batch).expect("populate bucket");
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.iter_batched(
+                || mclabels!("code_file" => "true", "filetype" => "rust_code"),
+                |labels| {
+                    bucket
+                        .insert(b"fn main() {}".to_vec(), labels)
+                        .expect("insert")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert_hot_label);
+criterion_main!(benches);
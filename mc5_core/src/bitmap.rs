@@ -0,0 +1,143 @@
+use crate::errors::MangoChainsawError;
+use crate::storage::{Batch, TransactionalTree, Tree};
+use roaring::RoaringBitmap;
+use sled::transaction::UnabortableTransactionError;
+use std::collections::BTreeMap;
+
+/// Label bytes of the bitmap holding every document in the bucket.
+/// Real labels are never empty, so this can't collide with one.
+pub(crate) const UNIVERSE: &[u8] = b"";
+
+/// Separates the label from the chunk number in bitmap keys
+const CHUNK_SEPARATOR: u8 = 0xFF;
+
+/// Ordinals are split into chunks of 2^16 so that updating a label
+/// only rewrites the part of its bitmap that holds the document
+const CHUNK_BITS: u32 = 16;
+
+/// Encode an ordinal as a big-endian key for the `{name}::ordinal_ids` tree
+pub(crate) fn ordinal_key(ordinal: u32) -> [u8; 4] {
+    ordinal.to_be_bytes()
+}
+
+/// Decode a big-endian ordinal from the `{name}::ordinals` tree
+pub(crate) fn decode_ordinal(raw: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&raw[..4]);
+    u32::from_be_bytes(bytes)
+}
+
+/// The prefix shared by every chunk of a label's bitmap
+fn chunk_prefix(label: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(label.len() + 3);
+    key.extend_from_slice(label);
+    key.push(CHUNK_SEPARATOR);
+    key
+}
+
+/// Bitmap keys are the label bytes, a separator and the big-endian chunk number
+fn chunk_key(label: &[u8], chunk: u16) -> Vec<u8> {
+    let mut key = chunk_prefix(label);
+    key.extend_from_slice(&chunk.to_be_bytes());
+    key
+}
+
+/// Group ordinals by the chunk that holds them
+fn by_chunk(ordinals: &[u32]) -> BTreeMap<u16, Vec<u32>> {
+    let mut chunks: BTreeMap<u16, Vec<u32>> = BTreeMap::new();
+    for ordinal in ordinals {
+        chunks
+            .entry((ordinal >> CHUNK_BITS) as u16)
+            .or_default()
+            .push(*ordinal);
+    }
+    chunks
+}
+
+fn encode(bitmap: &RoaringBitmap) -> Result<Vec<u8>, std::io::Error> {
+    let mut raw = Vec::with_capacity(bitmap.serialized_size());
+    bitmap.serialize_into(&mut raw)?;
+    Ok(raw)
+}
+
+fn decode(raw: &[u8]) -> Result<RoaringBitmap, std::io::Error> {
+    RoaringBitmap::deserialize_from(raw)
+}
+
+fn storage_error(e: std::io::Error) -> UnabortableTransactionError {
+    UnabortableTransactionError::Storage(sled::Error::ReportableBug(e.to_string()))
+}
+
+/// Add ordinals to a label's bitmap, rewriting each affected chunk once
+pub(crate) fn insert(
+    t: &dyn TransactionalTree,
+    label: &[u8],
+    ordinals: &[u32],
+) -> Result<(), UnabortableTransactionError> {
+    for (chunk, ordinals) in by_chunk(ordinals) {
+        let key = chunk_key(label, chunk);
+        let mut bitmap = match t.get(&key)? {
+            Some(raw) => decode(&raw).map_err(storage_error)?,
+            None => RoaringBitmap::new(),
+        };
+        bitmap.extend(ordinals);
+        t.insert(key, encode(&bitmap).map_err(storage_error)?)?;
+    }
+    Ok(())
+}
+
+/// Remove ordinals from a label's bitmap, dropping chunks that become empty
+pub(crate) fn remove(
+    t: &dyn TransactionalTree,
+    label: &[u8],
+    ordinals: &[u32],
+) -> Result<(), UnabortableTransactionError> {
+    for (chunk, ordinals) in by_chunk(ordinals) {
+        let key = chunk_key(label, chunk);
+        let Some(raw) = t.get(&key)? else {
+            continue;
+        };
+        let mut bitmap = decode(&raw).map_err(storage_error)?;
+        for ordinal in ordinals {
+            bitmap.remove(ordinal);
+        }
+        if bitmap.is_empty() {
+            t.remove(key)?;
+        } else {
+            t.insert(key, encode(&bitmap).map_err(storage_error)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Load the full bitmap for a label. A label that doesn't exist has an empty bitmap.
+pub(crate) fn load(tree: &Tree, label: &[u8]) -> Result<RoaringBitmap, MangoChainsawError> {
+    load_prefix(tree, &chunk_prefix(label))
+}
+
+/// Load the union of the bitmaps of every label whose bytes start with `prefix`
+pub(crate) fn load_prefix(tree: &Tree, prefix: &[u8]) -> Result<RoaringBitmap, MangoChainsawError> {
+    let mut bitmap = RoaringBitmap::new();
+    for result in tree.scan_prefix(prefix).values() {
+        bitmap |= decode(&result?)?;
+    }
+    Ok(bitmap)
+}
+
+/// Write whole bitmaps outside of a transaction, as a migration does
+pub(crate) fn write_all(
+    tree: &Tree,
+    bitmaps: BTreeMap<Vec<u8>, RoaringBitmap>,
+) -> Result<(), MangoChainsawError> {
+    let mut batch = Batch::default();
+    for (label, bitmap) in bitmaps {
+        for (chunk, ordinals) in by_chunk(&bitmap.into_iter().collect::<Vec<_>>()) {
+            let chunk_bitmap = RoaringBitmap::from_sorted_iter(ordinals).map_err(|e| {
+                MangoChainsawError::Etc(format!("unsorted ordinals in bitmap chunk: {e}"))
+            })?;
+            batch.insert(chunk_key(&label, chunk), encode(&chunk_bitmap)?);
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(())
+}
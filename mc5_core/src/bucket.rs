@@ -1,35 +1,686 @@
+use crate::bitmap::{self, decode_ordinal, ordinal_key, UNIVERSE};
+use crate::codec::{decode_body, encode_body, raw_body, split_body, Codec, CodecKind, Raw};
+use crate::config::IdStrategy;
+use crate::dedup::{self, Digest, Hasher};
+use crate::label::LabelValue;
+use crate::large::{self, LargeObjectWriter, Lease, Manifest, RawStream, WriteTarget};
+use crate::metadata::{to_hex, DocumentMetadata};
+use crate::page::{Cursor, CursorKind, KeyPage, Page};
+use crate::query::Query;
+use crate::quota::{self, Usage};
+use crate::range;
+use crate::schema::{BodySchema, BodyValidator, LabelSchema, Validators};
+use crate::settings::{BucketSettings, Quota};
+use crate::storage::{Batch, TransactionalTree, Tree};
 use crate::{errors::MangoChainsawError, label::Label, mango::MangoChainsaw};
+use roaring::RoaringBitmap;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use sled::transaction::UnabortableTransactionError;
-use sled::Transactional;
-use std::{cell::RefCell, collections::HashMap};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, UnabortableTransactionError,
+};
+use sled::IVec;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{error, info, instrument, warn};
-use uuid::Uuid;
+use uuid::{NoContext, Timestamp, Uuid};
+
+/// Size of the revision header stored in front of every document body
+const REVISION_LEN: usize = 8;
+
+/// Prefix a serialized document with its revision
+fn pack_document(rev: u64, body: &[u8]) -> IVec {
+    let mut raw = Vec::with_capacity(REVISION_LEN + body.len());
+    raw.extend_from_slice(&rev.to_be_bytes());
+    raw.extend_from_slice(body);
+    IVec::from(raw)
+}
+
+/// Split a serialized body into the codec that wrote it and the encoded bytes, without copying
+fn untag_body(body: IVec) -> Result<(CodecKind, IVec), MangoChainsawError> {
+    let codec = split_body(&body)?.0;
+    Ok((codec, body.subslice(1, body.len() - 1)))
+}
+
+/// Split a stored document into its revision and serialized body
+fn unpack_document(raw: IVec) -> Result<(u64, IVec), MangoChainsawError> {
+    if raw.len() < REVISION_LEN {
+        return Err(MangoChainsawError::Etc(format!(
+            "document is too short to hold a revision ({} bytes)",
+            raw.len()
+        )));
+    }
+    Ok((
+        decode_u64(&raw),
+        raw.subslice(REVISION_LEN, raw.len() - REVISION_LEN),
+    ))
+}
+
+/// Key that held the bucket settings in the `{name}::settings` tree, before they moved to the
+/// bucket registry
+const SETTINGS_KEY: &[u8] = b"settings";
+
+/// Key holding the on-disk format version in the `{name}::settings` tree
+const FORMAT_KEY: &[u8] = b"format";
+
+/// Current on-disk format version. Buckets written by older versions are migrated on open.
+///
+/// 0. The original format: bare documents and one id list per label
+/// 1. Revision headers on documents, and one `kev`/`vek` posting key per (label, document)
+/// 2. Document ordinals, and a compressed bitmap of ordinals per label
+/// 3. A range index of typed label values
+/// 4. Documents keyed by their id's bytes rather than a flexbuffer, so they sort by creation time
+/// 5. Document bodies lead with the tag of the codec that wrote them
+/// 6. A metadata record per document
+/// 7. Settings kept in the bucket registry
+/// 8. Usage totals and per-label document counts, for quotas
+/// 9. Integers and floats share one ordering in the range index
+/// 10. The id strategy kept in the bucket's settings
+const FORMAT_VERSION: u64 = 10;
+
+/// Key holding the next unused document ordinal in the `{name}::settings` tree
+const ORDINAL_KEY: &[u8] = b"next_ordinal";
+
+/// Suffixes of the trees that make up a bucket, each named `{name}::{suffix}`
+const TREES: [&str; 20] = [
+    "doc",
+    "kev",
+    "vek",
+    "labels",
+    "history",
+    "settings",
+    "ttl",
+    "expiry",
+    "bitmaps",
+    "range",
+    "ordinals",
+    "ordinal_ids",
+    "keys",
+    "key_names",
+    "chunks",
+    "blobs",
+    "blob_refs",
+    "meta",
+    "usage",
+    "orphans",
+];
+
+/// Length of a document id in keys
+const ID_LEN: usize = 16;
+
+/// Separates the label from the document id in posting keys.
+/// Labels are UTF-8, which never contains this byte.
+const POSTING_SEPARATOR: u8 = 0xFF;
+
+/// Length of the separator and id at the end of every posting key
+const POSTING_SUFFIX_LEN: usize = 17;
+
+/// The prefix shared by every posting key for a label
+fn posting_prefix(label: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(label.len() + POSTING_SUFFIX_LEN);
+    key.extend_from_slice(label);
+    key.push(POSTING_SEPARATOR);
+    key
+}
+
+/// Posting keys are the label bytes, a separator and the document id
+fn posting_key(label: &[u8], id: Uuid) -> Vec<u8> {
+    let mut key = posting_prefix(label);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// Split a posting key into its label bytes and document id.
+/// Returns None for keys in the legacy one-list-per-label format.
+fn split_posting_key(key: &[u8]) -> Option<(&[u8], Uuid)> {
+    if key.len() < POSTING_SUFFIX_LEN || key[key.len() - POSTING_SUFFIX_LEN] != POSTING_SEPARATOR {
+        return None;
+    }
+    let (label, suffix) = key.split_at(key.len() - POSTING_SUFFIX_LEN);
+    Some((label, Uuid::from_slice(&suffix[1..]).ok()?))
+}
+
+/// A prior version of a document kept in the `{name}::history` tree
+#[derive(Serialize, Deserialize)]
+struct Version {
+    labels: Vec<Label>,
+    #[serde(with = "serde_bytes")]
+    raw: Vec<u8>,
+
+    /// The key the document was stored under, recorded when it is deleted
+    /// so that restoring it can bring the key back
+    #[serde(default, with = "serde_bytes")]
+    key: Option<Vec<u8>>,
+}
+
+/// History keys are the id followed by the big-endian revision, so versions sort oldest first
+fn version_key(id: Uuid, rev: u64) -> Vec<u8> {
+    let mut key = id.as_bytes().to_vec();
+    key.extend_from_slice(&rev.to_be_bytes());
+    key
+}
+
+/// Milliseconds since the unix epoch, the resolution used for document expiry
+fn now_millis() -> Result<u64, MangoChainsawError> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+/// Decode a big-endian u64, such as an expiry time or a revision
+fn decode_u64(raw: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&raw[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Document keys are the id's bytes. Generated ids lead with their timestamp,
+/// so documents sort by creation time in every tree keyed by document.
+fn document_key(id: Uuid) -> IVec {
+    IVec::from(id.as_bytes())
+}
+
+/// Get the id back from a document key
+fn document_id(key: &[u8]) -> Result<Uuid, MangoChainsawError> {
+    Uuid::from_slice(key)
+        .map_err(|e| MangoChainsawError::Etc(format!("invalid document key {key:?}: {e}")))
+}
+
+/// The smallest document key for an id created at `t` by `strategy`. v6 uuids have a 100ns
+/// resolution, v7 uuids and ULIDs lead with a 48 bit count of milliseconds.
+fn created_at_key(strategy: IdStrategy, t: SystemTime) -> IVec {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match strategy {
+        IdStrategy::V7 | IdStrategy::Ulid => {
+            let millis = since_epoch.as_millis().min((1 << 48) - 1);
+            document_key(Uuid::from_u128(millis << 80))
+        }
+        IdStrategy::V6 | IdStrategy::Provided => {
+            let secs = since_epoch.as_secs();
+            let ts = Timestamp::from_unix(NoContext, secs, since_epoch.subsec_nanos());
+            document_key(Uuid::new_v6(ts, &[0; 6]))
+        }
+    }
+}
+
+/// When an id was generated, if it records that
+fn created_at(strategy: IdStrategy, id: Uuid) -> Option<SystemTime> {
+    match id.get_timestamp() {
+        Some(ts) => {
+            let (secs, nanos) = ts.to_unix();
+            Some(UNIX_EPOCH + Duration::new(secs, nanos))
+        }
+        None if strategy == IdStrategy::Ulid => {
+            Some(UNIX_EPOCH + Duration::from_millis((id.as_u128() >> 80) as u64))
+        }
+        None => None,
+    }
+}
+
+/// Get the id from a document key in the flexbuffer `(u64, u64)` form used before format 4
+fn legacy_document_id(key: IVec) -> Result<Uuid, MangoChainsawError> {
+    let (hi, lo): (u64, u64) = MangoChainsaw::de(key)?;
+    Ok(Uuid::from_u64_pair(hi, lo))
+}
+
+/// Expiry index keys are the big-endian expiry time followed by the id,
+/// so the reaper can scan everything that has expired as a single range
+fn expiry_key(expires_at: u64, id: Uuid) -> Vec<u8> {
+    let mut key = expires_at.to_be_bytes().to_vec();
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// The transactional trees that have to change together whenever a label is added or removed
+struct LabelIndex<'a> {
+    kev: &'a dyn TransactionalTree,
+    vek: &'a dyn TransactionalTree,
+    bitmaps: &'a dyn TransactionalTree,
+    range: &'a dyn TransactionalTree,
+    ordinals: &'a dyn TransactionalTree,
+    /// Only there while the bucket's quota needs its usage counted
+    usage: Option<&'a dyn TransactionalTree>,
+}
+
+impl LabelIndex<'_> {
+    /// Get the ordinal of a document, if it has one
+    fn ordinal(&self, idb: &[u8]) -> Result<Option<u32>, UnabortableTransactionError> {
+        Ok(self.ordinals.get(idb)?.map(|raw| decode_ordinal(&raw)))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct MangoChainsawBucket {
     parent: MangoChainsaw,
     name: String,
 
-    documents: sled::Tree,
-    labels_kev: sled::Tree,
-    labels_vek: sled::Tree,
-    docs_labels: sled::Tree,
+    documents: Tree,
+    labels_kev: Tree,
+    labels_vek: Tree,
+    docs_labels: Tree,
+    history: Tree,
+    settings: Tree,
+    ttl: Tree,
+    expiry: Tree,
+    bitmaps: Tree,
+    range: Tree,
+    ordinals: Tree,
+    ordinal_ids: Tree,
+    keys: Tree,
+    key_names: Tree,
+    chunks: Tree,
+    blobs: Tree,
+    blob_refs: Tree,
+    meta: Tree,
+    usage: Tree,
+    orphans: Tree,
 }
 
 impl MangoChainsawBucket {
-    /// Create a new Bucket
+    /// Open the trees of a bucket. It is up to the caller to have registered it.
     #[instrument(skip(parent))]
-    pub fn new(parent: &MangoChainsaw, name: &str) -> Result<Self, MangoChainsawError> {
-        Ok(Self {
+    pub(crate) fn new(parent: &MangoChainsaw, name: &str) -> Result<Self, MangoChainsawError> {
+        let this = Self {
             parent: parent.clone(),
             name: name.to_string(),
             documents: parent.get_tree(&format!("{name}::doc"))?,
             labels_kev: parent.get_tree(&format!("{name}::kev"))?,
             labels_vek: parent.get_tree(&format!("{name}::vek"))?,
             docs_labels: parent.get_tree(&format!("{name}::labels"))?,
-        })
+            history: parent.get_tree(&format!("{name}::history"))?,
+            settings: parent.get_tree(&format!("{name}::settings"))?,
+            ttl: parent.get_tree(&format!("{name}::ttl"))?,
+            expiry: parent.get_tree(&format!("{name}::expiry"))?,
+            bitmaps: parent.get_tree(&format!("{name}::bitmaps"))?,
+            range: parent.get_tree(&format!("{name}::range"))?,
+            ordinals: parent.get_tree(&format!("{name}::ordinals"))?,
+            ordinal_ids: parent.get_tree(&format!("{name}::ordinal_ids"))?,
+            keys: parent.get_tree(&format!("{name}::keys"))?,
+            key_names: parent.get_tree(&format!("{name}::key_names"))?,
+            chunks: parent.get_tree(&format!("{name}::chunks"))?,
+            blobs: parent.get_tree(&format!("{name}::blobs"))?,
+            blob_refs: parent.get_tree(&format!("{name}::blob_refs"))?,
+            meta: parent.get_tree(&format!("{name}::meta"))?,
+            usage: parent.get_tree(&format!("{name}::usage"))?,
+            orphans: parent.get_tree(&format!("{name}::orphans"))?,
+        };
+        this.migrate()?;
+        Ok(this)
+    }
+
+    /// Bring a bucket written by an older version up to the current on-disk format
+    #[instrument(skip(self), fields(name = self.name))]
+    fn migrate(&self) -> Result<(), MangoChainsawError> {
+        let format = match self.settings.get(FORMAT_KEY)? {
+            Some(raw) => decode_u64(&raw),
+            None if self.documents.is_empty() && self.labels_kev.is_empty() => {
+                self.settings
+                    .insert(FORMAT_KEY, &FORMAT_VERSION.to_be_bytes())?;
+                return Ok(());
+            }
+            None => 0,
+        };
+        if format > FORMAT_VERSION {
+            return Err(MangoChainsawError::Etc(format!(
+                "bucket {} has format {format}, newer than the supported {FORMAT_VERSION}",
+                self.name
+            )));
+        }
+        if format == FORMAT_VERSION {
+            return Ok(());
+        }
+
+        info!(format, "Migrating bucket to format {FORMAT_VERSION}");
+        if format < 1 {
+            self.migrate_revision_headers()?;
+            Self::migrate_posting_lists(&self.labels_kev)?;
+            Self::migrate_posting_lists(&self.labels_vek)?;
+        }
+        if format < 2 {
+            self.migrate_bitmaps()?;
+        }
+        if format < 3 {
+            self.migrate_range_index()?;
+        }
+        if format < 4 {
+            self.migrate_document_keys()?;
+        }
+        if format < 5 {
+            self.migrate_codec_tags()?;
+        }
+        if format < 6 {
+            self.migrate_metadata()?;
+        }
+        if format < 7 {
+            self.migrate_settings()?;
+        }
+        if format < 8 {
+            self.migrate_usage()?;
+        }
+        // Formats 3 to 8 kept integers and floats apart in the range index
+        if (3..9).contains(&format) {
+            self.migrate_range_index()?;
+        }
+        if format < 10 {
+            self.migrate_id_strategy()?;
+        }
+        self.settings
+            .insert(FORMAT_KEY, &FORMAT_VERSION.to_be_bytes())?;
+        info!("Migration complete");
+        Ok(())
+    }
+
+    /// Give every bare document a revision header, starting at revision 1
+    fn migrate_revision_headers(&self) -> Result<usize, MangoChainsawError> {
+        let mut batch = Batch::default();
+        let mut migrated = 0;
+        for result in self.documents.iter() {
+            let (key, val) = result?;
+            batch.insert(key, pack_document(1, &val));
+            migrated += 1;
+        }
+        self.documents.apply_batch(batch)?;
+        info!("Migrated {migrated} documents");
+        Ok(migrated)
+    }
+
+    /// Rewrite legacy `label -> Vec<(u64, u64)>` entries as one posting key per document
+    fn migrate_posting_lists(tree: &Tree) -> Result<usize, MangoChainsawError> {
+        let mut batch = Batch::default();
+        let mut migrated = 0;
+        for result in tree.iter() {
+            let (key, val) = result?;
+            if split_posting_key(&key).is_some() {
+                continue;
+            }
+            let ids: Vec<(u64, u64)> = MangoChainsaw::de(val)?;
+            for (hi, lo) in ids {
+                batch.insert(posting_key(&key, Uuid::from_u64_pair(hi, lo)), &[]);
+            }
+            batch.remove(key);
+            migrated += 1;
+        }
+        tree.apply_batch(batch)?;
+        info!("Migrated {migrated} posting lists");
+        Ok(migrated)
+    }
+
+    /// Give every document an ordinal and build the label bitmaps from each document's labels
+    fn migrate_bitmaps(&self) -> Result<usize, MangoChainsawError> {
+        self.bitmaps.clear()?;
+        self.ordinals.clear()?;
+        self.ordinal_ids.clear()?;
+        self.settings.remove(ORDINAL_KEY)?;
+
+        let first = self.allocate_ordinals(self.documents.len())?;
+        let mut ordinals = Batch::default();
+        let mut ordinal_ids = Batch::default();
+        let mut bitmaps: BTreeMap<Vec<u8>, RoaringBitmap> = BTreeMap::new();
+        let mut migrated = 0;
+        for (ordinal, key) in (first..).zip(self.documents.iter().keys()) {
+            let idb = key?;
+            ordinals.insert(&idb, &ordinal_key(ordinal));
+            ordinal_ids.insert(
+                &ordinal_key(ordinal),
+                legacy_document_id(idb.clone())?.as_bytes(),
+            );
+
+            bitmaps
+                .entry(UNIVERSE.to_vec())
+                .or_default()
+                .insert(ordinal);
+            if let Some(raw) = self.docs_labels.get(&idb)? {
+                let labels: Vec<Label> = MangoChainsaw::de(raw)?;
+                for label in labels {
+                    bitmaps.entry(label.as_bytes()).or_default().insert(ordinal);
+                }
+            }
+            migrated += 1;
+        }
+        self.ordinals.apply_batch(ordinals)?;
+        self.ordinal_ids.apply_batch(ordinal_ids)?;
+        bitmap::write_all(&self.bitmaps, bitmaps)?;
+        info!("Assigned ordinals to {migrated} documents");
+        Ok(migrated)
+    }
+
+    /// Build the range index from each document's labels and ordinal
+    fn migrate_range_index(&self) -> Result<usize, MangoChainsawError> {
+        self.range.clear()?;
+        let mut batch = Batch::default();
+        let mut migrated = 0;
+        for result in self.ordinals.iter() {
+            let (idb, ordinal) = result?;
+            if let Some(raw) = self.docs_labels.get(&idb)? {
+                let labels: Vec<Label> = MangoChainsaw::de(raw)?;
+                for label in labels {
+                    batch.insert(range::range_key(&label, decode_ordinal(&ordinal)), &[]);
+                }
+            }
+            migrated += 1;
+        }
+        self.range.apply_batch(batch)?;
+        info!("Indexed label values of {migrated} documents");
+        Ok(migrated)
+    }
+
+    /// Re-key everything keyed by document from the flexbuffer id to the id's bytes
+    fn migrate_document_keys(&self) -> Result<usize, MangoChainsawError> {
+        let mut migrated = 0;
+        for tree in [
+            &self.documents,
+            &self.docs_labels,
+            &self.ttl,
+            &self.ordinals,
+        ] {
+            let mut batch = Batch::default();
+            for result in tree.iter() {
+                let (key, val) = result?;
+                // Keys already in the new form are left alone, in case a migration was interrupted
+                if key.len() == 16 {
+                    continue;
+                }
+                batch.remove(key.clone());
+                batch.insert(document_key(legacy_document_id(key)?), val);
+                migrated += 1;
+            }
+            tree.apply_batch(batch)?;
+        }
+
+        let mut batch = Batch::default();
+        for result in self.expiry.iter() {
+            let (key, _val) = result?;
+            batch.insert(key.clone(), document_key(document_id(&key[8..])?));
+        }
+        self.expiry.apply_batch(batch)?;
+        info!("Re-keyed {migrated} document entries");
+        Ok(migrated)
+    }
+
+    /// Tag every document and archived version with the flexbuffers codec, which wrote them all.
+    /// Tagging twice would corrupt every body, so this is one transaction that also records
+    /// format 5, and an interrupted migration picks up after it.
+    fn migrate_codec_tags(&self) -> Result<usize, MangoChainsawError> {
+        let tag = |raw: IVec| -> Result<IVec, MangoChainsawError> {
+            let (rev, body) = unpack_document(raw)?;
+            let mut tagged = vec![CodecKind::Flexbuffers.tag()];
+            tagged.extend_from_slice(&body);
+            Ok(pack_document(rev, &tagged))
+        };
+
+        let mut documents = vec![];
+        for result in self.documents.iter() {
+            let (key, val) = result?;
+            documents.push((key, tag(val)?));
+        }
+        let mut versions = vec![];
+        for result in self.history.iter() {
+            let (key, val) = result?;
+            let mut version: Version = MangoChainsaw::de(val)?;
+            version.raw = tag(IVec::from(version.raw))?.to_vec();
+            versions.push((key, MangoChainsaw::ser(&version)?));
+        }
+
+        self.transaction([&self.documents, &self.history, &self.settings], |trees| {
+            let [docs, history, settings] = trees;
+            for (key, val) in &documents {
+                docs.insert(key, val)?;
+            }
+            for (key, val) in &versions {
+                history.insert(key, val)?;
+            }
+            settings.insert(FORMAT_KEY, &5u64.to_be_bytes())?;
+            Ok(())
+        })?;
+        let migrated = documents.len() + versions.len();
+        info!("Tagged {migrated} documents and versions with their codec");
+        Ok(migrated)
+    }
+
+    /// Give every document a metadata record. Their modification times are unknown,
+    /// so they are taken to be unmodified since they were created.
+    fn migrate_metadata(&self) -> Result<usize, MangoChainsawError> {
+        let mut batch = Batch::default();
+        let mut migrated = 0;
+        for result in self.documents.iter() {
+            let (key, val) = result?;
+            let (revision, body) = unpack_document(val)?;
+            let created =
+                created_at(self.id_strategy()?, document_id(&key)?).unwrap_or_else(SystemTime::now);
+            let meta = DocumentMetadata {
+                created,
+                modified: created,
+                revision,
+                ..self.describe(&body)?
+            };
+            batch.insert(key, MangoChainsaw::ser(&meta)?);
+            migrated += 1;
+        }
+        self.meta.apply_batch(batch)?;
+        info!("Recorded metadata for {migrated} documents");
+        Ok(migrated)
+    }
+
+    /// Move the bucket's settings into its registry entry
+    fn migrate_settings(&self) -> Result<(), MangoChainsawError> {
+        if let Some(raw) = self.settings.get(SETTINGS_KEY)? {
+            self.parent
+                .set_bucket_settings(&self.name, MangoChainsaw::de(raw)?)?;
+            self.settings.remove(SETTINGS_KEY)?;
+            info!("Moved settings to the bucket registry");
+        }
+        Ok(())
+    }
+
+    /// Count what the bucket holds, for its quota
+    fn migrate_usage(&self) -> Result<(), MangoChainsawError> {
+        self.recount_usage(&self.settings()?.quota)
+    }
+
+    /// Rebuild the usage counts if the quota needs them, or drop them if it doesn't
+    fn recount_usage(&self, quota: &Quota) -> Result<(), MangoChainsawError> {
+        if !quota.counts_usage() {
+            self.usage.clear()?;
+            return Ok(());
+        }
+        let (totals, labels) = self.count_usage()?;
+        info!(
+            documents = totals.documents,
+            labels = labels.len(),
+            "Counted bucket usage"
+        );
+        quota::recount(&self.usage, totals, labels)
+    }
+
+    /// Count what the bucket holds by going over its documents and labels
+    fn count_usage(&self) -> Result<(Usage, BTreeMap<Vec<u8>, u64>), MangoChainsawError> {
+        let mut totals = Usage::default();
+        for raw in self.meta.iter().values() {
+            let meta: DocumentMetadata = MangoChainsaw::de(raw?)?;
+            totals.documents += 1;
+            totals.bytes += meta.size;
+        }
+        let mut labels: BTreeMap<Vec<u8>, u64> = BTreeMap::new();
+        for key in self.labels_kev.iter().keys() {
+            if let Some((label, _id)) = split_posting_key(&key?) {
+                *labels.entry(label.to_vec()).or_default() += 1;
+            }
+        }
+        totals.labels = labels.len() as u64;
+        Ok((totals, labels))
+    }
+
+    /// Record the configured id strategy in the bucket's settings, since that is what
+    /// its ids were generated with
+    fn migrate_id_strategy(&self) -> Result<(), MangoChainsawError> {
+        let settings = self.settings()?;
+        if settings.id_strategy.is_none() {
+            let strategy = self.parent.id_strategy;
+            self.parent.set_bucket_settings(
+                &self.name,
+                BucketSettings {
+                    id_strategy: Some(strategy),
+                    ..settings
+                },
+            )?;
+            info!(?strategy, "Recorded the bucket's id strategy");
+        }
+        Ok(())
+    }
+
+    /// Reserve `n` consecutive document ordinals, returning the first.
+    /// Ordinals are never reused, so a bucket can hold at most 2^32 documents over its lifetime.
+    fn allocate_ordinals(&self, n: usize) -> Result<u32, MangoChainsawError> {
+        let previous = self.settings.fetch_and_update(ORDINAL_KEY, |old| {
+            let next = old.map(decode_u64).unwrap_or(0);
+            Some(next.saturating_add(n as u64).to_be_bytes().to_vec())
+        })?;
+        let first = previous.map(|raw| decode_u64(&raw)).unwrap_or(0);
+        if first + n as u64 > u32::MAX as u64 + 1 {
+            return Err(MangoChainsawError::Etc(format!(
+                "bucket {} has run out of document ordinals",
+                self.name
+            )));
+        }
+        Ok(first as u32)
+    }
+
+    /// How this bucket generates document ids, fixed when it was created
+    #[instrument(skip(self))]
+    pub fn id_strategy(&self) -> Result<IdStrategy, MangoChainsawError> {
+        Ok(self.strategy(&self.settings()?))
+    }
+
+    /// The id strategy in the bucket's settings. Only a bucket that is still being
+    /// migrated can be without one, and it was written with the configured strategy.
+    fn strategy(&self, settings: &BucketSettings) -> IdStrategy {
+        settings.id_strategy.unwrap_or(self.parent.id_strategy)
+    }
+
+    /// Generate an id for a new document in this bucket
+    fn next_id(&self, settings: &BucketSettings) -> Result<Uuid, MangoChainsawError> {
+        self.parent.generate_id(self.strategy(settings))
+    }
+
+    /// Run a transaction over some of the bucket's trees, once no copy or rename of the
+    /// bucket is holding writes back. Transactions don't nest, so neither do their gates.
+    fn transaction<const N: usize, R>(
+        &self,
+        trees: [&Tree; N],
+        f: impl Fn([&dyn TransactionalTree; N]) -> ConflictableTransactionResult<R, MangoChainsawError>,
+    ) -> Result<R, MangoChainsawError> {
+        let _gate = self.parent.gates.enter(&self.name)?;
+        self.parent.storage.transaction(trees, f)
+    }
+
+    /// Serialize a document body with the bucket's codec
+    fn encode<T>(&self, doc: &T) -> Result<IVec, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        encode_body(self.settings()?.codec, doc)
     }
 
     /// Get the current bucket name
@@ -37,6 +688,73 @@ impl MangoChainsawBucket {
         &self.name
     }
 
+    /// Get the settings for this bucket
+    #[instrument(skip(self))]
+    pub fn settings(&self) -> Result<BucketSettings, MangoChainsawError> {
+        match self.parent.bucket_info(&self.name)? {
+            Some(info) => Ok(info.settings),
+            None => Err(MangoChainsawError::BucketNotFound(self.name.clone())),
+        }
+    }
+
+    /// Replace the settings for this bucket. Setting a quota that limits the bucket as a whole
+    /// counts what the bucket holds, so it can take a while on a large bucket.
+    #[instrument(skip(self))]
+    pub fn set_settings(&self, settings: BucketSettings) -> Result<(), MangoChainsawError> {
+        let _gate = self.parent.gates.enter(&self.name)?;
+        let counted = settings.quota.counts_usage();
+        let previous = self
+            .parent
+            .set_bucket_settings(&self.name, settings.clone())?;
+        if counted != previous.quota.counts_usage() {
+            self.recount_usage(&settings.quota)?;
+        }
+        info!("Updated bucket settings");
+        Ok(())
+    }
+
+    /// Get the rules this bucket's labels have to follow, if it has any
+    pub fn label_schema(&self) -> Result<Option<LabelSchema>, MangoChainsawError> {
+        Ok(self.settings()?.label_schema)
+    }
+
+    /// Replace the rules this bucket's labels have to follow, or remove them with `None`.
+    /// Documents already in the bucket aren't checked against the new rules.
+    #[instrument(skip(self))]
+    pub fn set_label_schema(&self, schema: Option<LabelSchema>) -> Result<(), MangoChainsawError> {
+        let settings = BucketSettings {
+            label_schema: schema,
+            ..self.settings()?
+        };
+        self.set_settings(settings)
+    }
+
+    /// Get the JSON Schema this bucket's document bodies have to match, if it has one
+    pub fn body_schema(&self) -> Result<Option<BodySchema>, MangoChainsawError> {
+        Ok(self.settings()?.body_schema)
+    }
+
+    /// Replace the JSON Schema this bucket's document bodies have to match,
+    /// or remove it with `None`. Documents already in the bucket aren't checked against it.
+    #[instrument(skip(self))]
+    pub fn set_body_schema(&self, schema: Option<BodySchema>) -> Result<(), MangoChainsawError> {
+        let settings = BucketSettings {
+            body_schema: schema,
+            ..self.settings()?
+        };
+        self.set_settings(settings)
+    }
+
+    /// Get what the bucket holds, as counted against its quota. A bucket whose quota
+    /// doesn't need counting is counted on the spot.
+    #[instrument(skip(self))]
+    pub fn usage(&self) -> Result<Usage, MangoChainsawError> {
+        match self.settings()?.quota.counts_usage() {
+            true => quota::usage(&self.usage),
+            false => Ok(self.count_usage()?.0),
+        }
+    }
+
     #[instrument(skip(self), ret)]
     pub fn stat(&self) -> Result<HashMap<String, usize>, MangoChainsawError> {
         let mut map = HashMap::new();
@@ -44,214 +762,2165 @@ impl MangoChainsawBucket {
         map.insert("num_labels_kev", self.labels_kev.len());
         map.insert("num_labels_vec", self.labels_vek.len());
         map.insert("num_docs_labels", self.docs_labels.len());
+        map.insert("num_history", self.history.len());
+        map.insert("num_expiring", self.ttl.len());
+        map.insert("num_ordinals", self.ordinals.len());
+        map.insert("num_bitmap_chunks", self.bitmaps.len());
+        map.insert("num_range_entries", self.range.len());
+        map.insert("num_keys", self.keys.len());
+        map.insert("num_chunks", self.chunks.len());
+        map.insert("num_blobs", self.blobs.len());
+        let (logical, physical) = self.body_bytes()?;
+        map.insert("logical_bytes", logical as usize);
+        map.insert("physical_bytes", physical as usize);
         map.insert("crc32_documents", self.documents.checksum()? as usize);
         map.insert("crc32_labels_kev", self.labels_kev.checksum()? as usize);
         map.insert("crc32_labels_vek", self.labels_vek.checksum()? as usize);
         map.insert("crc32_docs_labels", self.docs_labels.checksum()? as usize);
 
-        Ok(map.into_iter().map(|(k, v)| (format!("{k}"), v)).collect())
+        Ok(map.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Count the bytes in the bodies of the current documents: as read back,
+    /// and as stored, where documents that share a body only count it once
+    fn body_bytes(&self) -> Result<(u64, u64), MangoChainsawError> {
+        let len = |body: &[u8]| -> Result<u64, MangoChainsawError> {
+            match Manifest::from_body(body)? {
+                Some(manifest) => Ok(manifest.len),
+                None => Ok(body.len().saturating_sub(1) as u64),
+            }
+        };
+        let mut shared: HashMap<Digest, u64> = HashMap::new();
+        let (mut logical, mut physical) = (0, 0);
+        for raw in self.documents.iter().values() {
+            let (_rev, body) = unpack_document(raw?)?;
+            let size = match dedup::pointer(&body) {
+                Some(digest) => match shared.get(&digest) {
+                    Some(size) => *size,
+                    None => {
+                        let size = len(&self.resolve(body)?)?;
+                        physical += size;
+                        shared.insert(digest, size);
+                        size
+                    }
+                },
+                None => {
+                    let size = len(&body)?;
+                    physical += size;
+                    size
+                }
+            };
+            logical += size;
+        }
+        Ok((logical, physical))
+    }
+
+    /// Get a document by id
+    #[instrument(skip(self))]
+    pub fn get<T>(&self, id: Uuid) -> Result<Option<T>, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(self.get_with_revision(id)?.map(|(doc, _rev)| doc))
+    }
+
+    /// Get a document and its current revision by id
+    #[instrument(skip(self))]
+    pub fn get_with_revision<T>(&self, id: Uuid) -> Result<Option<(T, u64)>, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        let idb = document_key(id);
+        if self.is_expired(&idb)? {
+            info!("Object has expired");
+            return Ok(None);
+        }
+        match self.documents.get(idb) {
+            Ok(Some(thing)) => {
+                info!("Found object");
+                let (rev, body) = unpack_document(thing)?;
+                let out: T = self.decode(&body)?;
+                info!(rev, "Deserialized object");
+                Ok(Some((out, rev)))
+            }
+            Ok(None) => {
+                info!("Object not found");
+                Ok(None)
+            }
+            Err(e) => {
+                error!(error = format!("{e}"), "Failed to lookup object");
+                Err(e)
+            }
+        }
+    }
+
+    /// Get the current revision of a document by id
+    #[instrument(skip(self))]
+    pub fn revision(&self, id: Uuid) -> Result<Option<u64>, MangoChainsawError> {
+        let idb = document_key(id);
+        if self.is_expired(&idb)? {
+            return Ok(None);
+        }
+        match self.documents.get(idb)? {
+            Some(thing) => Ok(Some(unpack_document(thing)?.0)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get many documents by id
+    #[instrument(skip(self))]
+    pub fn get_many<T>(&self, ids: Vec<Uuid>) -> Result<Vec<(Uuid, Option<T>)>, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        let mut results = vec![];
+        for id in &ids {
+            results.push((*id, self.get(*id)?));
+        }
+        Ok(results)
+    }
+
+    /// Get labels for a given document id
+    #[instrument(skip(self))]
+    pub fn get_document_labels(&self, id: Uuid) -> Result<Option<Vec<Label>>, MangoChainsawError> {
+        let idb = document_key(id);
+        if self.is_expired(&idb)? {
+            info!("Document has expired");
+            return Ok(None);
+        }
+        match self.docs_labels.get(idb) {
+            Ok(Some(thing)) => {
+                let labels: Vec<Label> = MangoChainsaw::de(thing)?;
+                info!("Found {} labels for document", labels.len());
+                Ok(Some(labels))
+            }
+            Ok(None) => {
+                info!("Document not found or document has no labels");
+                Ok(None)
+            }
+            Err(e) => {
+                error!("Error looking up document labels");
+                Err(e)
+            }
+        }
+    }
+
+    /// Get the system metadata of a document: when it was created and last modified,
+    /// its revision, size, checksum, codec and content type
+    #[instrument(skip(self))]
+    pub fn metadata(&self, id: Uuid) -> Result<Option<DocumentMetadata>, MangoChainsawError> {
+        let idb = document_key(id);
+        if self.is_expired(&idb)? {
+            return Ok(None);
+        }
+        match self.meta.get(idb)? {
+            Some(raw) => Ok(Some(MangoChainsaw::de(raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set or clear the media type recorded for a document.
+    /// Returns whether the document exists.
+    #[instrument(skip(self))]
+    pub fn set_content_type(
+        &self,
+        id: Uuid,
+        content_type: Option<&str>,
+    ) -> Result<bool, MangoChainsawError> {
+        let idb = document_key(id);
+        self.transaction([&self.meta], |[meta]| {
+            let Some(raw) = meta.get(&idb)? else {
+                return Ok(false);
+            };
+            let mut record: DocumentMetadata =
+                MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?;
+            record.content_type = content_type.map(str::to_string);
+            let record =
+                MangoChainsaw::ser(&record).map_err(ConflictableTransactionError::Abort)?;
+            meta.insert(&idb, record)?;
+            Ok(true)
+        })
+    }
+
+    /// Insert a new document with a given set of identifying labels.
+    /// The document expires after the bucket's default TTL, if one is set.
+    #[instrument(skip(self, doc), fields(id))]
+    pub fn insert<T>(&self, doc: T, labels: Vec<Label>) -> Result<Uuid, MangoChainsawError>
+    where
+        T: Serialize,
+    {
+        let settings = self.settings()?;
+        let id = self.next_id(&settings)?;
+        let body = encode_body(settings.codec, &doc)?;
+        let ids = self.insert_inner(vec![(id, None, body, labels)], settings.default_ttl, None)?;
+        Ok(ids[0])
+    }
+
+    /// Insert a new document under an id chosen by the caller, such as one mirrored from
+    /// another system. Fails with [`MangoChainsawError::DuplicateId`] if the id is taken.
+    /// The document expires after the bucket's default TTL, if one is set.
+    #[instrument(skip(self, doc))]
+    pub fn insert_with_id<T>(
+        &self,
+        id: Uuid,
+        doc: T,
+        labels: Vec<Label>,
+    ) -> Result<(), MangoChainsawError>
+    where
+        T: Serialize,
+    {
+        let settings = self.settings()?;
+        let body = encode_body(settings.codec, &doc)?;
+        self.insert_inner(vec![(id, None, body, labels)], settings.default_ttl, None)?;
+        Ok(())
+    }
+
+    /// Insert many documents in a single transaction.
+    /// Each label's posting list is updated once for the whole batch.
+    /// Returns the new ids in the same order as the documents.
+    #[instrument(skip(self, docs))]
+    pub fn insert_many<T>(
+        &self,
+        docs: Vec<(T, Vec<Label>)>,
+    ) -> Result<Vec<Uuid>, MangoChainsawError>
+    where
+        T: Serialize,
+    {
+        let settings = self.settings()?;
+        let mut prepared = Vec::with_capacity(docs.len());
+        for (doc, labels) in docs {
+            let id = self.next_id(&settings)?;
+            prepared.push((id, None, encode_body(settings.codec, &doc)?, labels));
+        }
+        self.insert_inner(prepared, settings.default_ttl, None)
+    }
+
+    /// Insert a new document that expires after `ttl`
+    #[instrument(skip(self, doc), fields(id))]
+    pub fn insert_with_ttl<T>(
+        &self,
+        doc: T,
+        labels: Vec<Label>,
+        ttl: Duration,
+    ) -> Result<Uuid, MangoChainsawError>
+    where
+        T: Serialize,
+    {
+        let settings = self.settings()?;
+        let id = self.next_id(&settings)?;
+        let doc = encode_body(settings.codec, &doc)?;
+        let ids = self.insert_inner(vec![(id, None, doc, labels)], Some(ttl), None)?;
+        Ok(ids[0])
+    }
+
+    /// Insert serialized documents with their ids, optional keys and labels in a single
+    /// transaction, recording `content_type` as the media type of each.
+    /// Aborts without inserting anything if any of the ids or keys is taken.
+    fn insert_inner(
+        &self,
+        docs: Vec<(Uuid, Option<IVec>, IVec, Vec<Label>)>,
+        ttl: Option<Duration>,
+        content_type: Option<&str>,
+    ) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let expires_at = match ttl {
+            Some(ttl) => Some(now_millis()?.saturating_add(ttl.as_millis() as u64)),
+            None => None,
+        };
+
+        let settings = self.settings()?;
+        let validators = self.validators(&settings)?;
+        // Ordinals are never reused, so turn away taken ids and keys before reserving any.
+        // The transaction checks again, in case they are taken in the meantime.
+        let mut ids = HashSet::with_capacity(docs.len());
+        for (id, key, ..) in &docs {
+            if !ids.insert(*id) || self.documents.get(document_key(*id))?.is_some() {
+                return Err(MangoChainsawError::DuplicateId(*id));
+            }
+            if let Some(key) = key {
+                if self.keys.get(key)?.is_some() {
+                    return Err(MangoChainsawError::DuplicateKey(key.to_vec()));
+                }
+            }
+        }
+        let first = self.allocate_ordinals(docs.len())?;
+        let mut prepared = Vec::with_capacity(docs.len());
+        let mut label_ids: BTreeMap<Label, (Vec<Uuid>, Vec<u32>)> = BTreeMap::new();
+        let mut bytes = 0;
+        for (ordinal, (id, key, body, labels)) in (first..).zip(docs) {
+            let id_ivec = document_key(id);
+            info!(id = id.to_string(), ordinal, "Preparing document");
+
+            let digest = match settings.dedup {
+                true => dedup::body_digest(&body)?,
+                false => None,
+            };
+            let meta = DocumentMetadata {
+                revision: 1,
+                content_type: content_type.map(str::to_string),
+                ..self.describe(&body)?
+            };
+            quota::check_size(&settings.quota, &self.name, meta.size)?;
+            quota::check_label_count(&settings.quota, &self.name, labels.len())?;
+            if let Some(validator) = &validators.labels {
+                validator.validate(&labels)?;
+            }
+            if let Some(validator) = &validators.body {
+                self.check_body(validator, &body)?;
+            }
+            bytes += meta.size;
+            let meta = MangoChainsaw::ser(&meta)?;
+            let document = pack_document(1, &body);
+            info!(id = id.to_string(), "Doc size: {}", document.len());
+            for label in &labels {
+                let (ids, ordinals) = label_ids.entry(label.clone()).or_default();
+                ids.push(id);
+                ordinals.push(ordinal);
+            }
+            let doclbl = MangoChainsaw::ser(&labels)?;
+            prepared.push((id, id_ivec, key, ordinal, document, (doclbl, meta), digest));
+        }
+        let all_ordinals: Vec<u32> = prepared
+            .iter()
+            .map(|(_, _, _, ordinal, ..)| *ordinal)
+            .collect();
+        info!(
+            "Prepared {} documents with {} distinct labels",
+            prepared.len(),
+            label_ids.len()
+        );
+
+        self.transaction(
+            [
+                &self.documents,
+                &self.docs_labels,
+                &self.ttl,
+                &self.expiry,
+                &self.ordinal_ids,
+                &self.keys,
+                &self.key_names,
+                &self.labels_kev,
+                &self.labels_vek,
+                &self.bitmaps,
+                &self.range,
+                &self.ordinals,
+                &self.blobs,
+                &self.blob_refs,
+                &self.meta,
+                &self.usage,
+            ],
+            |trees| {
+                let [docs, docs_labels, ttl, expiry, ordinal_ids, keys, key_names, index @ ..] =
+                    trees;
+                let [kev, vek, bitmaps, range, ordinals, blobs, refs, meta, usage] = index;
+                for (id, id_ivec, key, ordinal, document, (doclbl, record), digest) in &prepared {
+                    if docs.get(id_ivec)?.is_some() {
+                        return Err(ConflictableTransactionError::Abort(
+                            MangoChainsawError::DuplicateId(*id),
+                        ));
+                    }
+                    if let Some(key) = key {
+                        if keys.insert(key, id_ivec)?.is_some() {
+                            return Err(ConflictableTransactionError::Abort(
+                                MangoChainsawError::DuplicateKey(key.to_vec()),
+                            ));
+                        }
+                        key_names.insert(id_ivec, key)?;
+                    }
+                    let document = match digest {
+                        Some(digest) => {
+                            let body = &document[REVISION_LEN..];
+                            pack_document(1, &dedup::reference(blobs, refs, digest, body)?)
+                        }
+                        None => document.clone(),
+                    };
+                    docs.insert(id_ivec, document)?;
+                    docs_labels.insert(id_ivec, doclbl)?;
+                    meta.insert(id_ivec, record)?;
+                    ordinals.insert(id_ivec, &ordinal_key(*ordinal))?;
+                    ordinal_ids.insert(ordinal_key(*ordinal), id.as_bytes())?;
+                    info!(
+                        id = id.to_string(),
+                        "Inserted document and document labels pending transaction completion"
+                    );
+
+                    if let Some(expires_at) = expires_at {
+                        ttl.insert(id_ivec, &expires_at.to_be_bytes())?;
+                        expiry.insert(expiry_key(expires_at, *id), id_ivec)?;
+                        info!(id = id.to_string(), expires_at, "Set document expiry");
+                    }
+                }
+                bitmap::insert(bitmaps, UNIVERSE, &all_ordinals)?;
+                let added = prepared.len() as i64;
+                quota::charge(usage, &settings.quota, &self.name, added, bytes as i64)?;
+
+                let index = LabelIndex {
+                    kev,
+                    vek,
+                    bitmaps,
+                    range,
+                    ordinals,
+                    usage: settings.quota.counts_usage().then_some(usage),
+                };
+                let mut grew = false;
+                for (label, (ids, ordinals)) in &label_ids {
+                    grew |= self.upsert_label(&index, label, ids, ordinals)?;
+                }
+                if grew {
+                    quota::check_labels(usage, &settings.quota, &self.name)?;
+                }
+                info!("Upserted {} labels", label_ids.len());
+
+                info!("Transaction complete");
+                Ok(())
+            },
+        )?;
+
+        Ok(prepared.into_iter().map(|(id, ..)| id).collect())
+    }
+
+    /// Delete a document from the bucket
+    #[instrument(skip(self))]
+    pub fn delete<T>(&self, id: Uuid) -> Result<Option<T>, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        match self.remove_documents(&[id])?.pop().flatten() {
+            Some(body) => Ok(Some(self.decode_and_release(body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete many documents in a single transaction.
+    /// Each label's posting list is updated once for the whole batch.
+    #[instrument(skip(self))]
+    pub fn delete_many<T>(
+        &self,
+        ids: Vec<Uuid>,
+    ) -> Result<Vec<(Uuid, Option<T>)>, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        let removed = self.remove_documents(&ids)?;
+        let mut results = Vec::with_capacity(ids.len());
+        for (id, body) in ids.into_iter().zip(removed) {
+            match body {
+                Some(body) => results.push((id, Some(self.decode_and_release(body)?))),
+                None => results.push((id, None)),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Delete a document without reading it. Returns whether it existed.
+    #[instrument(skip(self))]
+    pub fn remove(&self, id: Uuid) -> Result<bool, MangoChainsawError> {
+        match self.remove_documents(&[id])?.pop().flatten() {
+            Some(body) => {
+                self.release_body(&body)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Insert bytes as a document, stored as they are with the raw codec.
+    /// Reading them back with [`MangoChainsawBucket::get_raw`] doesn't copy or decode them.
+    #[instrument(skip(self, bytes))]
+    pub fn insert_raw<B>(&self, bytes: B, labels: Vec<Label>) -> Result<Uuid, MangoChainsawError>
+    where
+        B: AsRef<[u8]>,
+    {
+        let settings = self.settings()?;
+        let id = self.next_id(&settings)?;
+        let body = raw_body(bytes.as_ref());
+        let ids = self.insert_inner(vec![(id, None, body, labels)], settings.default_ttl, None)?;
+        Ok(ids[0])
+    }
+
+    /// Store bytes under a key with the raw codec, replacing whatever was stored there.
+    /// Returns the id of the document.
+    #[instrument(skip(self, key, bytes))]
+    pub fn put_raw<K, B>(
+        &self,
+        key: K,
+        bytes: B,
+        labels: Vec<Label>,
+    ) -> Result<Uuid, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        B: AsRef<[u8]>,
+    {
+        let target = WriteTarget::Key(key.as_ref().to_vec());
+        match self.commit_body(&target, raw_body(bytes.as_ref()), Some(labels), None)? {
+            Some((id, _rev)) => Ok(id),
+            None => Err(MangoChainsawError::Etc(
+                "keyed document vanished while it was stored".to_string(),
+            )),
+        }
+    }
+
+    /// Replace an existing document with bytes stored by the raw codec, and replace its labels
+    /// too if any are given. Returns the new revision, or None if the id does not exist.
+    #[instrument(skip(self, bytes))]
+    pub fn replace_raw<B>(
+        &self,
+        id: Uuid,
+        bytes: B,
+        labels: Option<Vec<Label>>,
+    ) -> Result<Option<u64>, MangoChainsawError>
+    where
+        B: AsRef<[u8]>,
+    {
+        let target = WriteTarget::Replace {
+            id,
+            expected_rev: None,
+        };
+        let stored = self.commit_body(&target, raw_body(bytes.as_ref()), labels, None)?;
+        Ok(stored.map(|(_id, rev)| rev))
+    }
+
+    /// Replace a document with bytes stored by the raw codec, and its labels if any are given,
+    /// only if its current revision is `expected_rev`. Returns the new revision,
+    /// or `MangoChainsawError::Conflict` if the document has moved on or does not exist.
+    #[instrument(skip(self, bytes))]
+    pub fn compare_and_swap_raw<B>(
+        &self,
+        id: Uuid,
+        expected_rev: u64,
+        bytes: B,
+        labels: Option<Vec<Label>>,
+    ) -> Result<u64, MangoChainsawError>
+    where
+        B: AsRef<[u8]>,
+    {
+        let target = WriteTarget::Replace {
+            id,
+            expected_rev: Some(expected_rev),
+        };
+        match self.commit_body(&target, raw_body(bytes.as_ref()), labels, None)? {
+            Some((_id, rev)) => Ok(rev),
+            None => Err(MangoChainsawError::Conflict {
+                id,
+                expected: expected_rev,
+                found: None,
+            }),
+        }
+    }
+
+    /// Write a new document of any size with the raw codec.
+    /// Documents bigger than [`large::CHUNK_SIZE`] are split into chunks as they are written.
+    #[instrument(skip(self))]
+    pub fn write_large(&self, labels: Vec<Label>) -> LargeObjectWriter {
+        LargeObjectWriter::new(
+            self.clone(),
+            self.chunks.clone(),
+            WriteTarget::Insert,
+            Some(labels),
+        )
+    }
+
+    /// Write a document of any size under a key, replacing whatever was stored there
+    #[instrument(skip(self, key))]
+    pub fn put_large<K>(&self, key: K, labels: Vec<Label>) -> LargeObjectWriter
+    where
+        K: AsRef<[u8]>,
+    {
+        LargeObjectWriter::new(
+            self.clone(),
+            self.chunks.clone(),
+            WriteTarget::Key(key.as_ref().to_vec()),
+            Some(labels),
+        )
+    }
+
+    /// Write a document of any size in place of an existing one, replacing its labels too
+    /// if any are given. With `expected_rev`, finishing fails with
+    /// `MangoChainsawError::Conflict` unless that is the document's current revision.
+    #[instrument(skip(self))]
+    pub fn replace_large(
+        &self,
+        id: Uuid,
+        expected_rev: Option<u64>,
+        labels: Option<Vec<Label>>,
+    ) -> LargeObjectWriter {
+        LargeObjectWriter::new(
+            self.clone(),
+            self.chunks.clone(),
+            WriteTarget::Replace { id, expected_rev },
+            labels,
+        )
+    }
+
+    /// Store a finished body as `target` says. Returns the document's id and new revision,
+    /// or None if it was to replace a document that doesn't exist.
+    /// `Some(None)` clears the content type of a replaced document; None keeps it.
+    pub(crate) fn commit_body(
+        &self,
+        target: &WriteTarget,
+        body: IVec,
+        labels: Option<Vec<Label>>,
+        content_type: Option<Option<String>>,
+    ) -> Result<Option<(Uuid, u64)>, MangoChainsawError> {
+        let content_type = content_type.as_ref().map(Option::as_deref);
+        let stored = self.store_body(target, body.clone(), labels, content_type)?;
+        if let Some((id, _rev)) = stored {
+            self.release_duplicate(id, &body)?;
+        }
+        Ok(stored)
+    }
+
+    /// Store a finished body as `target` says, without checking for duplicate chunks
+    fn store_body(
+        &self,
+        target: &WriteTarget,
+        body: IVec,
+        labels: Option<Vec<Label>>,
+        content_type: Option<Option<&str>>,
+    ) -> Result<Option<(Uuid, u64)>, MangoChainsawError> {
+        match target {
+            WriteTarget::Insert => {
+                let settings = self.settings()?;
+                let id = self.next_id(&settings)?;
+                let labels = labels.unwrap_or_default();
+                let docs = vec![(id, None, body, labels)];
+                self.insert_inner(docs, settings.default_ttl, content_type.flatten())?;
+                Ok(Some((id, 1)))
+            }
+            WriteTarget::Key(key) => {
+                if let Some(id) = self.key_id(key)? {
+                    let swapped =
+                        self.swap_document(id, None, body.clone(), labels.clone(), content_type)?;
+                    if let Some((rev, old)) = swapped {
+                        self.release_body(&old)?;
+                        return Ok(Some((id, rev + 1)));
+                    }
+                }
+                let labels = labels.unwrap_or_default();
+                let id = self.insert_keyed(key, body, labels, content_type.flatten())?;
+                Ok(Some((id, 1)))
+            }
+            WriteTarget::Replace { id, expected_rev } => {
+                let swapped =
+                    self.swap_document(*id, *expected_rev, body.clone(), labels, content_type)?;
+                match swapped {
+                    Some((rev, old)) => {
+                        self.release_body(&old)?;
+                        Ok(Some((*id, rev + 1)))
+                    }
+                    None => match expected_rev {
+                        Some(expected) => Err(MangoChainsawError::Conflict {
+                            id: *id,
+                            expected: *expected,
+                            found: None,
+                        }),
+                        None => Ok(None),
+                    },
+                }
+            }
+        }
+    }
+
+    /// Get the stored bytes of a document without decoding them. For documents written
+    /// by a serializing codec these are the encoded bytes, such as JSON text.
+    /// The result shares memory with the store rather than being copied out of it,
+    /// except for chunked documents, which are read whole. Use
+    /// [`MangoChainsawBucket::stream_raw`] to read those a chunk at a time.
+    #[instrument(skip(self))]
+    pub fn get_raw(&self, id: Uuid) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(self.get_raw_with_revision(id)?.map(|(bytes, _rev)| bytes))
+    }
+
+    /// Get the stored bytes of a document and its current revision, without decoding them
+    #[instrument(skip(self))]
+    pub fn get_raw_with_revision(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<(IVec, u64)>, MangoChainsawError> {
+        match self.raw_document(id)? {
+            Some((rev, body)) => Ok(Some((self.read_raw(body)?, rev))),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the stored bytes of a document a slice at a time, without decoding or copying them.
+    /// The stream is also a [`std::io::Read`] and [`std::io::Seek`] over the document.
+    #[instrument(skip(self))]
+    pub fn stream_raw(&self, id: Uuid) -> Result<Option<RawStream>, MangoChainsawError> {
+        Ok(self
+            .stream_raw_with_revision(id)?
+            .map(|(stream, _rev)| stream))
+    }
+
+    /// Stream the stored bytes of a document, along with its current revision
+    #[instrument(skip(self))]
+    pub fn stream_raw_with_revision(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<(RawStream, u64)>, MangoChainsawError> {
+        match self.raw_document(id)? {
+            Some((rev, body)) => Ok(Some((self.stream_body(body)?, rev))),
+            None => Ok(None),
+        }
+    }
+
+    /// Stream a document as the bytes it was given as, along with its current revision.
+    /// A body a serializing codec wrote from a byte string, as the server stored uploads
+    /// before they were kept as they are, is decoded back to that byte string.
+    /// Anything else streams as it is stored.
+    #[instrument(skip(self))]
+    pub fn stream_bytes_with_revision(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<(RawStream, u64)>, MangoChainsawError> {
+        let Some((rev, body)) = self.raw_document(id)? else {
+            return Ok(None);
+        };
+        let body = self.resolve(body)?;
+        if Manifest::from_body(&body)?.is_some() {
+            return Ok(Some((self.stream_body(body)?, rev)));
+        }
+        let stream = match untag_body(body)? {
+            (CodecKind::Raw, bytes) => RawStream::inline(bytes, CodecKind::Raw),
+            (codec, bytes) => match codec.decode::<Vec<u8>>(&bytes) {
+                Ok(decoded) => RawStream::inline(IVec::from(decoded), CodecKind::Raw),
+                Err(_) => RawStream::inline(bytes, codec),
+            },
+        };
+        Ok(Some((stream, rev)))
+    }
+
+    /// Delete a document, returning its stored bytes without decoding them
+    #[instrument(skip(self))]
+    pub fn delete_raw(&self, id: Uuid) -> Result<Option<IVec>, MangoChainsawError> {
+        match self.remove_documents(&[id])?.pop().flatten() {
+            Some(body) => {
+                let bytes = self.read_raw(body.clone());
+                self.release_body(&body)?;
+                Ok(Some(bytes?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the revision and serialized body of a live document
+    fn raw_document(&self, id: Uuid) -> Result<Option<(u64, IVec)>, MangoChainsawError> {
+        let idb = document_key(id);
+        if self.is_expired(&idb)? {
+            return Ok(None);
+        }
+        match self.documents.get(idb)? {
+            Some(raw) => Ok(Some(unpack_document(raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the shared body a serialized body points to, or the body itself if it is its own
+    fn resolve(&self, body: IVec) -> Result<IVec, MangoChainsawError> {
+        match dedup::pointer(&body) {
+            Some(digest) => self.blobs.get(digest)?.ok_or_else(|| {
+                MangoChainsawError::Etc("shared document body is missing".to_string())
+            }),
+            None => Ok(body),
+        }
+    }
+
+    /// Work out the size, checksum and codec of a serialized body. The rest of the metadata
+    /// is left for the caller to fill in; the times are now, the revision 0.
+    fn describe(&self, body: &[u8]) -> Result<DocumentMetadata, MangoChainsawError> {
+        if dedup::pointer(body).is_some() {
+            return self.describe(&self.resolve(IVec::from(body))?);
+        }
+        let (size, checksum, codec) = match Manifest::from_body(body)? {
+            Some(manifest) => {
+                let checksum = match manifest.checksum {
+                    Some(checksum) => checksum,
+                    None => {
+                        let mut hasher = Hasher::default();
+                        let lease = self.parent.leases.lease(manifest.blob);
+                        for slice in RawStream::chunked(self.chunks.clone(), manifest, lease) {
+                            hasher.update(&slice?);
+                        }
+                        hasher.finish()
+                    }
+                };
+                (manifest.len, checksum, CodecKind::Raw)
+            }
+            None => {
+                let (codec, bytes) = split_body(body)?;
+                (bytes.len() as u64, dedup::digest(bytes), codec)
+            }
+        };
+        let now = SystemTime::now();
+        Ok(DocumentMetadata {
+            created: now,
+            modified: now,
+            revision: 0,
+            size,
+            checksum: to_hex(&checksum),
+            codec,
+            content_type: None,
+        })
+    }
+
+    /// Stream the bytes of a serialized body, from the chunks tree if it is chunked
+    fn stream_body(&self, body: IVec) -> Result<RawStream, MangoChainsawError> {
+        let body = self.resolve(body)?;
+        match Manifest::from_body(&body)? {
+            Some(manifest) => {
+                let lease = self.parent.leases.lease(manifest.blob);
+                Ok(RawStream::chunked(self.chunks.clone(), manifest, lease))
+            }
+            None => {
+                let (codec, bytes) = untag_body(body)?;
+                Ok(RawStream::inline(bytes, codec))
+            }
+        }
+    }
+
+    /// Get the bytes of a serialized body, gathering its chunks if it is chunked
+    fn read_raw(&self, body: IVec) -> Result<IVec, MangoChainsawError> {
+        let body = self.resolve(body)?;
+        if Manifest::from_body(&body)?.is_none() {
+            return Ok(untag_body(body)?.1);
+        }
+        let mut stream = self.stream_body(body)?;
+        let mut bytes = Vec::with_capacity(stream.len() as usize);
+        stream.read_to_end(&mut bytes)?;
+        Ok(IVec::from(bytes))
+    }
+
+    /// Get the bucket's schemas, compiled once for as long as they don't change
+    fn validators(&self, settings: &BucketSettings) -> Result<Arc<Validators>, MangoChainsawError> {
+        self.parent.schemas.get(
+            &self.name,
+            settings.label_schema.as_ref(),
+            settings.body_schema.as_ref(),
+        )
+    }
+
+    /// Check a serialized body against the bucket's body schema. Bytes stored as they are,
+    /// inline or chunked, are read as JSON, the only codec a body schema is allowed with.
+    fn check_body(&self, validator: &BodyValidator, body: &[u8]) -> Result<(), MangoChainsawError> {
+        let body = self.resolve(IVec::from(body))?;
+        if Manifest::from_body(&body)?.is_some() {
+            return validator.validate_encoded(CodecKind::Json, &self.read_raw(body)?);
+        }
+        match split_body(&body)? {
+            (CodecKind::Raw, bytes) => validator.validate_encoded(CodecKind::Json, bytes),
+            (tagged, bytes) => validator.validate_encoded(tagged, bytes),
+        }
+    }
+
+    /// Deserialize a serialized body, gathering its chunks if it is chunked
+    fn decode<T>(&self, body: &IVec) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        let body = self.resolve(body.clone())?;
+        match Manifest::from_body(&body)? {
+            Some(_) => Raw::decode(&self.read_raw(body)?),
+            None => decode_body(&body),
+        }
+    }
+
+    /// Deserialize the body of a document that was just removed or replaced,
+    /// then release what it kept stored whether or not that worked
+    fn decode_and_release<T>(&self, body: IVec) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        let doc = self.decode(&body);
+        self.release_body(&body)?;
+        doc
+    }
+
+    /// Free what a body that was removed or replaced kept stored: the shared body it points to,
+    /// once nothing refers to that. Chunks are left for [`MangoChainsawBucket::collect_chunks`],
+    /// since streams opened before now may still be reading them.
+    fn release_body(&self, body: &[u8]) -> Result<(), MangoChainsawError> {
+        if let Some(digest) = dedup::pointer(body) {
+            let freed = self.transaction([&self.blobs, &self.blob_refs], |[blobs, refs]| {
+                dedup::collect(blobs, refs, &digest)
+            })?;
+            if freed.is_some() {
+                info!("Removed shared body");
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the chunks just written for a document if it was deduplicated
+    /// against an identical body that was already stored
+    fn release_duplicate(&self, id: Uuid, body: &[u8]) -> Result<(), MangoChainsawError> {
+        let Some(Manifest {
+            blob,
+            digest: Some(digest),
+            ..
+        }) = Manifest::from_body(body)?
+        else {
+            return Ok(());
+        };
+        let Some(raw) = self.documents.get(document_key(id))? else {
+            return Ok(());
+        };
+        if dedup::pointer(&unpack_document(raw)?.1) != Some(digest) {
+            return Ok(());
+        }
+        match self.blobs.get(digest)? {
+            Some(shared) if shared != body => {
+                info!(blob, "Removing chunks of a duplicate body");
+                large::remove_blob(&self.chunks, blob)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get an id for a new set of chunks, held until the writer is done with it
+    pub(crate) fn allocate_blob(&self) -> Result<Lease, MangoChainsawError> {
+        self.parent.leases.allocate(&*self.parent.storage)
+    }
+
+    /// Remove the chunks nothing refers to anymore: those of documents that were replaced or
+    /// deleted, and those of writers that never finished. Chunks are only removed once they
+    /// have gone unreferenced for `grace`, counted from the sweep that first found them, and
+    /// never while this process is reading or writing them. Returns the number of blobs removed.
+    #[instrument(skip(self))]
+    pub fn collect_chunks(&self, grace: Duration) -> Result<usize, MangoChainsawError> {
+        // Blobs allocated after the snapshot are left for the next sweep, as are those held
+        // in it, which covers writers that finish while this sweep looks for references
+        let (held, horizon) = self.parent.leases.snapshot(&*self.parent.storage)?;
+        let referenced = self.referenced_blobs()?;
+        let mut blobs = vec![];
+        for key in self.chunks.iter().keys() {
+            match large::chunk_blob(&key?) {
+                Some(blob) if blobs.last() != Some(&blob) => blobs.push(blob),
+                _ => {}
+            }
+        }
+        let now = now_millis()?;
+        let grace = grace.as_millis() as u64;
+        let mut orphans = Batch::default();
+        let mut removed = 0;
+        for blob in blobs {
+            if blob >= horizon || held.contains(&blob) || referenced.contains(&blob) {
+                continue;
+            }
+            // A stream may have read the manifest just before the blob was freed and not
+            // hold it yet, so a blob is only removed by a later sweep than the one that found it
+            match self
+                .orphans
+                .get(blob.to_be_bytes())?
+                .map(|since| decode_u64(&since))
+            {
+                Some(since)
+                    if since.saturating_add(grace) <= now && !self.parent.leases.is_held(blob) =>
+                {
+                    info!(blob, "Removing unreferenced chunks");
+                    large::remove_blob(&self.chunks, blob)?;
+                    removed += 1;
+                }
+                since => orphans.insert(&blob.to_be_bytes(), &since.unwrap_or(now).to_be_bytes()),
+            }
+        }
+        self.orphans.clear()?;
+        self.orphans.apply_batch(orphans)?;
+        Ok(removed)
+    }
+
+    /// Get the blobs that documents, their archived versions and shared bodies keep chunks in
+    fn referenced_blobs(&self) -> Result<HashSet<u64>, MangoChainsawError> {
+        let mut blobs = HashSet::new();
+        let mut add = |body: &[u8]| -> Result<(), MangoChainsawError> {
+            if let Some(manifest) = Manifest::from_body(body)? {
+                blobs.insert(manifest.blob);
+            }
+            Ok(())
+        };
+        for raw in self.documents.iter().values() {
+            add(&unpack_document(raw?)?.1)?;
+        }
+        for raw in self.history.iter().values() {
+            let version: Version = MangoChainsaw::de(raw?)?;
+            add(&unpack_document(IVec::from(version.raw))?.1)?;
+        }
+        for raw in self.blobs.iter().values() {
+            add(&raw?)?;
+        }
+        Ok(blobs)
+    }
+
+    /// Get the id of the document stored under a key
+    #[instrument(skip(self, key))]
+    pub fn key_id<K>(&self, key: K) -> Result<Option<Uuid>, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+    {
+        match self.keys.get(key.as_ref())? {
+            Some(idb) if !self.is_expired(&idb)? => Ok(Some(document_id(&idb)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the key a document is stored under, if it was stored under one
+    #[instrument(skip(self))]
+    pub fn id_key(&self, id: Uuid) -> Result<Option<Vec<u8>>, MangoChainsawError> {
+        let idb = document_key(id);
+        if self.is_expired(&idb)? {
+            return Ok(None);
+        }
+        Ok(self.key_names.get(idb)?.map(|key| key.to_vec()))
+    }
+
+    /// Insert a new document under a byte or string key.
+    /// Fails with [`MangoChainsawError::DuplicateKey`] if the key is taken.
+    /// The document still gets an id, which is what searches return.
+    #[instrument(skip(self, key, doc))]
+    pub fn insert_with_key<K, T>(
+        &self,
+        key: K,
+        doc: T,
+        labels: Vec<Label>,
+    ) -> Result<Uuid, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        T: Serialize,
+    {
+        self.insert_keyed(key.as_ref(), self.encode(&doc)?, labels, None)
+    }
+
+    /// Insert a serialized document under a key
+    fn insert_keyed(
+        &self,
+        key: &[u8],
+        doc: IVec,
+        labels: Vec<Label>,
+        content_type: Option<&str>,
+    ) -> Result<Uuid, MangoChainsawError> {
+        if let Some(idb) = self.keys.get(key)? {
+            // An expired document only holds on to its key until it is reaped
+            if !self.is_expired(&idb)? {
+                return Err(MangoChainsawError::DuplicateKey(key.to_vec()));
+            }
+            self.remove(document_id(&idb)?)?;
+        }
+        let settings = self.settings()?;
+        let id = self.next_id(&settings)?;
+        let ids = self.insert_inner(
+            vec![(id, Some(IVec::from(key)), doc, labels)],
+            settings.default_ttl,
+            content_type,
+        )?;
+        Ok(ids[0])
+    }
+
+    /// Store a document and its labels under a key, replacing whatever was stored there.
+    /// Returns the id of the document.
+    #[instrument(skip(self, key, doc))]
+    pub fn put<K, T>(&self, key: K, doc: T, labels: Vec<Label>) -> Result<Uuid, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        T: Serialize,
+    {
+        let target = WriteTarget::Key(key.as_ref().to_vec());
+        match self.commit_body(&target, self.encode(&doc)?, Some(labels), None)? {
+            Some((id, _rev)) => Ok(id),
+            None => Err(MangoChainsawError::Etc(
+                "keyed document vanished while it was stored".to_string(),
+            )),
+        }
+    }
+
+    /// Get the document stored under a key
+    #[instrument(skip(self, key))]
+    pub fn get_by_key<K, T>(&self, key: K) -> Result<Option<T>, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        T: DeserializeOwned,
+    {
+        match self.key_id(key)? {
+            Some(id) => self.get(id),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete the document stored under a key, freeing the key
+    #[instrument(skip(self, key))]
+    pub fn delete_by_key<K, T>(&self, key: K) -> Result<Option<T>, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        T: DeserializeOwned,
+    {
+        match self.key_id(key)? {
+            Some(id) => self.delete(id),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove documents, their labels and their expiry from the bucket in a single transaction.
+    /// Returns the serialized body of each removed document.
+    #[instrument(skip(self))]
+    fn remove_documents(&self, ids: &[Uuid]) -> Result<Vec<Option<IVec>>, MangoChainsawError> {
+        let settings = self.settings()?;
+        let mut idbs = Vec::with_capacity(ids.len());
+        for id in ids {
+            idbs.push(document_key(*id));
+        }
+        let output = self.transaction(
+            [
+                &self.documents,
+                &self.docs_labels,
+                &self.history,
+                &self.ttl,
+                &self.expiry,
+                &self.ordinal_ids,
+                &self.keys,
+                &self.key_names,
+                &self.labels_kev,
+                &self.labels_vek,
+                &self.bitmaps,
+                &self.range,
+                &self.ordinals,
+                &self.blob_refs,
+                &self.meta,
+                &self.usage,
+            ],
+            |trees| {
+                let [docs, labels, history, ttl, expiry, ordinal_ids, keys, key_names, index @ ..] =
+                    trees;
+                let [kev, vek, bitmaps, range, ordinals, refs, meta, usage] = index;
+                let index = LabelIndex {
+                    kev,
+                    vek,
+                    bitmaps,
+                    range,
+                    ordinals,
+                    usage: settings.quota.counts_usage().then_some(usage),
+                };
+                let (mut removed, mut bytes) = (0, 0);
+                let mut output = Vec::with_capacity(ids.len());
+                let mut label_ids: BTreeMap<Label, (Vec<Uuid>, Vec<u32>)> = BTreeMap::new();
+                let mut removed_ordinals = vec![];
+                for (id, idb) in ids.iter().zip(&idbs) {
+                    let ordinal = index.ordinal(idb)?;
+                    if let Some(ordinal) = ordinal {
+                        ordinals.remove(idb)?;
+                        ordinal_ids.remove(ordinal_key(ordinal))?;
+                        removed_ordinals.push(ordinal);
+                    }
+
+                    info!(id = id.to_string(), "deleting document labels");
+                    let doc_labels: Vec<Label> = match labels.remove(idb)? {
+                        Some(raw_labels) => MangoChainsaw::de(raw_labels)
+                            .map_err(ConflictableTransactionError::Abort)?,
+                        None => vec![],
+                    };
+                    for label in &doc_labels {
+                        let (ids, ordinals) = label_ids.entry(label.clone()).or_default();
+                        ids.push(*id);
+                        ordinals.extend(ordinal);
+                    }
+
+                    if let Some(expires_at) = ttl.remove(idb)? {
+                        expiry.remove(expiry_key(decode_u64(&expires_at), *id))?;
+                        info!(id = id.to_string(), "removed document expiry");
+                    }
+
+                    let key = key_names.remove(idb)?;
+                    if let Some(key) = &key {
+                        keys.remove(key)?;
+                        info!(id = id.to_string(), "removed document key");
+                    }
+
+                    info!(id = id.to_string(), "deleting document");
+                    if let Some(raw) = meta.remove(idb)? {
+                        let record: DocumentMetadata =
+                            MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?;
+                        removed += 1;
+                        bytes += record.size as i64;
+                    }
+                    match docs.remove(idb)? {
+                        Some(raw_doc) => {
+                            let (rev, body) = unpack_document(raw_doc.clone())
+                                .map_err(ConflictableTransactionError::Abort)?;
+                            if settings.keep_history {
+                                self.archive_version(
+                                    history,
+                                    *id,
+                                    rev,
+                                    &raw_doc,
+                                    &doc_labels,
+                                    key.as_deref(),
+                                )?;
+                            } else {
+                                dedup::release(refs, &body)?;
+                            }
+                            output.push(Some(body));
+                        }
+                        None => output.push(None),
+                    }
+                }
+                bitmap::remove(bitmaps, UNIVERSE, &removed_ordinals)?;
+                quota::charge(usage, &settings.quota, &self.name, -removed, -bytes)?;
+
+                info!("downserting ids from {} labels", label_ids.len());
+                for (label, (ids, ordinals)) in &label_ids {
+                    self.downsert_label(&index, label, ids, ordinals)?;
+                }
+                Ok(output)
+            },
+        )?;
+        info!("transaction complete");
+        for id in ids {
+            self.prune_history(*id, &settings)?;
+        }
+        Ok(output)
+    }
+
+    /// Check whether a document has outlived its TTL
+    fn is_expired(&self, idb: &[u8]) -> Result<bool, MangoChainsawError> {
+        match self.ttl.get(idb)? {
+            Some(expires_at) => Ok(decode_u64(&expires_at) <= now_millis()?),
+            None => Ok(false),
+        }
+    }
+
+    /// Get the time remaining before a document expires, if it has a TTL
+    #[instrument(skip(self))]
+    pub fn ttl(&self, id: Uuid) -> Result<Option<Duration>, MangoChainsawError> {
+        match self.ttl.get(document_key(id))? {
+            Some(expires_at) => {
+                let remaining = decode_u64(&expires_at).saturating_sub(now_millis()?);
+                Ok(Some(Duration::from_millis(remaining)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Remove every document whose TTL has passed, returning how many were removed
+    #[instrument(skip(self), ret)]
+    pub fn reap_expired(&self) -> Result<usize, MangoChainsawError> {
+        let end = expiry_key(now_millis()?, Uuid::max());
+        let mut expired = vec![];
+        for key in self.expiry.range(..=end).keys() {
+            let key = key?;
+            expired.push(Uuid::from_slice(&key[8..]).map_err(|e| {
+                MangoChainsawError::Etc(format!("invalid id in expiry index: {e}"))
+            })?);
+        }
+        let removed = self.remove_documents(&expired)?;
+        for body in removed.into_iter().flatten() {
+            self.release_body(&body)?;
+        }
+        if !expired.is_empty() {
+            info!("Reaped {} expired documents", expired.len());
+        }
+        Ok(expired.len())
+    }
+
+    /// Replace an existing document, keeping its id and labels.
+    /// Returns the previous document, or None if the id does not exist.
+    #[instrument(skip(self, doc))]
+    pub fn replace<T>(&self, id: Uuid, doc: T) -> Result<Option<T>, MangoChainsawError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let new = self.encode(&doc)?;
+        match self.swap_document(id, None, new.clone(), None, None)? {
+            Some((_rev, old)) => Ok(Some(self.decode_and_release(old)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace an existing document and its labels, keeping its id.
+    /// Returns the previous document, or None if the id does not exist.
+    #[instrument(skip(self, doc))]
+    pub fn replace_with_labels<T>(
+        &self,
+        id: Uuid,
+        doc: T,
+        labels: Vec<Label>,
+    ) -> Result<Option<T>, MangoChainsawError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let new = self.encode(&doc)?;
+        match self.swap_document(id, None, new.clone(), Some(labels), None)? {
+            Some((_rev, old)) => Ok(Some(self.decode_and_release(old)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Update an existing document in place with a function of its current value.
+    /// Returns the previous document, or None if the id does not exist.
+    /// The function is called again if the document changes before the update is written.
+    #[instrument(skip(self, f))]
+    pub fn update<T, F>(&self, id: Uuid, f: F) -> Result<Option<T>, MangoChainsawError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Fn(T) -> T,
+    {
+        let codec = self.settings()?.codec;
+        loop {
+            let Some((rev, raw)) = self.raw_document(id)? else {
+                return Ok(None);
+            };
+            let new = encode_body(codec, &f(self.decode(&raw)?))?;
+            match self.swap_document(id, Some(rev), new, None, None) {
+                Ok(Some((_rev, old))) => return Ok(Some(self.decode_and_release(old)?)),
+                Ok(None) => return Ok(None),
+                Err(MangoChainsawError::Conflict { .. }) => {
+                    info!(rev, "Document changed while updating, trying again");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Replace a document only if its current revision is `expected_rev`.
+    /// Returns the new revision, or `MangoChainsawError::Conflict` if the document
+    /// has moved on or does not exist.
+    #[instrument(skip(self, doc))]
+    pub fn compare_and_swap<T>(
+        &self,
+        id: Uuid,
+        expected_rev: u64,
+        doc: T,
+    ) -> Result<u64, MangoChainsawError>
+    where
+        T: Serialize,
+    {
+        let new = self.encode(&doc)?;
+        match self.swap_document(id, Some(expected_rev), new.clone(), None, None)? {
+            Some((rev, old)) => {
+                self.release_body(&old)?;
+                Ok(rev + 1)
+            }
+            None => Err(MangoChainsawError::Conflict {
+                id,
+                expected: expected_rev,
+                found: None,
+            }),
+        }
+    }
+
+    /// Replace a document and its labels only if its current revision is `expected_rev`.
+    /// Returns the new revision, or `MangoChainsawError::Conflict` if the document
+    /// has moved on or does not exist.
+    #[instrument(skip(self, doc))]
+    pub fn compare_and_swap_with_labels<T>(
+        &self,
+        id: Uuid,
+        expected_rev: u64,
+        doc: T,
+        labels: Vec<Label>,
+    ) -> Result<u64, MangoChainsawError>
+    where
+        T: Serialize,
+    {
+        let new = self.encode(&doc)?;
+        match self.swap_document(id, Some(expected_rev), new.clone(), Some(labels), None)? {
+            Some((rev, old)) => {
+                self.release_body(&old)?;
+                Ok(rev + 1)
+            }
+            None => Err(MangoChainsawError::Conflict {
+                id,
+                expected: expected_rev,
+                found: None,
+            }),
+        }
+    }
+
+    /// Swap the stored bytes of an existing document, optionally replacing its labels
+    /// and content type, and optionally requiring a specific current revision.
+    /// `Some(None)` clears the content type; None keeps it.
+    /// Returns the previous revision and serialized document.
+    fn swap_document(
+        &self,
+        id: Uuid,
+        expected_rev: Option<u64>,
+        new: IVec,
+        labels: Option<Vec<Label>>,
+        content_type: Option<Option<&str>>,
+    ) -> Result<Option<(u64, IVec)>, MangoChainsawError> {
+        let settings = self.settings()?;
+        let validators = self.validators(&settings)?;
+        if let (Some(validator), Some(labels)) = (&validators.labels, &labels) {
+            validator.validate(labels)?;
+        }
+        if let Some(validator) = &validators.body {
+            self.check_body(validator, &new)?;
+        }
+        let described = self.describe(&new)?;
+        let size = described.size;
+        quota::check_size(&settings.quota, &self.name, size)?;
+        if let Some(labels) = &labels {
+            quota::check_label_count(&settings.quota, &self.name, labels.len())?;
+        }
+        let digest = match settings.dedup {
+            true => dedup::body_digest(&new)?,
+            false => None,
+        };
+        let idb = document_key(id);
+        let now = now_millis()?;
+        let previous = self.transaction(
+            [
+                &self.documents,
+                &self.docs_labels,
+                &self.labels_kev,
+                &self.labels_vek,
+                &self.history,
+                &self.bitmaps,
+                &self.range,
+                &self.ordinals,
+                &self.blobs,
+                &self.blob_refs,
+                &self.meta,
+                &self.usage,
+                &self.ttl,
+            ],
+            |trees| {
+                let [docs, docs_labels, kev, vek, history, bitmaps, range, ordinals, index @ ..] =
+                    trees;
+                let [blobs, refs, meta, usage, ttl] = index;
+                let index = LabelIndex {
+                    kev,
+                    vek,
+                    bitmaps,
+                    range,
+                    ordinals,
+                    usage: settings.quota.counts_usage().then_some(usage),
+                };
+                let Some(raw_old) = docs.get(&idb)? else {
+                    info!("Document not found, nothing to replace");
+                    return Ok(None);
+                };
+                // An expired document is gone, even if the reaper hasn't removed it yet
+                if let Some(expires_at) = ttl.get(&idb)? {
+                    if decode_u64(&expires_at) <= now {
+                        info!("Document has expired, nothing to replace");
+                        return Ok(None);
+                    }
+                }
+                let (rev, old) = unpack_document(raw_old.clone())
+                    .map_err(ConflictableTransactionError::Abort)?;
+                if let Some(expected) = expected_rev {
+                    if expected != rev {
+                        warn!(rev, expected, "Revision mismatch");
+                        return Err(ConflictableTransactionError::Abort(
+                            MangoChainsawError::Conflict {
+                                id,
+                                expected,
+                                found: Some(rev),
+                            },
+                        ));
+                    }
+                }
+                let strategy = self.strategy(&settings);
+                let replaced = self.rewrite_metadata(
+                    meta,
+                    id,
+                    rev + 1,
+                    described.clone(),
+                    content_type,
+                    strategy,
+                )?;
+                let grown = size as i64 - replaced.unwrap_or(0) as i64;
+                quota::charge(usage, &settings.quota, &self.name, 0, grown)?;
+                let new = match &digest {
+                    Some(digest) => dedup::reference(blobs, refs, digest, &new)?,
+                    None => new.clone(),
+                };
+                docs.insert(&idb, pack_document(rev + 1, &new))?;
+                info!(
+                    rev = rev + 1,
+                    "Replaced document pending transaction completion"
+                );
+
+                let old_labels: Vec<Label> = match docs_labels.get(&idb)? {
+                    Some(raw) => {
+                        MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?
+                    }
+                    None => vec![],
+                };
+                if settings.keep_history {
+                    self.archive_version(history, id, rev, &raw_old, &old_labels, None)?;
+                } else {
+                    dedup::release(refs, &old)?;
+                }
+
+                if let Some(labels) = &labels {
+                    let ordinal = index.ordinal(&idb)?;
+                    for label in old_labels.iter().filter(|l| !labels.contains(l)) {
+                        self.downsert_label(&index, label, &[id], ordinal.as_slice())?;
+                    }
+                    let mut grew = false;
+                    for label in labels.iter().filter(|l| !old_labels.contains(l)) {
+                        grew |= self.upsert_label(&index, label, &[id], ordinal.as_slice())?;
+                    }
+                    if grew {
+                        quota::check_labels(usage, &settings.quota, &self.name)?;
+                    }
+                    let new_labels =
+                        MangoChainsaw::ser(labels).map_err(ConflictableTransactionError::Abort)?;
+                    docs_labels.insert(&idb, new_labels)?;
+                    info!("Replaced document labels pending transaction completion");
+                }
+                Ok(Some((rev, old)))
+            },
+        )?;
+        info!("transaction complete");
+        self.prune_history(id, &settings)?;
+        Ok(previous)
+    }
+
+    /// List the revisions available for a document, oldest first.
+    /// This includes archived versions and the current revision if the document exists.
+    #[instrument(skip(self), ret)]
+    pub fn list_versions(&self, id: Uuid) -> Result<Vec<u64>, MangoChainsawError> {
+        let mut results = vec![];
+        for key in self.history.scan_prefix(id.as_bytes()).keys() {
+            let key = key?;
+            results.push(decode_u64(&key[key.len() - REVISION_LEN..]));
+        }
+        if let Some(rev) = self.revision(id)? {
+            results.push(rev);
+        }
+        Ok(results)
     }
 
-    /// Get a document by id
+    /// Get a specific revision of a document, whether archived or current
     #[instrument(skip(self))]
-    pub fn get<T>(&self, id: Uuid) -> Result<Option<T>, MangoChainsawError>
+    pub fn get_version<T>(&self, id: Uuid, rev: u64) -> Result<Option<T>, MangoChainsawError>
     where
         T: DeserializeOwned,
     {
-        match self.documents.get(MangoChainsaw::ser(id.as_u64_pair())?) {
-            Ok(Some(thing)) => {
-                info!("Found object");
-                let out: T = MangoChainsaw::de(thing)?;
-                info!("Deserialized object");
-                Ok(Some(out))
+        if let Some(raw) = self.history.get(version_key(id, rev))? {
+            let version: Version = MangoChainsaw::de(raw)?;
+            let (_rev, body) = unpack_document(IVec::from(version.raw))?;
+            return Ok(Some(self.decode(&body)?));
+        }
+        match self.get_with_revision(id)? {
+            Some((doc, current)) if current == rev => Ok(Some(doc)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Restore a document and its labels to an archived revision.
+    /// This works for deleted and expired documents too, which get back the key they had,
+    /// unless another document has taken it since, and the bucket's default TTL from now.
+    /// The restored document gets a new revision, which is returned, or None if the revision
+    /// is not in the history. Like any other write, the revision has to match the bucket's
+    /// schemas and quota as they are now.
+    #[instrument(skip(self))]
+    pub fn restore(&self, id: Uuid, rev: u64) -> Result<Option<u64>, MangoChainsawError> {
+        let settings = self.settings()?;
+        let Some(raw) = self.history.get(version_key(id, rev))? else {
+            info!("Version not found");
+            return Ok(None);
+        };
+        let version: Version = MangoChainsaw::de(raw)?;
+        let (_rev, body) = unpack_document(IVec::from(version.raw))?;
+        // The schemas may have changed since the version was written
+        let validators = self.validators(&settings)?;
+        if let Some(validator) = &validators.labels {
+            validator.validate(&version.labels)?;
+        }
+        if let Some(validator) = &validators.body {
+            self.check_body(validator, &body)?;
+        }
+        let described = self.describe(&body)?;
+        quota::check_size(&settings.quota, &self.name, described.size)?;
+        quota::check_label_count(&settings.quota, &self.name, version.labels.len())?;
+        let latest = self.list_versions(id)?.last().copied().unwrap_or(rev);
+        let idb = document_key(id);
+        // A deleted document gave up its ordinal, so it needs a new one to be restored.
+        // Ordinals are never reused, so this one is skipped if the transaction aborts.
+        let spare_ordinal = match self.ordinals.get(&idb)? {
+            Some(_) => None,
+            None => Some(self.allocate_ordinals(1)?),
+        };
+        let now = now_millis()?;
+        let expires_at = settings
+            .default_ttl
+            .map(|ttl| now.saturating_add(ttl.as_millis() as u64));
+
+        let (new_rev, replaced) = self.transaction(
+            [
+                &self.documents,
+                &self.docs_labels,
+                &self.labels_kev,
+                &self.labels_vek,
+                &self.history,
+                &self.bitmaps,
+                &self.range,
+                &self.ordinals,
+                &self.ordinal_ids,
+                &self.blob_refs,
+                &self.meta,
+                &self.usage,
+                &self.keys,
+                &self.key_names,
+                &self.ttl,
+                &self.expiry,
+            ],
+            |trees| {
+                let [docs, docs_labels, kev, vek, history, bitmaps, range, ordinals, index @ ..] =
+                    trees;
+                let [ordinal_ids, refs, meta, usage, keys, key_names, ttl, expiry] = index;
+                let index = LabelIndex {
+                    kev,
+                    vek,
+                    bitmaps,
+                    range,
+                    ordinals,
+                    usage: settings.quota.counts_usage().then_some(usage),
+                };
+                let current_labels: Vec<Label> = match docs_labels.get(&idb)? {
+                    Some(raw) => {
+                        MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?
+                    }
+                    None => vec![],
+                };
+                let (new_rev, replaced) = match docs.get(&idb)? {
+                    Some(raw_current) => {
+                        let (current, current_body) = unpack_document(raw_current.clone())
+                            .map_err(ConflictableTransactionError::Abort)?;
+                        if settings.keep_history {
+                            self.archive_version(
+                                history,
+                                id,
+                                current,
+                                &raw_current,
+                                &current_labels,
+                                None,
+                            )?;
+                        } else {
+                            dedup::release(refs, &current_body)?;
+                        }
+                        (current + 1, Some(current_body))
+                    }
+                    None => (latest + 1, None),
+                };
+                if replaced.is_none() {
+                    self.restore_key(history, keys, key_names, id, latest)?;
+                }
+                self.restart_expiry(ttl, expiry, id, replaced.is_none(), now, expires_at)?;
+                // The archived version keeps its own reference to a shared body
+                dedup::retain(refs, &body)?;
+                docs.insert(&idb, pack_document(new_rev, &body))?;
+                let strategy = self.strategy(&settings);
+                let replaced_size =
+                    self.rewrite_metadata(meta, id, new_rev, described.clone(), None, strategy)?;
+                let restored = match replaced_size {
+                    Some(_) => 0,
+                    None => 1,
+                };
+                let grown = described.size as i64 - replaced_size.unwrap_or(0) as i64;
+                quota::charge(usage, &settings.quota, &self.name, restored, grown)?;
+
+                let ordinal = match (index.ordinal(&idb)?, spare_ordinal) {
+                    (Some(ordinal), _) => Some(ordinal),
+                    (None, Some(ordinal)) => {
+                        ordinals.insert(&idb, &ordinal_key(ordinal))?;
+                        ordinal_ids.insert(ordinal_key(ordinal), id.as_bytes())?;
+                        bitmap::insert(bitmaps, UNIVERSE, &[ordinal])?;
+                        Some(ordinal)
+                    }
+                    (None, None) => None,
+                };
+                for label in current_labels
+                    .iter()
+                    .filter(|l| !version.labels.contains(l))
+                {
+                    self.downsert_label(&index, label, &[id], ordinal.as_slice())?;
+                }
+                let mut grew = false;
+                for label in version
+                    .labels
+                    .iter()
+                    .filter(|l| !current_labels.contains(l))
+                {
+                    grew |= self.upsert_label(&index, label, &[id], ordinal.as_slice())?;
+                }
+                if grew {
+                    quota::check_labels(usage, &settings.quota, &self.name)?;
+                }
+                let labels = MangoChainsaw::ser(&version.labels)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                docs_labels.insert(&idb, labels)?;
+                info!(new_rev, "Restored document pending transaction completion");
+                Ok((new_rev, replaced))
+            },
+        )?;
+        info!("transaction complete");
+        if let Some(replaced) = replaced {
+            self.release_body(&replaced)?;
+        }
+        self.prune_history(id, &settings)?;
+        Ok(Some(new_rev))
+    }
+
+    /// Give a restored document back the key it had when it was deleted, as recorded
+    /// with its latest version. Aborts if another document has taken the key since.
+    fn restore_key(
+        &self,
+        history: &dyn TransactionalTree,
+        keys: &dyn TransactionalTree,
+        key_names: &dyn TransactionalTree,
+        id: Uuid,
+        latest: u64,
+    ) -> ConflictableTransactionResult<(), MangoChainsawError> {
+        let Some(raw) = history.get(version_key(id, latest))? else {
+            return Ok(());
+        };
+        let version: Version =
+            MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?;
+        let Some(key) = version.key else {
+            return Ok(());
+        };
+        let idb = document_key(id);
+        match keys.get(&key)? {
+            Some(owner) if owner != idb => Err(ConflictableTransactionError::Abort(
+                MangoChainsawError::DuplicateKey(key),
+            )),
+            _ => {
+                keys.insert(key.as_slice(), &idb)?;
+                key_names.insert(&idb, key)?;
+                info!(id = id.to_string(), "Restored document key");
+                Ok(())
             }
-            Ok(None) => {
-                info!("Object not found");
-                Ok(None)
+        }
+    }
+
+    /// Start a restored document's expiry over at `expires_at` if it was deleted or had
+    /// expired, so it isn't reaped as soon as it is back. A live document keeps its expiry.
+    fn restart_expiry(
+        &self,
+        ttl: &dyn TransactionalTree,
+        expiry: &dyn TransactionalTree,
+        id: Uuid,
+        deleted: bool,
+        now: u64,
+        expires_at: Option<u64>,
+    ) -> ConflictableTransactionResult<(), MangoChainsawError> {
+        let idb = document_key(id);
+        let current = ttl.get(&idb)?.map(|raw| decode_u64(&raw));
+        if !deleted && current.is_none_or(|current| current > now) {
+            return Ok(());
+        }
+        if let Some(current) = current {
+            ttl.remove(&idb)?;
+            expiry.remove(expiry_key(current, id))?;
+        }
+        if let Some(expires_at) = expires_at {
+            ttl.insert(&idb, &expires_at.to_be_bytes())?;
+            expiry.insert(expiry_key(expires_at, id), &idb)?;
+        }
+        Ok(())
+    }
+
+    /// Record the metadata of a document's new revision, keeping its creation time
+    /// and content type. Returns the size of the revision it replaced, if there was one.
+    fn rewrite_metadata(
+        &self,
+        meta: &dyn TransactionalTree,
+        id: Uuid,
+        revision: u64,
+        described: DocumentMetadata,
+        content_type: Option<Option<&str>>,
+        strategy: IdStrategy,
+    ) -> ConflictableTransactionResult<Option<u64>, MangoChainsawError> {
+        let idb = document_key(id);
+        let (record, replaced) = match meta.get(&idb)? {
+            Some(raw) => {
+                let previous: DocumentMetadata =
+                    MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?;
+                let record = DocumentMetadata {
+                    created: previous.created,
+                    content_type: match content_type {
+                        Some(content_type) => content_type.map(str::to_string),
+                        None => previous.content_type,
+                    },
+                    revision,
+                    ..described
+                };
+                (record, Some(previous.size))
             }
-            Err(e) => {
-                error!(error = format!("{e}"), "Failed to lookup object");
-                Err(e.into())
+            None => {
+                let record = DocumentMetadata {
+                    created: created_at(strategy, id).unwrap_or(described.created),
+                    content_type: content_type.flatten().map(str::to_string),
+                    revision,
+                    ..described
+                };
+                (record, None)
+            }
+        };
+        let record = MangoChainsaw::ser(&record).map_err(ConflictableTransactionError::Abort)?;
+        meta.insert(&idb, record)?;
+        Ok(replaced)
+    }
+
+    /// Store a prior version of a document in the history tree
+    #[instrument(skip(self, history, raw, labels))]
+    fn archive_version(
+        &self,
+        history: &dyn TransactionalTree,
+        id: Uuid,
+        rev: u64,
+        raw: &[u8],
+        labels: &[Label],
+        key: Option<&[u8]>,
+    ) -> Result<(), UnabortableTransactionError> {
+        let version = Version {
+            labels: labels.to_vec(),
+            raw: raw.to_vec(),
+            key: key.map(<[u8]>::to_vec),
+        };
+        let version = MangoChainsaw::ser(&version).map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::ReportableBug(e.to_string()))
+        })?;
+        history.insert(version_key(id, rev), version)?;
+        info!("Archived version");
+        Ok(())
+    }
+
+    /// Remove the oldest versions of a document beyond the bucket's retention limit
+    #[instrument(skip(self, settings))]
+    fn prune_history(&self, id: Uuid, settings: &BucketSettings) -> Result<(), MangoChainsawError> {
+        let Some(max) = settings.max_versions else {
+            return Ok(());
+        };
+        let keys = self
+            .history
+            .scan_prefix(id.as_bytes())
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in keys.iter().take(keys.len().saturating_sub(max)) {
+            let removed =
+                self.transaction([&self.history, &self.blob_refs], |[history, refs]| {
+                    let Some(raw) = history.remove(key)? else {
+                        return Ok(None);
+                    };
+                    let version: Version =
+                        MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort)?;
+                    let (_rev, body) = unpack_document(IVec::from(version.raw))
+                        .map_err(ConflictableTransactionError::Abort)?;
+                    dedup::release(refs, &body)?;
+                    Ok(Some(body))
+                })?;
+            if let Some(body) = removed {
+                self.release_body(&body)?;
             }
+            info!("Pruned version");
         }
+        Ok(())
     }
 
-    /// Get many documents by id
-    #[instrument(skip(self))]
-    pub fn get_many<T>(&self, ids: Vec<Uuid>) -> Result<Vec<(Uuid, Option<T>)>, MangoChainsawError>
-    where
-        T: DeserializeOwned,
-    {
-        let mut results = vec![];
-        for id in &ids {
-            results.push((*id, self.get(*id)?));
+    /// Get the ID's for all documents matching all given labels.
+    /// A label that doesn't exist matches nothing, and so does an empty list of labels.
+    #[instrument(skip(self), ret)]
+    pub fn search_inclusive(&self, labels: Vec<Label>) -> Result<Vec<Uuid>, MangoChainsawError> {
+        if labels.is_empty() {
+            return Ok(vec![]);
         }
-        Ok(results)
+        self.query(&Query::all_of(&labels))
     }
 
-    /// Get labels for a given document id
-    #[instrument(skip(self))]
-    pub fn get_document_labels(&self, id: Uuid) -> Result<Option<Vec<Label>>, MangoChainsawError> {
-        match self.docs_labels.get(MangoChainsaw::ser(id.as_u64_pair())?) {
-            Ok(Some(thing)) => {
-                let labels: Vec<Label> = MangoChainsaw::de(thing)?;
-                info!("Found {} labels for document", labels.len());
-                Ok(Some(labels))
+    /// Get the ID's for all documents matching any of the given labels
+    #[instrument(skip(self), ret)]
+    pub fn search_any(&self, labels: Vec<Label>) -> Result<Vec<Uuid>, MangoChainsawError> {
+        self.query(&Query::any_of(&labels))
+    }
+
+    /// Get the ID's for all documents matching all of `include` and none of `exclude`.
+    /// With no `include` labels, every document in the bucket is a candidate.
+    #[instrument(skip(self), ret)]
+    pub fn search_excluding(
+        &self,
+        include: Vec<Label>,
+        exclude: Vec<Label>,
+    ) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let mut queries: Vec<Query> = include.iter().map(Query::from).collect();
+        queries.push(!Query::any_of(&exclude));
+        self.query(&Query::And(queries))
+    }
+
+    /// Get the ID's for all documents matching a query
+    #[instrument(skip(self), ret)]
+    pub fn query(&self, query: &Query) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let matches = query.evaluate(self)?;
+        info!("Query matched {} ordinals", matches.len());
+        self.resolve_ordinals(&matches)
+    }
+
+    /// Get a page of the ID's for documents matching a query, continuing from `cursor`.
+    /// Pages are in insertion order rather than sorted by id, and hold at most `limit` ids.
+    #[instrument(skip(self), ret)]
+    pub fn query_page(
+        &self,
+        query: &Query,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Page, MangoChainsawError> {
+        let limit = limit.max(1);
+        let after = match cursor {
+            Some(cursor) => match cursor.position(CursorKind::Ordinal)? {
+                position if position.len() == 4 => Bound::Excluded(decode_ordinal(position)),
+                _ => return Err(MangoChainsawError::InvalidCursor(cursor.to_string())),
+            },
+            None => Bound::Unbounded,
+        };
+        let matches = query.evaluate(self)?;
+
+        let mut ids = Vec::with_capacity(limit);
+        let mut last = None;
+        for ordinal in matches.range((after, Bound::Unbounded)) {
+            let Some(id) = self.ordinal_id(ordinal)? else {
+                continue;
+            };
+            if self.is_expired(&document_key(id))? {
+                continue;
             }
-            Ok(None) => {
-                info!("Document not found or document has no labels");
-                Ok(None)
+            // Only hand out a cursor when there is something after the page
+            if ids.len() == limit {
+                return Ok(Page {
+                    ids,
+                    next: last.map(|o| Cursor::new(CursorKind::Ordinal, &ordinal_key(o))),
+                });
             }
-            Err(e) => {
-                error!("Error looking up document labels");
-                Err(e.into())
+            ids.push(id);
+            last = Some(ordinal);
+        }
+        Ok(Page { ids, next: None })
+    }
+
+    /// Get a page of the ID's for every document in the bucket, continuing from `cursor`.
+    /// Pages hold at most `limit` ids.
+    #[instrument(skip(self), ret)]
+    pub fn scan(&self, cursor: Option<&Cursor>, limit: usize) -> Result<Page, MangoChainsawError> {
+        let limit = limit.max(1);
+        let after = match cursor {
+            Some(cursor) => Bound::Excluded(cursor.position(CursorKind::Document)?.to_vec()),
+            None => Bound::Unbounded,
+        };
+
+        let mut ids = Vec::with_capacity(limit);
+        let mut last: Option<IVec> = None;
+        for key in self.documents.range((after, Bound::Unbounded)).keys() {
+            let key = key?;
+            if self.is_expired(&key)? {
+                continue;
             }
+            // Only hand out a cursor when there is something after the page
+            if ids.len() == limit {
+                return Ok(Page {
+                    ids,
+                    next: last.map(|k| Cursor::new(CursorKind::Document, &k)),
+                });
+            }
+            ids.push(document_id(&key)?);
+            last = Some(key);
         }
+        Ok(Page { ids, next: None })
     }
 
-    /// Insert a new document with a given set of identifying labels
-    #[instrument(skip(self, doc), fields(id))]
-    pub fn insert<T>(&self, doc: T, labels: Vec<Label>) -> Result<Uuid, MangoChainsawError>
+    /// Get a page of the keys that start with `prefix`, in byte order.
+    /// Pass the `next` cursor of one page to get the page after it.
+    #[instrument(skip(self, prefix))]
+    pub fn scan_keys<P>(
+        &self,
+        prefix: P,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<KeyPage, MangoChainsawError>
     where
-        T: Serialize,
+        P: AsRef<[u8]>,
     {
-        let id = self.parent.next_id()?;
-        let id_ivec = MangoChainsaw::ser(id.as_u64_pair())?;
-        info!(id = id.to_string(), "Preparing document");
+        let prefix = prefix.as_ref();
+        let limit = limit.max(1);
+        let start = match cursor {
+            Some(cursor) if cursor.position(CursorKind::Key)? >= prefix => {
+                Bound::Excluded(cursor.position(CursorKind::Key)?.to_vec())
+            }
+            _ => Bound::Included(prefix.to_vec()),
+        };
 
-        let document = (id_ivec.clone(), MangoChainsaw::ser(&doc)?);
-        let doclbl = (id_ivec.clone(), MangoChainsaw::ser(&labels)?);
-        info!(id = id.to_string(), "Doc size: {}", document.1.len());
-        let mut all_labels = vec![];
-        for label in &labels {
-            all_labels.push((label.as_bytes(), label.as_bytes_rev()));
+        let mut keys = Vec::with_capacity(limit);
+        let mut last: Option<IVec> = None;
+        for result in self.keys.range((start, Bound::Unbounded)) {
+            let (key, idb) = result?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if self.is_expired(&idb)? {
+                continue;
+            }
+            // Only hand out a cursor when there is something after the page
+            if keys.len() == limit {
+                return Ok(KeyPage {
+                    keys,
+                    next: last.map(|k| Cursor::new(CursorKind::Key, &k)),
+                });
+            }
+            keys.push(key.to_vec());
+            last = Some(key);
         }
-        info!(id = id.to_string(), "Prepared {} labels", all_labels.len());
-
-        (
-            &self.documents,
-            &self.docs_labels,
-            &self.labels_kev,
-            &self.labels_vek,
-        )
-            .transaction(|(docs, docs_labels, kev, vek)| {
-                docs.insert(&document.0, &document.1)?;
-                docs_labels.insert(&doclbl.0, &doclbl.1)?;
-                info!(
-                    id = id.to_string(),
-                    "Inserted document and document labels pending transaction completion"
-                );
+        Ok(KeyPage { keys, next: None })
+    }
 
-                for (label_kev, label_vek) in &all_labels {
-                    self.upsert_label(kev, label_kev, id.as_u64_pair())?;
-                    self.upsert_label(vek, label_vek, id.as_u64_pair())?;
-                    info!(id = id.to_string(), "Upserted label");
-                }
+    /// Get the ID's for all documents created at or after `t0` and before `t1`, oldest first.
+    /// Ids supplied with `insert_with_id` are placed by whatever timestamp their bytes hold.
+    #[instrument(skip(self), ret)]
+    pub fn scan_created_between(
+        &self,
+        t0: SystemTime,
+        t1: SystemTime,
+    ) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let strategy = self.id_strategy()?;
+        let (start, end) = (created_at_key(strategy, t0), created_at_key(strategy, t1));
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let mut ids = vec![];
+        for key in self.documents.range(start..end).keys() {
+            let key = key?;
+            if !self.is_expired(&key)? {
+                ids.push(document_id(&key)?);
+            }
+        }
+        Ok(ids)
+    }
 
-                info!(id = id.to_string(), "Transaction complete");
-                Ok(())
-            })?;
+    /// Get the ID's for the `n` most recently created documents, newest first
+    #[instrument(skip(self), ret)]
+    pub fn newest(&self, n: usize) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let mut ids = Vec::with_capacity(n);
+        for result in self.documents.iter().rev() {
+            if ids.len() == n {
+                break;
+            }
+            let (key, _val) = result?;
+            if !self.is_expired(&key)? {
+                ids.push(document_id(&key)?);
+            }
+        }
+        Ok(ids)
+    }
 
-        Ok(id)
+    /// Get the ordinals of every document with a label
+    pub(crate) fn label_bitmap(&self, label: &Label) -> Result<RoaringBitmap, MangoChainsawError> {
+        bitmap::load(&self.bitmaps, &label.as_bytes())
     }
 
-    /// Delete a document from the bucket
-    #[instrument(skip(self))]
-    pub fn delete<T>(&self, id: Uuid) -> Result<Option<T>, MangoChainsawError>
+    /// Get the ID's for all documents with a label for `key` whose typed value is in `range`.
+    /// Integers and floats compare with each other, other values only with values of
+    /// the same type, see [`LabelValue`].
+    #[instrument(skip(self, range), ret)]
+    pub fn range_search<R>(&self, key: &str, range: R) -> Result<Vec<Uuid>, MangoChainsawError>
     where
-        T: DeserializeOwned,
+        R: RangeBounds<LabelValue>,
     {
-        let output: RefCell<Option<T>> = RefCell::new(None);
-        let idb = MangoChainsaw::ser(id.as_u64_pair())?;
-        (
-            &self.documents,
-            &self.labels_kev,
-            &self.labels_vek,
-            &self.docs_labels,
-        )
-            .transaction(|(docs, kev, vek, labels)| {
-                info!("deleting document");
-                if let Some(raw_doc) = docs.remove(&idb)? {
-                    let result: T = MangoChainsaw::de(raw_doc).map_err(|e| {
-                        UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                            e.to_string(),
-                        ))
-                    })?;
-                    *output.borrow_mut() = Some(result);
-                }
-                info!("deleting document labels");
-                if let Some(raw_labels) = labels.remove(&idb)? {
-                    let labels: Vec<Label> = MangoChainsaw::de(raw_labels).map_err(|e| {
-                        UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                            e.to_string(),
-                        ))
-                    })?;
-                    info!("downserting id from labels");
-                    for label in labels {
-                        self.downsert_label(kev, &label.as_bytes(), id.as_u64_pair())?;
-                        self.downsert_label(vek, &label.as_bytes_rev(), id.as_u64_pair())?;
-                    }
-                }
-                Ok(())
-            })?;
-        info!("transaction complete");
-        Ok(RefCell::into_inner(output))
+        let matches = self.range_bitmap(key, range.start_bound(), range.end_bound())?;
+        self.resolve_ordinals(&matches)
     }
 
-    /// Get the ID's for all documents matching all given labels
-    #[instrument(skip(self), ret)]
-    pub fn search_inclusive(&self, labels: Vec<Label>) -> Result<Vec<Uuid>, MangoChainsawError> {
-        let mut results = vec![];
+    /// Get the ordinals of every document with a value for `key` within the bounds
+    pub(crate) fn range_bitmap(
+        &self,
+        key: &str,
+        start: Bound<&LabelValue>,
+        end: Bound<&LabelValue>,
+    ) -> Result<RoaringBitmap, MangoChainsawError> {
+        range::load(&self.range, key, start, end)
+    }
 
-        let mut middle = vec![];
-        for label in labels {
-            match self.labels_kev.get(&label.as_bytes()) {
-                Ok(Some(thing)) => {
-                    let ids: Vec<(u64, u64)> = MangoChainsaw::de(thing)?;
-                    let ids: Vec<Uuid> = ids
-                        .into_iter()
-                        .map(|id| Uuid::from_u64_pair(id.0, id.1))
-                        .collect();
-                    info!(
-                        label = format!("{label}"),
-                        "Found {} ids for label",
-                        ids.len()
-                    );
-                    results.extend(&ids);
-                    middle.push(ids);
-                }
-                Ok(None) => {
-                    info!(label = format!("{label}"), "Label does not exist");
-                }
-                Err(_e) => {
-                    error!(label = format!("{label}"), "Error looking up label");
-                }
-            }
+    /// Get the ordinals of every document with a label starting with `prefix`
+    pub(crate) fn prefix_bitmap(&self, prefix: &str) -> Result<RoaringBitmap, MangoChainsawError> {
+        bitmap::load_prefix(&self.bitmaps, prefix.as_bytes())
+    }
+
+    /// Get the ordinals of every document in the bucket
+    pub(crate) fn all_ordinals(&self) -> Result<RoaringBitmap, MangoChainsawError> {
+        bitmap::load(&self.bitmaps, UNIVERSE)
+    }
+
+    /// Turn a set of ordinals into sorted document ids, leaving out expired documents
+    pub(crate) fn resolve_ordinals(
+        &self,
+        ordinals: &RoaringBitmap,
+    ) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let mut results = Vec::with_capacity(ordinals.len() as usize);
+        for ordinal in ordinals {
+            results.extend(self.ordinal_id(ordinal)?);
         }
         results.sort();
-        results.dedup();
+        self.retain_unexpired(&mut results)?;
+        Ok(results)
+    }
 
-        for list in middle {
-            // Keep only the id's that are in all of the labels
-            results.retain(|id| list.contains(id))
+    /// Get the id of the document with an ordinal
+    fn ordinal_id(&self, ordinal: u32) -> Result<Option<Uuid>, MangoChainsawError> {
+        match self.ordinal_ids.get(ordinal_key(ordinal))? {
+            Some(raw) => Ok(Some(Uuid::from_slice(&raw).map_err(|e| {
+                MangoChainsawError::Etc(format!("invalid id for ordinal {ordinal}: {e}"))
+            })?)),
+            None => {
+                warn!(ordinal, "Ordinal has no document");
+                Ok(None)
+            }
         }
+    }
 
-        Ok(results)
+    /// Get the sorted ids of every document with a label, straight from its postings
+    fn label_ids(&self, label: &Label) -> Result<Vec<Uuid>, MangoChainsawError> {
+        let mut ids = vec![];
+        for key in self
+            .labels_kev
+            .scan_prefix(posting_prefix(&label.as_bytes()))
+            .keys()
+        {
+            if let Some((_label, id)) = split_posting_key(&key?) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Drop expired documents from a list of ids
+    fn retain_unexpired(&self, ids: &mut Vec<Uuid>) -> Result<(), MangoChainsawError> {
+        if self.ttl.is_empty() {
+            return Ok(());
+        }
+        let mut live = Vec::with_capacity(ids.len());
+        for id in ids.drain(..) {
+            if !self.is_expired(&document_key(id))? {
+                live.push(id);
+            }
+        }
+        *ids = live;
+        Ok(())
     }
 
     /// Get all labels matching a given key
     #[instrument(skip(self), ret)]
     pub fn label_name_search(&self, key: &str) -> Result<Vec<Label>, MangoChainsawError> {
-        let mut results = vec![];
-        for result in self.labels_kev.scan_prefix(key) {
-            let (key, val) = result?;
-            info!(
-                label = format!("{key:?}"),
-                value = format!("{val:?}"),
-                "Found label with prefix"
-            );
-            let label = Label::from_bytes(&key)?;
-            results.push(label);
+        let mut results: Vec<Label> = vec![];
+        for result in self.labels_kev.scan_prefix(key).keys() {
+            let key = result?;
+            let Some((label, id)) = split_posting_key(&key) else {
+                continue;
+            };
+            if self.is_expired(&document_key(id))? {
+                continue;
+            }
+            let label = Label::from_bytes(label)?;
+            // Postings for the same label are adjacent
+            if results.last() != Some(&label) {
+                info!(label = format!("{label}"), "Found label with prefix");
+                results.push(label);
+            }
         }
         Ok(results)
     }
@@ -259,17 +2928,22 @@ impl MangoChainsawBucket {
     /// Get all labels matching a given value
     #[instrument(skip(self), ret)]
     pub fn label_value_search(&self, value: &str) -> Result<Vec<Label>, MangoChainsawError> {
-        let mut results = vec![];
-        for result in self.labels_vek.scan_prefix(value) {
-            let (key, val) = result?;
-            info!(
-                label = format!("{key:?}"),
-                value = format!("{val:?}"),
-                "Found label with prefix"
-            );
-            let mut label = Label::from_bytes(&key)?;
+        let mut results: Vec<Label> = vec![];
+        for result in self.labels_vek.scan_prefix(value).keys() {
+            let key = result?;
+            let Some((label, id)) = split_posting_key(&key) else {
+                continue;
+            };
+            if self.is_expired(&document_key(id))? {
+                continue;
+            }
+            let mut label = Label::from_bytes(label)?;
             label.swap_key_value();
-            results.push(label);
+            // Postings for the same label are adjacent
+            if results.last() != Some(&label) {
+                info!(label = format!("{label}"), "Found label with prefix");
+                results.push(label);
+            }
         }
         Ok(results)
     }
@@ -277,192 +2951,339 @@ impl MangoChainsawBucket {
     /// Get all document id's with a given label
     #[instrument(skip(self), ret)]
     pub fn get_label(&self, label: Label) -> Result<Option<Vec<Uuid>>, MangoChainsawError> {
-        match self.labels_kev.get(label.as_bytes())? {
-            Some(raw_labels) => {
-                let ids: Vec<(u64, u64)> = MangoChainsaw::de(raw_labels)?;
-                Ok(Some(
-                    ids.into_iter()
-                        .map(|id| Uuid::from_u64_pair(id.0, id.1))
-                        .collect(),
-                ))
-            }
-            None => Ok(None),
+        let mut ids = self.label_ids(&label)?;
+        if ids.is_empty() {
+            return Ok(None);
         }
+        self.retain_unexpired(&mut ids)?;
+        Ok(Some(ids))
     }
 
-    /// Add labels to an existing document
+    /// Add labels to an existing document.
+    /// Returns whether the document exists; a missing or expired one is left unlabelled.
     #[instrument(skip(self), ret)]
     pub fn add_document_labels(
         &self,
         id: Uuid,
         labels: Vec<Label>,
-    ) -> Result<(), MangoChainsawError> {
-        let idbytes = MangoChainsaw::ser(id.as_u64_pair())?;
-        (&self.labels_kev, &self.labels_vek, &self.docs_labels).transaction(
-            |(kev, vek, doc_labels)| {
+    ) -> Result<bool, MangoChainsawError> {
+        let settings = self.settings()?;
+        let validators = self.validators(&settings)?;
+        let quota = settings.quota;
+        let idbytes = document_key(id);
+        let now = now_millis()?;
+        self.transaction(
+            [
+                &self.documents,
+                &self.ttl,
+                &self.labels_kev,
+                &self.labels_vek,
+                &self.docs_labels,
+                &self.bitmaps,
+                &self.range,
+                &self.ordinals,
+                &self.usage,
+            ],
+            |[docs, ttl, kev, vek, doc_labels, bitmaps, range, ordinals, usage]| {
+                let index = LabelIndex {
+                    kev,
+                    vek,
+                    bitmaps,
+                    range,
+                    ordinals,
+                    usage: quota.counts_usage().then_some(usage),
+                };
+                if !self.document_live(docs, ttl, &idbytes, now)? {
+                    info!("Document not found, nothing to label");
+                    return Ok(false);
+                }
+
                 // Update the docs_labels tree with the new labels
-                if let Some(raw_labels) = doc_labels.remove(&idbytes)? {
-                    let mut has_labels: Vec<Label> =
-                        MangoChainsaw::de(raw_labels).map_err(|e| {
-                            UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                                e.to_string(),
-                            ))
-                        })?;
-                    has_labels.extend(labels.clone());
-                    has_labels.sort();
-                    has_labels.dedup();
-                    let new = MangoChainsaw::ser(has_labels).map_err(|e| {
-                        UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                            e.to_string(),
-                        ))
-                    })?;
-                    doc_labels.insert(&idbytes, new)?;
+                let mut has_labels = self.document_labels(doc_labels, &idbytes)?;
+                has_labels.extend(labels.clone());
+                has_labels.sort();
+                has_labels.dedup();
+                quota::check_label_count(&quota, &self.name, has_labels.len())
+                    .map_err(ConflictableTransactionError::Abort)?;
+                if let Some(validator) = &validators.labels {
+                    validator
+                        .validate(&has_labels)
+                        .map_err(ConflictableTransactionError::Abort)?;
                 }
+                let new =
+                    MangoChainsaw::ser(has_labels).map_err(ConflictableTransactionError::Abort)?;
+                doc_labels.insert(&idbytes, new)?;
 
                 // Upsert each new label
+                let ordinal = index.ordinal(&idbytes)?;
+                let mut grew = false;
                 for label in &labels {
-                    self.upsert_label(kev, &label.as_bytes(), id.as_u64_pair())?;
-                    self.upsert_label(vek, &label.as_bytes_rev(), id.as_u64_pair())?;
+                    grew |= self.upsert_label(&index, label, &[id], ordinal.as_slice())?;
                 }
-                Ok(())
+                if grew {
+                    quota::check_labels(usage, &quota, &self.name)?;
+                }
+                Ok(true)
             },
-        )?;
-        Ok(())
+        )
     }
 
-    /// Remove labels from a document
+    /// Remove labels from a document.
+    /// Returns whether the document exists; a missing or expired one is left as it is.
     #[instrument(skip(self), ret)]
     pub fn remove_document_labels(
         &self,
         id: Uuid,
         labels: Vec<Label>,
-    ) -> Result<(), MangoChainsawError> {
-        let idbytes = MangoChainsaw::ser(id.as_u64_pair())?;
-        (&self.labels_kev, &self.labels_vek, &self.docs_labels).transaction(
-            |(kev, vek, doc_labels)| {
+    ) -> Result<bool, MangoChainsawError> {
+        let settings = self.settings()?;
+        let validators = self.validators(&settings)?;
+        let idbytes = document_key(id);
+        let now = now_millis()?;
+        self.transaction(
+            [
+                &self.documents,
+                &self.ttl,
+                &self.labels_kev,
+                &self.labels_vek,
+                &self.docs_labels,
+                &self.bitmaps,
+                &self.range,
+                &self.ordinals,
+                &self.usage,
+            ],
+            |[docs, ttl, kev, vek, doc_labels, bitmaps, range, ordinals, usage]| {
+                let index = LabelIndex {
+                    kev,
+                    vek,
+                    bitmaps,
+                    range,
+                    ordinals,
+                    usage: settings.quota.counts_usage().then_some(usage),
+                };
+                if !self.document_live(docs, ttl, &idbytes, now)? {
+                    info!("Document not found, nothing to unlabel");
+                    return Ok(false);
+                }
+
                 // Update the docs_labels tree with the labels removed
-                if let Some(raw_labels) = doc_labels.remove(&idbytes)? {
-                    let mut has_labels: Vec<Label> =
-                        MangoChainsaw::de(raw_labels).map_err(|e| {
-                            UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                                e.to_string(),
-                            ))
-                        })?;
-                    has_labels.retain(|l| !labels.contains(l));
-                    has_labels.sort();
-                    has_labels.dedup();
-                    let new = MangoChainsaw::ser(has_labels).map_err(|e| {
-                        UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                            e.to_string(),
-                        ))
-                    })?;
-                    doc_labels.insert(&idbytes, new)?;
+                let mut has_labels = self.document_labels(doc_labels, &idbytes)?;
+                has_labels.retain(|l| !labels.contains(l));
+                has_labels.sort();
+                has_labels.dedup();
+                if let Some(validator) = &validators.labels {
+                    validator
+                        .validate(&has_labels)
+                        .map_err(ConflictableTransactionError::Abort)?;
                 }
+                let new =
+                    MangoChainsaw::ser(has_labels).map_err(ConflictableTransactionError::Abort)?;
+                doc_labels.insert(&idbytes, new)?;
 
                 // Downsert each new label
+                let ordinal = index.ordinal(&idbytes)?;
                 for label in &labels {
-                    self.downsert_label(kev, &label.as_bytes(), id.as_u64_pair())?;
-                    self.downsert_label(vek, &label.as_bytes_rev(), id.as_u64_pair())?;
+                    self.downsert_label(&index, label, &[id], ordinal.as_slice())?;
                 }
-                Ok(())
+                Ok(true)
             },
-        )?;
-        Ok(())
+        )
     }
 
-    /// Insert a new label or update existing labels with a new document id
-    #[instrument(skip(self, t), fields(labels, new))]
+    /// Whether a document is stored and hasn't expired, as seen by a transaction
+    fn document_live(
+        &self,
+        docs: &dyn TransactionalTree,
+        ttl: &dyn TransactionalTree,
+        idbytes: &[u8],
+        now: u64,
+    ) -> ConflictableTransactionResult<bool, MangoChainsawError> {
+        if docs.get(idbytes)?.is_none() {
+            return Ok(false);
+        }
+        // An expired document is gone, even if the reaper hasn't removed it yet
+        match ttl.get(idbytes)? {
+            Some(expires_at) => Ok(decode_u64(&expires_at) > now),
+            None => Ok(true),
+        }
+    }
+
+    /// Read a document's labels from the docs_labels tree, as seen by a transaction
+    fn document_labels(
+        &self,
+        doc_labels: &dyn TransactionalTree,
+        idbytes: &[u8],
+    ) -> ConflictableTransactionResult<Vec<Label>, MangoChainsawError> {
+        match doc_labels.get(idbytes)? {
+            Some(raw) => MangoChainsaw::de(raw).map_err(ConflictableTransactionError::Abort),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Add a label to documents as postings, in the label's bitmap and in the range index.
+    /// Returns whether the label is new to the bucket.
+    #[instrument(skip(self, index, ids, ordinals))]
     fn upsert_label(
         &self,
-        t: &sled::transaction::TransactionalTree,
-        k: &[u8],
-        id: (u64, u64),
-    ) -> Result<(), UnabortableTransactionError> {
+        index: &LabelIndex,
+        label: &Label,
+        ids: &[Uuid],
+        ordinals: &[u32],
+    ) -> ConflictableTransactionResult<bool, MangoChainsawError> {
         info!("Upserting label");
-        match t.get(k) {
-            Ok(Some(current)) => {
-                info!("Label already exists, updating references");
-                let mut docs: Vec<(u64, u64)> = MangoChainsaw::de(current).map_err(|e| {
-                    UnabortableTransactionError::Storage(sled::Error::ReportableBug(e.to_string()))
-                })?;
-                docs.push(id);
-                docs.sort();
-                docs.dedup();
-                let new = MangoChainsaw::ser(docs).map_err(|e| {
-                    UnabortableTransactionError::Storage(sled::Error::ReportableBug(e.to_string()))
-                })?;
-                let _ = t.insert(k, new)?;
-                Ok(())
-            }
-            Ok(None) => {
-                info!("Label does not exist, creating");
-                let new = MangoChainsaw::ser(vec![id]).map_err(|e| {
-                    UnabortableTransactionError::Storage(sled::Error::ReportableBug(e.to_string()))
-                })?;
-                let _ = t.insert(k, new)?;
-                Ok(())
-            }
-            Err(e) => {
-                error!("Label failed to upsert");
-                Err(UnabortableTransactionError::Storage(
-                    sled::Error::ReportableBug(e.to_string()),
-                ))
+        let (label_kev, label_vek) = (label.as_bytes(), label.as_bytes_rev());
+        let mut added = 0;
+        for id in ids {
+            if index
+                .kev
+                .insert(posting_key(&label_kev, *id), &[])?
+                .is_none()
+            {
+                added += 1;
             }
+            index.vek.insert(posting_key(&label_vek, *id), &[])?;
+        }
+        bitmap::insert(index.bitmaps, &label_kev, ordinals)?;
+        range::insert(index.range, label, ordinals)?;
+        match index.usage {
+            Some(usage) => quota::count_label(usage, &label_kev, added),
+            None => Ok(false),
         }
     }
 
-    /// downsert an id from a label. This will remove the label if it is unused
-    #[instrument(skip(self, t))]
+    /// Remove a label from documents. A label with no postings no longer exists.
+    #[instrument(skip(self, index, ids, ordinals))]
     fn downsert_label(
         &self,
-        t: &sled::transaction::TransactionalTree,
-        k: &[u8],
-        id: (u64, u64),
-    ) -> Result<(), UnabortableTransactionError> {
-        match t.get(k) {
-            Ok(Some(raw_labels)) => {
-                info!("Found label");
-                let mut ids: Vec<(u64, u64)> = MangoChainsaw::de(raw_labels).map_err(|e| {
-                    UnabortableTransactionError::Storage(sled::Error::ReportableBug(e.to_string()))
-                })?;
-                if ids.len() == 1 {
-                    info!("Label has only one item, deleting");
-                    t.remove(k)?;
-                } else {
-                    ids.retain(|i| i.0 != id.0 && i.1 != id.1);
-                    t.insert(
-                        k,
-                        MangoChainsaw::ser(ids).map_err(|e| {
-                            UnabortableTransactionError::Storage(sled::Error::ReportableBug(
-                                e.to_string(),
-                            ))
-                        })?,
-                    )?;
-                }
-                Ok(())
+        index: &LabelIndex,
+        label: &Label,
+        ids: &[Uuid],
+        ordinals: &[u32],
+    ) -> ConflictableTransactionResult<(), MangoChainsawError> {
+        let (label_kev, label_vek) = (label.as_bytes(), label.as_bytes_rev());
+        let mut removed = 0;
+        for id in ids {
+            match index.kev.remove(posting_key(&label_kev, *id))? {
+                Some(_) => removed += 1,
+                None => warn!(id = id.to_string(), "Label did not exist to downsert"),
             }
-            Ok(None) => {
-                warn!("Label did not exist to downsert");
-                Ok(())
+            index.vek.remove(posting_key(&label_vek, *id))?;
+        }
+        bitmap::remove(index.bitmaps, &label_kev, ordinals)?;
+        range::remove(index.range, label, ordinals)?;
+        if let Some(usage) = index.usage {
+            quota::count_label(usage, &label_kev, -removed)?;
+        }
+        Ok(())
+    }
+
+    /// Copy every tree of this bucket into the trees of the bucket `target`, which should be
+    /// empty. Documents are given new ids unless `keep_ids` is set, in which case the copy
+    /// shares its ids with this bucket. New ids come with new creation times, the time they
+    /// were generated. Writes to this bucket have to be held back for the trees to agree.
+    /// Returns the number of documents copied.
+    pub(crate) fn copy_trees(
+        &self,
+        target: &str,
+        keep_ids: bool,
+    ) -> Result<usize, MangoChainsawError> {
+        let ids = if keep_ids {
+            HashMap::new()
+        } else {
+            self.fresh_ids()?
+        };
+        let strategy = self.strategy(&self.settings()?);
+        let mut copied = 0;
+        let swap = |raw: &[u8]| match Uuid::from_slice(raw).ok().and_then(|id| ids.get(&id)) {
+            Some(id) => id.as_bytes().to_vec(),
+            None => raw.to_vec(),
+        };
+        for suffix in TREES {
+            let source = self.parent.get_tree(&format!("{}::{suffix}", self.name))?;
+            let mut batch = Batch::default();
+            for result in source.iter() {
+                let (key, val) = result?;
+                if suffix == "doc" {
+                    copied += 1;
+                }
+                if ids.is_empty() {
+                    batch.insert(key, val);
+                    continue;
+                }
+                let (key, val) = match suffix {
+                    "doc" | "labels" | "ttl" | "ordinals" | "key_names" => {
+                        (swap(&key), val.to_vec())
+                    }
+                    "meta" => {
+                        let mut meta: DocumentMetadata = MangoChainsaw::de(val)?;
+                        let new_id = document_id(&swap(&key))?;
+                        meta.created = created_at(strategy, new_id).unwrap_or(meta.created);
+                        (
+                            document_key(new_id).to_vec(),
+                            MangoChainsaw::ser(&meta)?.to_vec(),
+                        )
+                    }
+                    "ordinal_ids" | "keys" => (key.to_vec(), swap(&val)),
+                    "history" if key.len() > ID_LEN => {
+                        let (id, rev) = key.split_at(ID_LEN);
+                        ([&swap(id), rev].concat(), val.to_vec())
+                    }
+                    "expiry" if key.len() > ID_LEN => {
+                        let (expires_at, id) = key.split_at(key.len() - ID_LEN);
+                        ([expires_at, &swap(id)].concat(), swap(&val))
+                    }
+                    "kev" | "vek" => match split_posting_key(&key) {
+                        Some((label, id)) => (
+                            posting_key(label, ids.get(&id).copied().unwrap_or(id)),
+                            val.to_vec(),
+                        ),
+                        None => (key.to_vec(), val.to_vec()),
+                    },
+                    _ => (key.to_vec(), val.to_vec()),
+                };
+                batch.insert(key, val);
             }
-            Err(e) => {
-                error!("Label failed to downsert");
-                Err(UnabortableTransactionError::Storage(
-                    sled::Error::ReportableBug(e.to_string()),
-                ))
+            self.parent
+                .get_tree(&format!("{target}::{suffix}"))?
+                .apply_batch(batch)?;
+        }
+        info!(target, keep_ids, "Copied bucket");
+        Ok(copied)
+    }
+
+    /// Generate a new id for every document, and every document that only has history left,
+    /// in the order they were created. Fails if the bucket's ids are provided by the caller.
+    fn fresh_ids(&self) -> Result<HashMap<Uuid, Uuid>, MangoChainsawError> {
+        let settings = self.settings()?;
+        if self.strategy(&settings) == IdStrategy::Provided {
+            return Err(MangoChainsawError::KeepIdsRequired(self.name.clone()));
+        }
+        let mut ids = HashMap::new();
+        for key in self.documents.iter().keys() {
+            ids.insert(document_id(&key?)?, self.next_id(&settings)?);
+        }
+        for key in self.history.iter().keys() {
+            let id = document_id(&key?[..ID_LEN])?;
+            if let Entry::Vacant(entry) = ids.entry(id) {
+                entry.insert(self.next_id(&settings)?);
             }
         }
+        Ok(ids)
     }
 
     /// Drop this bucket, deleting all of its documents and labels.
     /// This can't be undone.
     #[instrument(skip(self))]
     pub fn drop_bucket(&self) -> Result<(), MangoChainsawError> {
-        let name = &self.name;
-        self.parent.db.drop_tree(format!("{name}::doc"))?;
-        self.parent.db.drop_tree(format!("{name}::kev"))?;
-        self.parent.db.drop_tree(format!("{name}::vek"))?;
-        self.parent.db.drop_tree(format!("{name}::labels"))?;
+        Self::drop_trees(&self.parent, &self.name)
+    }
+
+    /// Drop the trees of a bucket and its registry entry, without opening it
+    pub(crate) fn drop_trees(parent: &MangoChainsaw, name: &str) -> Result<(), MangoChainsawError> {
+        for suffix in TREES {
+            parent.storage.drop_tree(&format!("{name}::{suffix}"))?;
+        }
+        parent.registry.remove(name)?;
         Ok(())
     }
 }
@@ -0,0 +1,627 @@
+use crate::errors::MangoChainsawError;
+use flexbuffers::FlexbufferSerializer;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Impossible};
+use serde::{forward_to_deserialize_any, Deserialize, Serialize};
+use sled::IVec;
+use std::fmt::Display;
+use thiserror::Error;
+
+/// A serialization format for document bodies
+pub trait Codec {
+    /// The tag stored in front of every body this codec writes
+    const KIND: CodecKind;
+
+    /// Serialize a document
+    fn encode<T>(value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized;
+
+    /// Deserialize a document
+    fn decode<T>(bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned;
+}
+
+/// The codecs a bucket can store documents with.
+/// The discriminant is the tag stored with each document, so it must never change.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum CodecKind {
+    /// Flexbuffers, the original format
+    #[default]
+    Flexbuffers = 0,
+    /// JSON text
+    Json = 1,
+    /// CBOR, RFC 8949
+    Cbor = 2,
+    /// MessagePack, with structs written as maps
+    MessagePack = 3,
+    /// Bytes stored as they are. Only documents that serialize as bytes or strings fit.
+    Raw = 4,
+}
+
+impl CodecKind {
+    /// Serialize a document with this codec
+    pub fn encode<T>(self, value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        match self {
+            CodecKind::Flexbuffers => Flexbuffers::encode(value),
+            CodecKind::Json => Json::encode(value),
+            CodecKind::Cbor => Cbor::encode(value),
+            CodecKind::MessagePack => MessagePack::encode(value),
+            CodecKind::Raw => Raw::encode(value),
+        }
+    }
+
+    /// Deserialize a document with this codec
+    pub fn decode<T>(self, bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            CodecKind::Flexbuffers => Flexbuffers::decode(bytes),
+            CodecKind::Json => Json::decode(bytes),
+            CodecKind::Cbor => Cbor::decode(bytes),
+            CodecKind::MessagePack => MessagePack::decode(bytes),
+            CodecKind::Raw => Raw::decode(bytes),
+        }
+    }
+
+    /// The media type of documents written by this codec
+    pub fn media_type(self) -> &'static str {
+        match self {
+            CodecKind::Json => "application/json",
+            CodecKind::Cbor => "application/cbor",
+            CodecKind::MessagePack => "application/vnd.msgpack",
+            CodecKind::Flexbuffers | CodecKind::Raw => "application/octet-stream",
+        }
+    }
+
+    /// Whether documents written by this codec can be read as JSON values,
+    /// so that a body schema can check them. Only JSON itself qualifies: the binary
+    /// formats have byte strings and non-string keys that JSON can't hold, and uploads
+    /// stored as they are can only be read one way.
+    pub fn is_json_compatible(self) -> bool {
+        matches!(self, CodecKind::Json)
+    }
+
+    /// The tag stored with documents written by this codec
+    pub fn tag(self) -> u8 {
+        self as u8
+    }
+
+    /// Get a codec back from a stored tag
+    pub fn from_tag(tag: u8) -> Result<Self, MangoChainsawError> {
+        match tag {
+            0 => Ok(CodecKind::Flexbuffers),
+            1 => Ok(CodecKind::Json),
+            2 => Ok(CodecKind::Cbor),
+            3 => Ok(CodecKind::MessagePack),
+            4 => Ok(CodecKind::Raw),
+            _ => Err(MangoChainsawError::UnknownCodec(tag)),
+        }
+    }
+}
+
+/// Serialize a document body, tagged with the codec that wrote it
+pub(crate) fn encode_body<T>(codec: CodecKind, value: &T) -> Result<IVec, MangoChainsawError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut body = vec![codec.tag()];
+    body.extend_from_slice(&codec.encode(value)?);
+    Ok(IVec::from(body))
+}
+
+/// Tag bytes that are stored as they are, without going through a serializer
+pub(crate) fn raw_body(bytes: &[u8]) -> IVec {
+    let mut body = Vec::with_capacity(1 + bytes.len());
+    body.push(CodecKind::Raw.tag());
+    body.extend_from_slice(bytes);
+    IVec::from(body)
+}
+
+/// Split a tagged document body into its codec and the encoded document
+pub(crate) fn split_body(body: &[u8]) -> Result<(CodecKind, &[u8]), MangoChainsawError> {
+    match body.split_first() {
+        Some((tag, encoded)) => Ok((CodecKind::from_tag(*tag)?, encoded)),
+        None => Err(MangoChainsawError::Etc(
+            "document body is missing its codec tag".to_string(),
+        )),
+    }
+}
+
+/// Deserialize a tagged document body with whichever codec wrote it
+pub(crate) fn decode_body<T>(body: &[u8]) -> Result<T, MangoChainsawError>
+where
+    T: DeserializeOwned,
+{
+    let (codec, encoded) = split_body(body)?;
+    codec.decode(encoded)
+}
+
+/// [Flexbuffers](https://google.github.io/flatbuffers/flexbuffers.html)
+#[derive(Clone, Copy, Debug)]
+pub struct Flexbuffers;
+
+impl Codec for Flexbuffers {
+    const KIND: CodecKind = CodecKind::Flexbuffers;
+
+    fn encode<T>(value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut ser = FlexbufferSerializer::new();
+        value.serialize(&mut ser)?;
+        Ok(ser.take_buffer())
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        let rdr = flexbuffers::Reader::get_root(bytes)?;
+        Ok(T::deserialize(rdr)?)
+    }
+}
+
+/// JSON, readable by nearly anything
+#[derive(Clone, Copy, Debug)]
+pub struct Json;
+
+impl Codec for Json {
+    const KIND: CodecKind = CodecKind::Json;
+
+    fn encode<T>(value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// [CBOR](https://cbor.io)
+#[derive(Clone, Copy, Debug)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    const KIND: CodecKind = CodecKind::Cbor;
+
+    fn encode<T>(value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut out = vec![];
+        ciborium::into_writer(value, &mut out)?;
+        Ok(out)
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+/// [MessagePack](https://msgpack.org). Structs are written as maps rather than arrays,
+/// so other tools see field names.
+#[derive(Clone, Copy, Debug)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    const KIND: CodecKind = CodecKind::MessagePack;
+
+    fn encode<T>(value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// Bytes stored as they are, for documents that are already encoded.
+/// Byte buffers, strings and sequences of `u8` can be stored and read back as any of those.
+#[derive(Clone, Copy, Debug)]
+pub struct Raw;
+
+impl Codec for Raw {
+    const KIND: CodecKind = CodecKind::Raw;
+
+    fn encode<T>(value: &T) -> Result<Vec<u8>, MangoChainsawError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut ser = RawSerializer::default();
+        value.serialize(&mut ser)?;
+        Ok(ser.out)
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, MangoChainsawError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(T::deserialize(RawDeserializer(bytes))?)
+    }
+}
+
+/// A document that the raw codec can't store or produce
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct RawCodecError(String);
+
+impl ser::Error for RawCodecError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl de::Error for RawCodecError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+fn unsupported(what: &str) -> RawCodecError {
+    RawCodecError(format!(
+        "the raw codec only stores bytes and strings, not {what}"
+    ))
+}
+
+/// Collects the bytes of a document that serializes as bytes, a string or a sequence of `u8`
+#[derive(Default)]
+struct RawSerializer {
+    out: Vec<u8>,
+    in_seq: bool,
+}
+
+impl ser::Serializer for &mut RawSerializer {
+    type Ok = ();
+    type Error = RawCodecError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Impossible<(), RawCodecError>;
+    type SerializeTupleVariant = Impossible<(), RawCodecError>;
+    type SerializeMap = Impossible<(), RawCodecError>;
+    type SerializeStruct = Impossible<(), RawCodecError>;
+    type SerializeStructVariant = Impossible<(), RawCodecError>;
+
+    fn serialize_u8(self, v: u8) -> Result<(), RawCodecError> {
+        self.out.push(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), RawCodecError> {
+        if self.in_seq {
+            return Err(unsupported("nested sequences"));
+        }
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), RawCodecError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, RawCodecError> {
+        if self.in_seq {
+            return Err(unsupported("nested sequences"));
+        }
+        self.in_seq = true;
+        self.out.reserve(len.unwrap_or(0));
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, RawCodecError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), RawCodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), RawCodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<(), RawCodecError> {
+        Err(unsupported("booleans"))
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), RawCodecError> {
+        Err(unsupported("numbers"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), RawCodecError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_none(self) -> Result<(), RawCodecError> {
+        Err(unsupported("missing values"))
+    }
+
+    fn serialize_unit(self) -> Result<(), RawCodecError> {
+        Err(unsupported("unit values"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), RawCodecError> {
+        Err(unsupported("unit structs"))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), RawCodecError> {
+        Err(unsupported("enums"))
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), RawCodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        Err(unsupported("enums"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, RawCodecError> {
+        Err(unsupported("tuple structs"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, RawCodecError> {
+        Err(unsupported("enums"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, RawCodecError> {
+        Err(unsupported("maps"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, RawCodecError> {
+        Err(unsupported("structs"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, RawCodecError> {
+        Err(unsupported("enums"))
+    }
+}
+
+impl ser::SerializeSeq for &mut RawSerializer {
+    type Ok = ();
+    type Error = RawCodecError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), RawCodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), RawCodecError> {
+        self.in_seq = false;
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut RawSerializer {
+    type Ok = ();
+    type Error = RawCodecError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), RawCodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), RawCodecError> {
+        self.in_seq = false;
+        Ok(())
+    }
+}
+
+/// Hands stored bytes to whatever asks for them: as bytes, a string or a sequence of `u8`
+struct RawDeserializer<'a>(&'a [u8]);
+
+impl<'de> de::Deserializer<'de> for RawDeserializer<'_> {
+    type Error = RawCodecError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, RawCodecError>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_bytes(self.0)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, RawCodecError>
+    where
+        V: Visitor<'de>,
+    {
+        match std::str::from_utf8(self.0) {
+            Ok(s) => visitor.visit_str(s),
+            Err(e) => Err(RawCodecError(format!("stored bytes are not a string: {e}"))),
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, RawCodecError>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, RawCodecError>
+    where
+        V: Visitor<'de>,
+    {
+        let bytes = self
+            .0
+            .iter()
+            .copied()
+            .map(IntoDeserializer::into_deserializer);
+        visitor.visit_seq(de::value::SeqDeserializer::new(bytes))
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, RawCodecError>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, RawCodecError>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit
+        unit_struct tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Doc {
+        name: String,
+        tags: Vec<String>,
+        count: u32,
+    }
+
+    #[test]
+    fn test_codecs_round_trip() -> Result<(), MangoChainsawError> {
+        let doc = Doc {
+            name: "mango".to_string(),
+            tags: vec!["fruit".to_string(), "orange".to_string()],
+            count: 7,
+        };
+        for codec in [
+            CodecKind::Flexbuffers,
+            CodecKind::Json,
+            CodecKind::Cbor,
+            CodecKind::MessagePack,
+        ] {
+            let body = encode_body(codec, &doc)?;
+            assert_eq!(split_body(&body)?.0, codec);
+            assert_eq!(decode_body::<Doc>(&body)?, doc, "{codec:?}");
+        }
+
+        assert_eq!(
+            Json::encode(&doc)?,
+            br#"{"name":"mango","tags":["fruit","orange"],"count":7}"#
+        );
+        assert!(Raw::encode(&doc).is_err());
+        assert!(CodecKind::from_tag(200).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_codec() -> Result<(), MangoChainsawError> {
+        let bytes = vec![0u8, 1, 2, 255];
+        assert_eq!(Raw::encode(&bytes)?, bytes);
+        assert_eq!(Raw::decode::<Vec<u8>>(&bytes)?, bytes);
+        assert_eq!(
+            Raw::decode::<serde_bytes::ByteBuf>(&bytes)?.into_vec(),
+            bytes
+        );
+
+        assert_eq!(Raw::encode("hello")?, b"hello");
+        assert_eq!(Raw::decode::<String>(b"hello")?, "hello");
+        assert!(Raw::decode::<String>(&bytes).is_err());
+        assert!(Raw::encode(&vec![vec![1u8]]).is_err());
+        Ok(())
+    }
+}
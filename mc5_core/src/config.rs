@@ -1,3 +1,4 @@
+use crate::errors::MangoChainsawError;
 use figment::{
     providers::{Format, YamlExtended},
     Figment, Metadata, Provider,
@@ -15,14 +16,41 @@ pub enum BackendMode {
     Small,
 }
 
+/// How new document ids are generated
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// Time-ordered v6 uuids with a node id from the sled idgen
+    #[default]
+    V6,
+    /// Time-ordered v7 uuids with millisecond timestamps and random tails
+    V7,
+    /// ULIDs, stored as the uuid with the same 128 bits
+    Ulid,
+    /// No ids are generated. Documents must be inserted with `insert_with_id`.
+    Provided,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct MangoChainsawConfig {
+    /// Keep everything in memory, to be thrown away when the process exits
     pub temporary: bool,
     pub listen: SocketAddr,
     pub data_path: PathBuf,
     pub backend_mode: BackendMode,
     pub idgen_interval: u64,
     pub compression_factor: i32,
+
+    /// Seconds between sweeps for expired documents. 0 disables the reaper.
+    #[serde(default = "default_reaper_interval")]
+    pub reaper_interval: u64,
+
+    /// How ids are generated for new documents
+    #[serde(default)]
+    pub id_strategy: IdStrategy,
+}
+
+fn default_reaper_interval() -> u64 {
+    60
 }
 
 impl Default for MangoChainsawConfig {
@@ -34,6 +62,8 @@ impl Default for MangoChainsawConfig {
             backend_mode: BackendMode::Fast,
             idgen_interval: 420_069,
             compression_factor: 3,
+            reaper_interval: default_reaper_interval(),
+            id_strategy: IdStrategy::default(),
         }
     }
 }
@@ -51,7 +81,7 @@ impl Provider for MangoChainsawConfig {
 }
 
 impl MangoChainsawConfig {
-    pub fn load<P: AsRef<Path>>(path: P, profile: &str) -> Result<Self, figment::Error> {
+    pub fn load<P: AsRef<Path>>(path: P, profile: &str) -> Result<Self, MangoChainsawError> {
         info!(
             path = format!("{:?}", path.as_ref()),
             profile = profile,
@@ -61,18 +91,25 @@ impl MangoChainsawConfig {
             .merge(YamlExtended::file(path.as_ref()).nested())
             .select(profile)
             .extract()
+            .map_err(MangoChainsawError::from)
     }
 
     pub fn to_sled_config(&self) -> sled::Config {
-        sled::Config::new()
+        let config = sled::Config::new()
             .mode(match self.backend_mode {
                 BackendMode::Fast => sled::Mode::HighThroughput,
                 BackendMode::Small => sled::Mode::LowSpace,
             })
             .temporary(self.temporary)
             .idgen_persist_interval(self.idgen_interval)
-            .path(&self.data_path)
             .use_compression(self.compression_factor > 0)
-            .compression_factor(self.compression_factor)
+            .compression_factor(self.compression_factor);
+
+        // Let sled pick a unique scratch path for temporary dbs without a data_path
+        if self.temporary && self.data_path.as_os_str().is_empty() {
+            config
+        } else {
+            config.path(&self.data_path)
+        }
     }
 }
@@ -0,0 +1,135 @@
+use crate::errors::MangoChainsawError;
+use crate::large::Manifest;
+use crate::storage::TransactionalTree;
+use sha2::{Digest as _, Sha256};
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult};
+use sled::IVec;
+
+/// SHA-256 of a serialized body, codec tag included, which it is stored under
+/// in the `{name}::blobs` tree of a bucket with deduplication on
+pub type Digest = [u8; 32];
+
+/// Body tag of a document whose body is in the blobs tree. It sits just below the tag
+/// of chunked documents, clear of the codec tags.
+const DIGEST_TAG: u8 = 0xFE;
+
+/// Hash a serialized body
+pub(crate) fn digest(body: &[u8]) -> Digest {
+    Sha256::digest(body).into()
+}
+
+/// Incrementally hashes a body that is written a piece at a time
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Hasher(Sha256);
+
+impl Hasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub(crate) fn finish(self) -> Digest {
+        self.0.finalize().into()
+    }
+}
+
+/// Get the digest a body would be stored under, or None if it can't be deduplicated:
+/// it already points to a blob, or is chunked without a recorded digest
+pub(crate) fn body_digest(body: &[u8]) -> Result<Option<Digest>, MangoChainsawError> {
+    if pointer(body).is_some() {
+        return Ok(None);
+    }
+    match Manifest::from_body(body)? {
+        Some(manifest) => Ok(manifest.digest),
+        None => Ok(Some(digest(body))),
+    }
+}
+
+/// The body stored with a document in place of the body it points to
+pub(crate) fn pointer_body(digest: &Digest) -> IVec {
+    let mut body = Vec::with_capacity(1 + digest.len());
+    body.push(DIGEST_TAG);
+    body.extend_from_slice(digest);
+    IVec::from(body)
+}
+
+/// Get the digest out of a document body, if it points to a blob
+pub(crate) fn pointer(body: &[u8]) -> Option<Digest> {
+    match body.split_first() {
+        Some((&DIGEST_TAG, digest)) => digest.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Decode a blob's reference count, aborting the transaction if it isn't a u64
+fn decode_count(raw: &[u8]) -> ConflictableTransactionResult<u64, MangoChainsawError> {
+    let bytes = raw.try_into().map_err(|_| {
+        ConflictableTransactionError::Abort(MangoChainsawError::CorruptCounter(raw.len()))
+    })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Store a body under its digest unless it is already there, and take a reference to it.
+/// Returns the body to store with the document in its place.
+pub(crate) fn reference(
+    blobs: &dyn TransactionalTree,
+    refs: &dyn TransactionalTree,
+    digest: &Digest,
+    body: &[u8],
+) -> ConflictableTransactionResult<IVec, MangoChainsawError> {
+    let count = match refs.get(digest)? {
+        Some(raw) if blobs.get(digest)?.is_some() => decode_count(&raw)?,
+        _ => {
+            blobs.insert(digest, body)?;
+            0
+        }
+    };
+    refs.insert(digest, &(count + 1).to_be_bytes())?;
+    Ok(pointer_body(digest))
+}
+
+/// Take another reference to the blob a body points to, if it points to one
+pub(crate) fn retain(
+    refs: &dyn TransactionalTree,
+    body: &[u8],
+) -> ConflictableTransactionResult<(), MangoChainsawError> {
+    let Some(digest) = pointer(body) else {
+        return Ok(());
+    };
+    let count = match refs.get(digest)? {
+        Some(raw) => decode_count(&raw)?,
+        None => 0,
+    };
+    refs.insert(digest, &(count + 1).to_be_bytes())?;
+    Ok(())
+}
+
+/// Give up a reference to the blob a body points to, if it points to one.
+/// The blob stays until [`collect`] finds it unreferenced, so the body can still be read.
+pub(crate) fn release(
+    refs: &dyn TransactionalTree,
+    body: &[u8],
+) -> ConflictableTransactionResult<(), MangoChainsawError> {
+    let Some(digest) = pointer(body) else {
+        return Ok(());
+    };
+    if let Some(raw) = refs.get(digest)? {
+        let count = decode_count(&raw)?.saturating_sub(1);
+        refs.insert(digest, &count.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// Remove a blob if nothing refers to it any more. Returns the removed body.
+pub(crate) fn collect(
+    blobs: &dyn TransactionalTree,
+    refs: &dyn TransactionalTree,
+    digest: &Digest,
+) -> ConflictableTransactionResult<Option<IVec>, MangoChainsawError> {
+    if let Some(raw) = refs.get(digest)? {
+        if decode_count(&raw)? > 0 {
+            return Ok(None);
+        }
+    }
+    refs.remove(digest)?;
+    Ok(blobs.remove(digest)?)
+}
@@ -1,12 +1,16 @@
+use crate::codec::RawCodecError;
+use crate::quota::QuotaLimit;
+use crate::schema::{describe, BodyViolation, LabelViolation};
 use flexbuffers::{DeserializationError, ReaderError, SerializationError};
 use sled::transaction::{TransactionError, UnabortableTransactionError};
 use std::{str::Utf8Error, time::SystemTimeError};
 use thiserror::Error;
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
 pub enum MangoChainsawError {
     #[error("Config erro: {0}")]
-    Config(#[from] figment::Error),
+    Config(Box<figment::Error>),
 
     #[error("Time travel is illegal: {0}")]
     Time(#[from] SystemTimeError),
@@ -20,9 +24,33 @@ pub enum MangoChainsawError {
     #[error("Flexbuffer read error: {0}")]
     FlexRead(#[from] ReaderError),
 
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("CBOR serialization error: {0}")]
+    CborSer(#[from] ciborium::ser::Error<std::io::Error>),
+
+    #[error("CBOR deserialization error: {0}")]
+    CborDe(#[from] ciborium::de::Error<std::io::Error>),
+
+    #[error("MessagePack serialization error: {0}")]
+    MsgpackSer(#[from] rmp_serde::encode::Error),
+
+    #[error("MessagePack deserialization error: {0}")]
+    MsgpackDe(#[from] rmp_serde::decode::Error),
+
+    #[error("Raw codec error: {0}")]
+    Raw(#[from] RawCodecError),
+
+    #[error("Unknown codec tag {0}, the document was written by a newer version")]
+    UnknownCodec(u8),
+
     #[error("Formatting error: {0}")]
     Format(#[from] std::fmt::Error),
 
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
     #[error("Sled error: {0}")]
     Sled(#[from] sled::Error),
 
@@ -35,6 +63,78 @@ pub enum MangoChainsawError {
     #[error("UTF-8 format error: {0}")]
     Utf(#[from] Utf8Error),
 
+    #[error("Revision conflict on {id}: expected {expected}, found {found:?}")]
+    Conflict {
+        id: Uuid,
+        expected: u64,
+        found: Option<u64>,
+    },
+
+    #[error("Invalid query at offset {offset}: {message}")]
+    InvalidQuery { offset: usize, message: String },
+
+    #[error("A document with id {0} already exists")]
+    DuplicateId(Uuid),
+
+    #[error("A document with key {:?} already exists", String::from_utf8_lossy(.0))]
+    DuplicateKey(Vec<u8>),
+
+    #[error("Ids are provided by the caller, use insert_with_id")]
+    IdRequired,
+
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(String),
+
+    #[error("Invalid bucket name {name:?}: {reason}")]
+    InvalidBucketName { name: String, reason: String },
+
+    #[error("Bucket {0} does not exist")]
+    BucketNotFound(String),
+
+    #[error("Bucket {0} already exists")]
+    BucketExists(String),
+
+    #[error("Bucket {bucket} is over its {limit} quota of {max}")]
+    QuotaExceeded {
+        bucket: String,
+        limit: QuotaLimit,
+        max: u64,
+    },
+
+    #[error("Bucket {0} keeps the id strategy it was created with")]
+    IdStrategyFixed(String),
+
+    #[error("Bucket {0} has ids provided by the caller, so a copy has to keep them")]
+    KeepIdsRequired(String),
+
+    #[error("Corrupt counter of {0} bytes")]
+    CorruptCounter(usize),
+
+    #[error("Invalid schema: {0}")]
+    InvalidSchema(String),
+
+    #[error("Labels don't match the bucket's schema: {}", describe(.0))]
+    LabelSchemaViolation(Vec<LabelViolation>),
+
+    #[error("Document body doesn't match the bucket's schema: {}", describe(.0))]
+    BodySchemaViolation(Vec<BodyViolation>),
+
     #[error("Undefined error: {0}")]
     Etc(String),
 }
+
+impl MangoChainsawError {
+    /// Unwrap an aborted transaction back into the error that aborted it
+    pub(crate) fn from_tx(value: TransactionError<MangoChainsawError>) -> Self {
+        match value {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+impl From<figment::Error> for MangoChainsawError {
+    fn from(value: figment::Error) -> Self {
+        Self::Config(Box::new(value))
+    }
+}
@@ -0,0 +1,157 @@
+use crate::errors::MangoChainsawError;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Lets writes into each bucket, unless a copy or rename of the bucket is holding them back
+/// so that it reads every tree in the same state
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WriteGates(Arc<Mutex<HashMap<String, Arc<Gate>>>>);
+
+#[derive(Debug, Default)]
+struct Gate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    /// Number of writes in progress
+    writers: usize,
+
+    /// Whether a copy or rename is holding writes back
+    closed: bool,
+
+    /// Whether the bucket was renamed away, so waiting writes have nowhere to go
+    retired: bool,
+}
+
+impl Gate {
+    /// The state is only ever changed a step at a time, so it is still right
+    /// if a thread panicked holding the lock
+    fn lock(&self) -> MutexGuard<'_, GateState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait until `ready` holds for the state
+    fn wait_for(&self, ready: impl Fn(&GateState) -> bool) -> MutexGuard<'_, GateState> {
+        let mut state = self.lock();
+        while !ready(&state) {
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        state
+    }
+}
+
+impl WriteGates {
+    fn gate(&self, bucket: &str) -> Arc<Gate> {
+        let mut gates = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        gates.entry(bucket.to_string()).or_default().clone()
+    }
+
+    /// Wait for any copy or rename of a bucket to finish, then hold the next one off
+    /// until the returned guard is dropped. Fails if the bucket was renamed meanwhile.
+    /// A write must not enter again while it holds a guard, or it can deadlock with a copy.
+    pub(crate) fn enter(&self, bucket: &str) -> Result<WriteGuard, MangoChainsawError> {
+        let gate = self.gate(bucket);
+        let mut state = gate.wait_for(|state| !state.closed);
+        if state.retired {
+            return Err(MangoChainsawError::BucketNotFound(bucket.to_string()));
+        }
+        state.writers += 1;
+        drop(state);
+        Ok(WriteGuard(gate))
+    }
+
+    /// Hold off writes to a bucket and wait for the ones in progress to finish.
+    /// Writes go on once the returned guard is dropped.
+    pub(crate) fn close(&self, bucket: &str) -> ClosedGate {
+        let gate = self.gate(bucket);
+        gate.wait_for(|state| !state.closed).closed = true;
+        drop(gate.wait_for(|state| state.writers == 0));
+        ClosedGate { gate }
+    }
+
+    /// Let writes into a bucket that is created under the name of one that was renamed
+    pub(crate) fn reopen(&self, bucket: &str) {
+        let mut gates = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if gates.get(bucket).is_some_and(|gate| gate.lock().retired) {
+            gates.remove(bucket);
+        }
+    }
+}
+
+/// A write in progress, see [`WriteGates::enter`]
+#[derive(Debug)]
+pub(crate) struct WriteGuard(Arc<Gate>);
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        self.0.lock().writers -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Writes held back by a copy or rename, see [`WriteGates::close`]
+#[derive(Debug)]
+pub(crate) struct ClosedGate {
+    gate: Arc<Gate>,
+}
+
+impl ClosedGate {
+    /// Turn away the writes that were held back, and any that come later,
+    /// once the bucket has been renamed
+    pub(crate) fn retire(self) {
+        self.gate.lock().retired = true;
+    }
+}
+
+impl Drop for ClosedGate {
+    fn drop(&mut self) {
+        self.gate.lock().closed = false;
+        self.gate.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_write_gate() -> Result<(), MangoChainsawError> {
+        let gates = WriteGates::default();
+        let writing = gates.enter("objects")?;
+        let (entered, wait) = mpsc::channel();
+
+        // A copy waits for the write in progress, and the next write waits for the copy
+        let closing = {
+            let gates = gates.clone();
+            std::thread::spawn(move || gates.close("objects"))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!closing.is_finished());
+        drop(writing);
+        let closed = closing.join().unwrap();
+        let writer = {
+            let gates = gates.clone();
+            std::thread::spawn(move || entered.send(gates.enter("objects").map(drop)))
+        };
+        assert!(wait.recv_timeout(Duration::from_millis(20)).is_err());
+        assert!(gates.enter("other_objects").is_ok());
+
+        // Once the bucket is renamed, the write is turned away
+        closed.retire();
+        assert!(matches!(
+            wait.recv().unwrap(),
+            Err(MangoChainsawError::BucketNotFound(_))
+        ));
+        writer.join().unwrap().unwrap();
+        gates.reopen("objects");
+        assert!(gates.enter("objects").is_ok());
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use crate::errors::MangoChainsawError;
 use serde::Deserialize;
 use serde::Serialize;
+use std::cmp::Ordering;
 use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Label {
@@ -47,6 +49,11 @@ impl Label {
             .to_vec()
     }
 
+    /// Get the value of this label as a typed value, for range queries
+    pub fn typed_value(&self) -> LabelValue {
+        LabelValue::infer(&self.value)
+    }
+
     pub fn from_bytes(s: &[u8]) -> Result<Self, MangoChainsawError> {
         let s = std::str::from_utf8(s)?;
         if let Some((lhs, rhs)) = s.split_once('=') {
@@ -57,6 +64,155 @@ impl Label {
     }
 }
 
+/// A label value interpreted as an ordered type.
+///
+/// Label values are stored as strings, and typed by what they parse as:
+/// integers, then finite floats, then RFC 3339 timestamps, and anything else is a string.
+/// Integers and floats are both numbers, and compare by value with each other.
+/// Otherwise values of different types never compare equal, and sort by type first.
+#[derive(Clone, Debug)]
+pub enum LabelValue {
+    Int(i64),
+    Float(f64),
+    Timestamp(SystemTime),
+    String(String),
+}
+
+impl LabelValue {
+    /// Type a label value by what it parses as
+    pub fn infer(value: &str) -> Self {
+        if let Ok(i) = value.parse::<i64>() {
+            return Self::Int(i);
+        }
+        if let Ok(f) = value.parse::<f64>() {
+            // Leave things like "inf" and "NaN" as strings
+            if f.is_finite() {
+                return Self::Float(f);
+            }
+        }
+        if let Ok(t) = humantime::parse_rfc3339_weak(value) {
+            return Self::Timestamp(t);
+        }
+        Self::String(value.to_string())
+    }
+
+    /// Tag byte that sorts values of each type together. Integers and floats share one.
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Self::Int(_) | Self::Float(_) => 1,
+            Self::Timestamp(_) => 3,
+            Self::String(_) => 4,
+        }
+    }
+
+    /// Encode the value so that byte order matches value order, tag first.
+    /// Strings are escaped and terminated so that no encoding is a prefix of another.
+    pub(crate) fn to_ordered_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.tag()];
+        match self {
+            // Numbers are the nearest float, then how far the exact value is from it.
+            // Only integers past 2^53 can be any distance from their float.
+            Self::Int(i) => {
+                let nearest = *i as f64;
+                let offset = (*i as i128 - nearest as i128) as i64;
+                out.extend_from_slice(&ordered_float(nearest).to_be_bytes());
+                out.extend_from_slice(&((offset as u64) ^ (1 << 63)).to_be_bytes());
+            }
+            Self::Float(f) => {
+                out.extend_from_slice(&ordered_float(*f).to_be_bytes());
+                out.extend_from_slice(&(1u64 << 63).to_be_bytes());
+            }
+            Self::Timestamp(t) => {
+                let (secs, nanos) = match t.duration_since(UNIX_EPOCH) {
+                    Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+                    Err(e) => {
+                        let d = e.duration();
+                        match d.subsec_nanos() {
+                            0 => (-(d.as_secs() as i64), 0),
+                            n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+                        }
+                    }
+                };
+                out.extend_from_slice(&((secs as u64) ^ (1 << 63)).to_be_bytes());
+                out.extend_from_slice(&nanos.to_be_bytes());
+            }
+            Self::String(s) => {
+                for b in s.as_bytes() {
+                    match b {
+                        0 => out.extend_from_slice(&[0, 0xFF]),
+                        b => out.push(*b),
+                    }
+                }
+                out.extend_from_slice(&[0, 0]);
+            }
+        }
+        out
+    }
+}
+
+/// The bits of a float, arranged so that they sort as unsigned integers in the float's order
+fn ordered_float(f: f64) -> u64 {
+    // Positive floats sort after negative ones, and negative ones sort in reverse
+    let bits = (f + 0.0).to_bits();
+    match bits >> 63 {
+        0 => bits | (1 << 63),
+        _ => !bits,
+    }
+}
+
+impl PartialEq for LabelValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_ordered_bytes() == other.to_ordered_bytes()
+    }
+}
+
+impl PartialOrd for LabelValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.to_ordered_bytes().cmp(&other.to_ordered_bytes()))
+    }
+}
+
+impl Display for LabelValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(i) => write!(f, "{i}"),
+            Self::Float(x) => write!(f, "{x:?}"),
+            // RFC 3339 formatting only covers times after the epoch,
+            // so earlier ones are shown as negative seconds
+            Self::Timestamp(t) => match t.duration_since(UNIX_EPOCH) {
+                Ok(d) if d.subsec_nanos() == 0 => write!(f, "{}", humantime::format_rfc3339(*t)),
+                Ok(_) => write!(f, "{}", humantime::format_rfc3339_nanos(*t)),
+                Err(e) => write!(f, "-{:?}", e.duration().as_secs_f64()),
+            },
+            Self::String(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+impl From<i64> for LabelValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<f64> for LabelValue {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<SystemTime> for LabelValue {
+    fn from(value: SystemTime) -> Self {
+        Self::Timestamp(value)
+    }
+}
+
+impl From<&str> for LabelValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
 #[macro_export]
 macro_rules! mclabel {
     ($k:expr => $v:expr) => {{
@@ -75,3 +231,57 @@ macro_rules! mclabels {
         labels
     }}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_value_order() {
+        let sorted = [
+            "-1.5e10",
+            "-9000000000",
+            "-3",
+            "-0.25",
+            "0",
+            "0.5",
+            "7",
+            "12.75",
+            "9000000000",
+            "9007199254740992",
+            "9007199254740993",
+            "9.1e15",
+            "1970-01-01T00:00:00Z",
+            "2024-05-01T00:00:00.000000001Z",
+            "2024-05-01T00:00:01Z",
+            "",
+            "NaN",
+            "a",
+            "a\0b",
+            "ab",
+        ];
+        let values: Vec<LabelValue> = sorted.iter().map(|v| LabelValue::infer(v)).collect();
+        for pair in values.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+        for value in &values {
+            assert_eq!(&LabelValue::infer(&value.to_string()), value);
+        }
+        assert_eq!(LabelValue::infer("-0.0"), LabelValue::Float(0.0));
+        assert_eq!(LabelValue::Int(3), LabelValue::Float(3.0));
+        assert_eq!(
+            LabelValue::Int(1 << 53),
+            LabelValue::Float(9007199254740992.0)
+        );
+        assert!(LabelValue::Int((1 << 53) + 1) > LabelValue::Float(9007199254740992.0));
+        assert!(LabelValue::Int(i64::MAX) < LabelValue::Float(1e19));
+
+        let before_epoch = UNIX_EPOCH - std::time::Duration::from_millis(1500);
+        assert!(LabelValue::Timestamp(before_epoch) < LabelValue::Timestamp(UNIX_EPOCH));
+        assert!(
+            LabelValue::Timestamp(before_epoch)
+                > LabelValue::Timestamp(UNIX_EPOCH - std::time::Duration::from_secs(2))
+        );
+        assert_eq!(LabelValue::Timestamp(before_epoch).to_string(), "-1.5");
+    }
+}
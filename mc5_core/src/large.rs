@@ -0,0 +1,531 @@
+use crate::bucket::MangoChainsawBucket;
+use crate::codec::{raw_body, CodecKind};
+use crate::dedup::{Digest, Hasher};
+use crate::errors::MangoChainsawError;
+use crate::label::Label;
+use crate::mango::MangoChainsaw;
+use crate::quota;
+use crate::settings::Quota;
+use crate::storage::{Batch, StorageBackend, Tree};
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Size of the chunks large documents are split into in the `{name}::chunks` tree.
+/// Documents that fit in a single chunk are stored inline instead.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest slice [`RawStream`] hands out for a document stored inline
+const INLINE_SLICE: usize = 64 * 1024;
+
+/// How long the chunks of a replaced or deleted document are kept once nothing refers
+/// to them, so that streams opened before the change can finish
+pub const FREED_CHUNK_GRACE: Duration = Duration::from_secs(60);
+
+/// Body tag of a document whose bytes are in the chunks tree.
+/// Codec tags count up from zero, so this stays clear of them.
+const CHUNKED_TAG: u8 = 0xFF;
+
+/// Where the bytes of a chunked document are, stored as its body in place of the bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Id the chunks are stored under
+    pub blob: u64,
+    /// Total length of the document
+    pub len: u64,
+    /// Length of every chunk but the last
+    pub chunk_size: u64,
+    /// Digest of the document as if it were stored inline, so it can be deduplicated
+    /// without reading it back. Missing from manifests written before deduplication.
+    #[serde(default)]
+    pub digest: Option<Digest>,
+    /// SHA-256 of the document's bytes. Missing from manifests written before it was kept.
+    #[serde(default)]
+    pub checksum: Option<Digest>,
+}
+
+impl Manifest {
+    /// Serialize the manifest as a document body
+    fn to_body(self) -> Result<IVec, MangoChainsawError> {
+        let mut body = vec![CHUNKED_TAG];
+        body.extend_from_slice(&MangoChainsaw::ser(self)?);
+        Ok(IVec::from(body))
+    }
+
+    /// Get the manifest out of a document body, if the document is chunked
+    pub(crate) fn from_body(body: &[u8]) -> Result<Option<Self>, MangoChainsawError> {
+        match body.split_first() {
+            Some((&CHUNKED_TAG, manifest)) => Ok(Some(MangoChainsaw::de(IVec::from(manifest))?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Chunk keys are the blob id followed by the chunk's index, both big-endian,
+/// so the chunks of a blob are contiguous and in order
+fn chunk_key(blob: u64, index: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&blob.to_be_bytes());
+    key[8..].copy_from_slice(&index.to_be_bytes());
+    key
+}
+
+/// Get the blob a chunk key belongs to
+pub(crate) fn chunk_blob(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.get(..8)?.try_into().ok()?))
+}
+
+/// Remove every chunk of a blob
+pub(crate) fn remove_blob(chunks: &Tree, blob: u64) -> Result<(), MangoChainsawError> {
+    let mut batch = Batch::default();
+    for key in chunks.scan_prefix(blob.to_be_bytes()).keys() {
+        batch.remove(key?);
+    }
+    chunks.apply_batch(batch)
+}
+
+/// The blobs this process is reading or writing, with how many streams and writers hold each.
+/// Sweeping chunks leaves these alone.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Leases(Arc<Mutex<HashMap<u64, usize>>>);
+
+impl Leases {
+    /// The counts are only ever changed a step at a time, so they are still right
+    /// if a thread panicked holding the lock
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, usize>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hold on to a blob's chunks until the lease is dropped
+    pub(crate) fn lease(&self, blob: u64) -> Lease {
+        *self.lock().entry(blob).or_default() += 1;
+        Lease {
+            leases: self.clone(),
+            blob,
+        }
+    }
+
+    /// Allocate a blob for a writer and hold on to it, in one step so that a sweep
+    /// can't see the blob without its lease
+    pub(crate) fn allocate(
+        &self,
+        storage: &dyn StorageBackend,
+    ) -> Result<Lease, MangoChainsawError> {
+        let mut held = self.lock();
+        let blob = storage.generate_id()?;
+        *held.entry(blob).or_default() += 1;
+        Ok(Lease {
+            leases: self.clone(),
+            blob,
+        })
+    }
+
+    /// Get the blobs held right now, and an id greater than every blob allocated so far
+    pub(crate) fn snapshot(
+        &self,
+        storage: &dyn StorageBackend,
+    ) -> Result<(HashSet<u64>, u64), MangoChainsawError> {
+        let held = self.lock();
+        Ok((held.keys().copied().collect(), storage.generate_id()?))
+    }
+
+    /// Whether a blob is held right now
+    pub(crate) fn is_held(&self, blob: u64) -> bool {
+        self.lock().contains_key(&blob)
+    }
+}
+
+/// A hold on a blob's chunks, see [`Leases`]
+#[derive(Debug)]
+pub(crate) struct Lease {
+    leases: Leases,
+    blob: u64,
+}
+
+impl Lease {
+    pub(crate) fn blob(&self) -> u64 {
+        self.blob
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut held = self.leases.lock();
+        if let Some(count) = held.get_mut(&self.blob) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&self.blob);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    Inline(IVec),
+    Chunked {
+        chunks: Tree,
+        blob: u64,
+        chunk_size: u64,
+        _lease: Arc<Lease>,
+    },
+}
+
+/// The stored bytes of a document. Iterating hands out slices that share memory with the
+/// store, a chunk at a time, and [`Read`] and [`Seek`] work over the whole document.
+#[derive(Clone, Debug)]
+pub struct RawStream {
+    source: Source,
+    codec: CodecKind,
+    len: u64,
+    pos: u64,
+    end: u64,
+}
+
+impl RawStream {
+    /// Stream the bytes of a document stored in one value
+    pub(crate) fn inline(bytes: IVec, codec: CodecKind) -> Self {
+        let len = bytes.len() as u64;
+        Self {
+            source: Source::Inline(bytes),
+            codec,
+            len,
+            pos: 0,
+            end: len,
+        }
+    }
+
+    /// Stream the bytes of a chunked document, holding on to its chunks until the stream
+    /// and its clones are dropped
+    pub(crate) fn chunked(chunks: Tree, manifest: Manifest, lease: Lease) -> Self {
+        Self {
+            source: Source::Chunked {
+                chunks,
+                blob: manifest.blob,
+                chunk_size: manifest.chunk_size.max(1),
+                _lease: Arc::new(lease),
+            },
+            codec: CodecKind::Raw,
+            len: manifest.len,
+            pos: 0,
+            end: manifest.len,
+        }
+    }
+
+    /// Total number of bytes in the document
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the document is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Codec the bytes were written with
+    pub fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    /// Whether the bytes are split into chunks, rather than stored in one value
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.source, Source::Chunked { .. })
+    }
+
+    /// Only stream a range of the document's bytes. Bounds past the end are clamped to it.
+    pub fn range<R>(mut self, range: R) -> Self
+    where
+        R: RangeBounds<u64>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => end.saturating_add(1),
+            Bound::Excluded(end) => *end,
+            Bound::Unbounded => self.len,
+        };
+        self.end = end.min(self.len);
+        self.pos = start.min(self.end);
+        self
+    }
+
+    /// Number of bytes left to stream
+    pub fn remaining(&self) -> u64 {
+        self.end.saturating_sub(self.pos)
+    }
+
+    /// Get up to `max` bytes from the current position, without crossing a chunk boundary
+    fn slice(&self, max: usize) -> Result<IVec, MangoChainsawError> {
+        let wanted = self.remaining().min(max as u64) as usize;
+        match &self.source {
+            Source::Inline(bytes) => Ok(bytes.subslice(self.pos as usize, wanted)),
+            Source::Chunked {
+                chunks,
+                blob,
+                chunk_size,
+                ..
+            } => {
+                let index = self.pos / chunk_size;
+                let offset = (self.pos % chunk_size) as usize;
+                let chunk = chunks.get(chunk_key(*blob, index))?.ok_or_else(|| {
+                    MangoChainsawError::Etc(format!("chunk {index} of blob {blob} is missing"))
+                })?;
+                let len = wanted.min(chunk.len().saturating_sub(offset));
+                Ok(chunk.subslice(offset.min(chunk.len()), len))
+            }
+        }
+    }
+}
+
+impl Iterator for RawStream {
+    type Item = Result<IVec, MangoChainsawError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining() == 0 {
+            return None;
+        }
+        let max = match &self.source {
+            Source::Inline(_) => INLINE_SLICE,
+            Source::Chunked { chunk_size, .. } => *chunk_size as usize,
+        };
+        match self.slice(max) {
+            Ok(slice) if slice.is_empty() => {
+                self.pos = self.end;
+                Some(Err(MangoChainsawError::Etc(
+                    "document is shorter than its manifest".to_string(),
+                )))
+            }
+            Ok(slice) => {
+                self.pos += slice.len() as u64;
+                Some(Ok(slice))
+            }
+            Err(e) => {
+                self.pos = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Read for RawStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.remaining() == 0 {
+            return Ok(0);
+        }
+        let slice = self.slice(buf.len()).map_err(io::Error::other)?;
+        if slice.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "document is shorter than its manifest",
+            ));
+        }
+        buf[..slice.len()].copy_from_slice(&slice);
+        self.pos += slice.len() as u64;
+        Ok(slice.len())
+    }
+}
+
+impl Seek for RawStream {
+    /// Seek within the whole document. Reads stop at the end of the range, if one is set.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        };
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+/// What a [`LargeObjectWriter`] does with the document once it is written
+#[derive(Clone, Debug)]
+pub(crate) enum WriteTarget {
+    /// Insert a new document
+    Insert,
+    /// Store the document under a key, replacing whatever was there
+    Key(Vec<u8>),
+    /// Replace an existing document, optionally only at a given revision
+    Replace { id: Uuid, expected_rev: Option<u64> },
+}
+
+/// Writes a document of any size with the raw codec, without holding more than a chunk of it
+/// in memory. Nothing is visible until [`LargeObjectWriter::finish`]; dropping the writer
+/// before then throws away what was written.
+#[derive(Debug)]
+pub struct LargeObjectWriter {
+    bucket: MangoChainsawBucket,
+    chunks: Tree,
+    target: WriteTarget,
+    labels: Option<Vec<Label>>,
+    content_type: Option<String>,
+    quota: Option<Quota>,
+    blob: Option<Lease>,
+    hasher: Hasher,
+    checksum: Hasher,
+    buffer: Vec<u8>,
+    written_chunks: u64,
+    len: u64,
+    finished: bool,
+}
+
+impl LargeObjectWriter {
+    pub(crate) fn new(
+        bucket: MangoChainsawBucket,
+        chunks: Tree,
+        target: WriteTarget,
+        labels: Option<Vec<Label>>,
+    ) -> Self {
+        Self {
+            bucket,
+            chunks,
+            target,
+            labels,
+            content_type: None,
+            quota: None,
+            blob: None,
+            hasher: Hasher::default(),
+            checksum: Hasher::default(),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            written_chunks: 0,
+            len: 0,
+            finished: false,
+        }
+    }
+
+    /// Record the media type of the document, such as the `Content-Type` it was uploaded with.
+    /// A document written without one has none, even if the document it replaces did.
+    pub fn with_content_type<S>(mut self, content_type: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether nothing has been written yet
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Store the document and its labels. Returns its id and new revision,
+    /// or None if it was to replace a document that doesn't exist.
+    pub fn finish(mut self) -> Result<Option<(Uuid, u64)>, MangoChainsawError> {
+        self.finished = true;
+        let result = self.commit();
+        if !matches!(result, Ok(Some(_))) {
+            self.discard();
+        }
+        result
+    }
+
+    fn commit(&mut self) -> Result<Option<(Uuid, u64)>, MangoChainsawError> {
+        let body = match self.blob.as_ref().map(Lease::blob) {
+            None => raw_body(&self.buffer),
+            Some(blob) => {
+                self.write_chunk()?;
+                Manifest {
+                    blob,
+                    len: self.len,
+                    chunk_size: CHUNK_SIZE as u64,
+                    digest: Some(std::mem::take(&mut self.hasher).finish()),
+                    checksum: Some(std::mem::take(&mut self.checksum).finish()),
+                }
+                .to_body()?
+            }
+        };
+        self.bucket.commit_body(
+            &self.target,
+            body,
+            self.labels.take(),
+            Some(self.content_type.take()),
+        )
+    }
+
+    /// Move the buffer into the chunks tree
+    fn write_chunk(&mut self) -> Result<(), MangoChainsawError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        // Stop a document that is over the size limit before any more of it is stored
+        let quota = match &self.quota {
+            Some(quota) => quota,
+            None => self.quota.insert(self.bucket.settings()?.quota),
+        };
+        quota::check_size(quota, self.bucket.name(), self.len)?;
+        let blob = match &self.blob {
+            Some(lease) => lease.blob(),
+            None => {
+                self.hasher.update(&[CodecKind::Raw.tag()]);
+                self.blob.insert(self.bucket.allocate_blob()?).blob()
+            }
+        };
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.hasher.update(&chunk);
+        self.checksum.update(&chunk);
+        self.chunks
+            .insert(chunk_key(blob, self.written_chunks), chunk)?;
+        self.written_chunks += 1;
+        Ok(())
+    }
+
+    /// Remove the chunks written so far. No document refers to them, so nothing can be
+    /// reading them.
+    fn discard(&mut self) {
+        if let Some(lease) = self.blob.take() {
+            let blob = lease.blob();
+            if let Err(e) = remove_blob(&self.chunks, blob) {
+                warn!(
+                    blob,
+                    error = format!("{e}"),
+                    "Failed to remove discarded chunks"
+                );
+            }
+        }
+    }
+}
+
+impl Write for LargeObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk().map_err(io::Error::other)?;
+        }
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    /// Chunks are only written once they are full, so this does nothing
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LargeObjectWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.discard();
+        }
+    }
+}
@@ -1,5 +1,20 @@
+mod bitmap;
 pub mod bucket;
+pub mod codec;
 pub mod config;
+mod dedup;
 pub mod errors;
+mod gate;
 pub mod label;
+pub mod large;
 pub mod mango;
+pub mod metadata;
+pub mod page;
+pub mod query;
+pub mod quota;
+mod range;
+mod reaper;
+pub mod registry;
+pub mod schema;
+pub mod settings;
+pub mod storage;
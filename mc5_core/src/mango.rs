@@ -1,40 +1,122 @@
-use crate::config::MangoChainsawConfig;
+use crate::codec::{Codec, Flexbuffers};
+use crate::config::{IdStrategy, MangoChainsawConfig};
+use crate::gate::WriteGates;
+use crate::large::{Leases, FREED_CHUNK_GRACE};
+use crate::reaper::Reaper;
+use crate::registry::{validate_bucket_name, BucketInfo, CopyProgress, COPIES_TREE, REGISTRY_TREE};
+use crate::schema::SchemaCache;
+use crate::settings::BucketSettings;
+use crate::storage::{MemoryBackend, SledBackend, StorageBackend, Tree};
 use crate::{bucket::MangoChainsawBucket, errors::MangoChainsawError};
-use flexbuffers::FlexbufferSerializer;
 use serde::{de::DeserializeOwned, Serialize};
+use sled::transaction::ConflictableTransactionError;
 use sled::IVec;
 use std::cmp::min;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tracing::debug;
+use tracing::info;
 use tracing::instrument;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub struct MangoChainsaw {
-    pub(crate) db: sled::Db,
+    pub(crate) storage: Arc<dyn StorageBackend>,
+    pub(crate) registry: Tree,
+    copies: Tree,
+    pub(crate) id_strategy: IdStrategy,
+    pub(crate) schemas: SchemaCache,
+    pub(crate) leases: Leases,
+    pub(crate) gates: WriteGates,
+    last_ulid: Arc<Mutex<ulid::Ulid>>,
+    reaper: Option<Arc<Reaper>>,
 }
 
 impl MangoChainsaw {
-    /// Create or open an existing Mc5 from a Config
+    /// Create or open an existing Mc5 from a Config.
+    /// Temporary databases are kept in memory, everything else is stored with sled.
     #[instrument]
     pub fn new(config: MangoChainsawConfig) -> Result<Self, MangoChainsawError> {
         debug!("Opening db");
-        Ok(Self {
-            db: config.to_sled_config().open()?,
-        })
+        let storage: Arc<dyn StorageBackend> = if config.temporary {
+            Arc::new(MemoryBackend::new())
+        } else {
+            Arc::new(SledBackend::open(&config)?)
+        };
+        Self::with_storage(config, storage)
     }
 
-    /// Get a named tree from the sled backend
+    /// Create or open an existing Mc5 on a given storage backend
+    #[instrument(skip(storage))]
+    pub fn with_storage(
+        config: MangoChainsawConfig,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<Self, MangoChainsawError> {
+        let mut this = Self {
+            registry: storage.open_tree(REGISTRY_TREE)?,
+            copies: storage.open_tree(COPIES_TREE)?,
+            storage,
+            id_strategy: config.id_strategy,
+            schemas: SchemaCache::default(),
+            leases: Leases::default(),
+            gates: WriteGates::default(),
+            last_ulid: Arc::new(Mutex::new(ulid::Ulid::nil())),
+            reaper: None,
+        };
+        this.finish_copies()?;
+        this.adopt_legacy_buckets()?;
+        if config.reaper_interval > 0 {
+            let interval = Duration::from_secs(config.reaper_interval);
+            this.reaper = Some(Arc::new(Reaper::spawn(this.clone(), interval)?));
+        }
+        Ok(this)
+    }
+
+    /// Get a named tree from the storage backend
     #[instrument(skip(self))]
-    pub(crate) fn get_tree(&self, name: &str) -> Result<sled::Tree, MangoChainsawError> {
+    pub(crate) fn get_tree(&self, name: &str) -> Result<Tree, MangoChainsawError> {
         debug!("Opening tree {name}");
-        Ok(self.db.open_tree(name)?)
+        self.storage.open_tree(name)
     }
 
-    /// Get the next ID from the sled monotonic idgen
-    #[instrument(skip(self), fields(node_id))]
+    /// Generate the next document ID with the configured [`IdStrategy`]
+    #[instrument(skip(self))]
     pub(crate) fn next_id(&self) -> Result<Uuid, MangoChainsawError> {
+        self.generate_id(self.id_strategy)
+    }
+
+    /// Generate the next document ID with a bucket's [`IdStrategy`]
+    pub(crate) fn generate_id(&self, strategy: IdStrategy) -> Result<Uuid, MangoChainsawError> {
+        match strategy {
+            IdStrategy::V6 => self.next_v6_id(),
+            IdStrategy::V7 => Ok(Uuid::now_v7()),
+            IdStrategy::Ulid => self.next_ulid(),
+            IdStrategy::Provided => Err(MangoChainsawError::IdRequired),
+        }
+    }
+
+    /// Get the next ULID, incrementing the random part when the millisecond hasn't changed
+    /// so that ids stay ordered by creation
+    fn next_ulid(&self) -> Result<Uuid, MangoChainsawError> {
+        let mut last = self
+            .last_ulid
+            .lock()
+            .map_err(|e| MangoChainsawError::Etc(format!("ulid generator poisoned: {e}")))?;
+        let mut next = ulid::Ulid::new();
+        if next <= *last {
+            next = last
+                .increment()
+                .ok_or_else(|| MangoChainsawError::Etc("ulid random part overflowed".into()))?;
+        }
+        *last = next;
+        Ok(Uuid::from_u128(next.0))
+    }
+
+    /// Get the next v6 ID, with a node id from the storage backend's monotonic idgen
+    #[instrument(skip(self), fields(node_id))]
+    fn next_v6_id(&self) -> Result<Uuid, MangoChainsawError> {
         let node_id = self
-            .db
+            .storage
             .generate_id()?
             .to_be_bytes()
             .into_iter()
@@ -47,74 +129,357 @@ impl MangoChainsaw {
         Ok(Uuid::now_v6(&node_id))
     }
 
-    /// Create or open a named bucket
+    /// Open an existing bucket. Fails with [`MangoChainsawError::BucketNotFound`]
+    /// if it hasn't been created.
     #[instrument(skip(self), fields(this))]
     pub fn get_bucket(&self, name: &str) -> Result<MangoChainsawBucket, MangoChainsawError> {
+        if !self.bucket_exists(name)? && !self.adopt_bucket(name)? {
+            return Err(MangoChainsawError::BucketNotFound(name.to_string()));
+        }
         let this = MangoChainsawBucket::new(self, name)?;
         debug!("Opened bucket {name}");
         Ok(this)
     }
 
+    /// Create a bucket with the given settings. Fails with
+    /// [`MangoChainsawError::BucketExists`] if there is already a bucket with that name,
+    /// or [`MangoChainsawError::InvalidBucketName`] if the name can't be used.
+    #[instrument(skip(self))]
+    pub fn create_bucket(
+        &self,
+        name: &str,
+        settings: BucketSettings,
+    ) -> Result<MangoChainsawBucket, MangoChainsawError> {
+        self.register(
+            name,
+            BucketInfo {
+                created: SystemTime::now(),
+                settings,
+            },
+        )?;
+        debug!("Created bucket {name}");
+        MangoChainsawBucket::new(self, name)
+    }
+
+    /// Add a bucket to the registry, unless the name is taken or can't be used.
+    /// A bucket without an id strategy gets the configured one.
+    fn register(&self, name: &str, info: BucketInfo) -> Result<(), MangoChainsawError> {
+        self.register_copy(name, info, None)
+    }
+
+    /// Add a bucket to the registry as [`Self::register`] does, and record in the same step
+    /// that it is being written as a copy of another, if it is
+    fn register_copy(
+        &self,
+        name: &str,
+        mut info: BucketInfo,
+        progress: Option<&CopyProgress>,
+    ) -> Result<(), MangoChainsawError> {
+        validate_bucket_name(name)?;
+        info.settings.validate()?;
+        info.settings.id_strategy.get_or_insert(self.id_strategy);
+        let info = Self::ser(info)?;
+        let progress = progress.map(Self::ser).transpose()?;
+        self.storage
+            .transaction([&self.registry, &self.copies], |[registry, copies]| {
+                if registry.insert(name, info.clone())?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(
+                        MangoChainsawError::BucketExists(name.to_string()),
+                    ));
+                }
+                if let Some(progress) = &progress {
+                    copies.insert(name, progress.clone())?;
+                }
+                Ok(())
+            })?;
+        self.gates.reopen(name);
+        Ok(())
+    }
+
+    /// Rename a bucket, keeping its documents, their ids, and its creation time and settings.
+    /// Writes to the bucket wait for the rename, then fail with
+    /// [`MangoChainsawError::BucketNotFound`]. A rename that is interrupted is finished,
+    /// or undone if it hadn't copied everything, when the database is next opened.
+    #[instrument(skip(self))]
+    pub fn rename_bucket(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<MangoChainsawBucket, MangoChainsawError> {
+        let source = self.get_bucket(from)?;
+        let closed = self.gates.close(from);
+        let info = self
+            .bucket_info(from)?
+            .ok_or_else(|| MangoChainsawError::BucketNotFound(from.to_string()))?;
+        let mut progress = CopyProgress {
+            from: from.to_string(),
+            rename: true,
+            copied: false,
+        };
+        self.register_copy(to, info, Some(&progress))?;
+        if let Err(e) = source.copy_trees(to, true) {
+            self.finish_copy(to, &progress)?;
+            return Err(e);
+        }
+        progress.copied = true;
+        self.copies.insert(to, Self::ser(&progress)?)?;
+        self.finish_copy(to, &progress)?;
+        closed.retire();
+        info!("Renamed bucket {from} to {to}");
+        MangoChainsawBucket::new(self, to)
+    }
+
+    /// Copy a bucket's documents, labels, history and settings into a new bucket.
+    /// The copies get new ids and creation times unless `keep_ids` is set, which a bucket
+    /// whose ids are provided by the caller needs. Writes to the bucket wait for the copy.
+    /// A copy that is interrupted is dropped when the database is next opened.
+    #[instrument(skip(self))]
+    pub fn copy_bucket(
+        &self,
+        from: &str,
+        to: &str,
+        keep_ids: bool,
+    ) -> Result<MangoChainsawBucket, MangoChainsawError> {
+        let source = self.get_bucket(from)?;
+        let _closed = self.gates.close(from);
+        let mut progress = CopyProgress {
+            from: from.to_string(),
+            rename: false,
+            copied: false,
+        };
+        let info = BucketInfo {
+            created: SystemTime::now(),
+            settings: source.settings()?,
+        };
+        self.register_copy(to, info, Some(&progress))?;
+        let copied = match source.copy_trees(to, keep_ids) {
+            Ok(copied) => copied,
+            Err(e) => {
+                self.finish_copy(to, &progress)?;
+                return Err(e);
+            }
+        };
+        progress.copied = true;
+        self.finish_copy(to, &progress)?;
+        info!("Copied {copied} documents from bucket {from} to {to}");
+        MangoChainsawBucket::new(self, to)
+    }
+
+    /// Settle a copy or rename into `to`: drop `to` if it wasn't copied in full,
+    /// or the bucket it was renamed from if it was, then forget about it
+    fn finish_copy(&self, to: &str, progress: &CopyProgress) -> Result<(), MangoChainsawError> {
+        match (progress.copied, progress.rename) {
+            (false, _) => MangoChainsawBucket::drop_trees(self, to)?,
+            (true, true) => MangoChainsawBucket::drop_trees(self, &progress.from)?,
+            (true, false) => {}
+        }
+        self.copies.remove(to)?;
+        Ok(())
+    }
+
+    /// Settle the copies and renames that were interrupted, see [`Self::finish_copy`]
+    fn finish_copies(&self) -> Result<(), MangoChainsawError> {
+        let mut interrupted = vec![];
+        for result in self.copies.iter() {
+            let (to, raw) = result?;
+            let progress: CopyProgress = Self::de(raw)?;
+            interrupted.push((std::str::from_utf8(&to)?.to_string(), progress));
+        }
+        for (to, progress) in interrupted {
+            info!(
+                from = progress.from,
+                to,
+                copied = progress.copied,
+                "Settling an interrupted copy"
+            );
+            self.finish_copy(&to, &progress)?;
+        }
+        Ok(())
+    }
+
+    /// Open a bucket, creating it with default settings if it doesn't exist
+    #[instrument(skip(self))]
+    pub fn get_or_create_bucket(
+        &self,
+        name: &str,
+    ) -> Result<MangoChainsawBucket, MangoChainsawError> {
+        match self.get_bucket(name) {
+            Err(MangoChainsawError::BucketNotFound(_)) => {
+                match self.create_bucket(name, BucketSettings::default()) {
+                    Err(MangoChainsawError::BucketExists(_)) => self.get_bucket(name),
+                    created => created,
+                }
+            }
+            opened => opened,
+        }
+    }
+
+    /// Check whether a bucket has been created
+    #[instrument(skip(self))]
+    pub fn bucket_exists(&self, name: &str) -> Result<bool, MangoChainsawError> {
+        Ok(self.registry.get(name)?.is_some())
+    }
+
+    /// Get a bucket's registry entry: when it was created and its settings
+    #[instrument(skip(self))]
+    pub fn bucket_info(&self, name: &str) -> Result<Option<BucketInfo>, MangoChainsawError> {
+        match self.registry.get(name)? {
+            Some(raw) => Ok(Some(Self::de(raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the settings in a bucket's registry entry. The id strategy can be filled in
+    /// if the bucket doesn't have one yet, but not changed. Returns the settings it replaced.
+    pub(crate) fn set_bucket_settings(
+        &self,
+        name: &str,
+        settings: BucketSettings,
+    ) -> Result<BucketSettings, MangoChainsawError> {
+        settings.validate()?;
+        self.storage.transaction([&self.registry], |[registry]| {
+            let Some(raw) = registry.get(name)? else {
+                return Err(ConflictableTransactionError::Abort(
+                    MangoChainsawError::BucketNotFound(name.to_string()),
+                ));
+            };
+            let mut info: BucketInfo =
+                Self::de(raw).map_err(ConflictableTransactionError::Abort)?;
+            let previous = info.settings.clone();
+            let strategy = match (info.settings.id_strategy, settings.id_strategy) {
+                (Some(current), Some(new)) if current != new => {
+                    return Err(ConflictableTransactionError::Abort(
+                        MangoChainsawError::IdStrategyFixed(name.to_string()),
+                    ));
+                }
+                (current, new) => current.or(new),
+            };
+            info.settings = BucketSettings {
+                id_strategy: strategy,
+                ..settings.clone()
+            };
+            let info = Self::ser(info).map_err(ConflictableTransactionError::Abort)?;
+            registry.insert(name, info)?;
+            Ok(previous)
+        })
+    }
+
+    /// Register a bucket that was written before the registry existed, if its trees are there.
+    /// Returns whether it was registered.
+    fn adopt_bucket(&self, name: &str) -> Result<bool, MangoChainsawError> {
+        let documents = IVec::from(format!("{name}::doc").as_str());
+        if !self.storage.tree_names().contains(&documents) {
+            return Ok(false);
+        }
+        let info = Self::ser(BucketInfo {
+            created: SystemTime::now(),
+            settings: BucketSettings {
+                id_strategy: Some(self.id_strategy),
+                ..Default::default()
+            },
+        })?;
+        if self.registry.insert(name, info)?.is_none() {
+            debug!("Registered existing bucket {name}");
+            // Opening it brings its settings over to the registry
+            MangoChainsawBucket::new(self, name)?;
+        }
+        Ok(true)
+    }
+
+    /// Register every bucket that was written before the registry existed.
+    /// Their names were never checked, so they are kept as they are.
+    fn adopt_legacy_buckets(&self) -> Result<(), MangoChainsawError> {
+        for raw_name in self.storage.tree_names() {
+            let tree = std::str::from_utf8(&raw_name)?;
+            if let Some(name) = tree.strip_suffix("::doc") {
+                if !name.starts_with("__") && !self.bucket_exists(name)? {
+                    self.adopt_bucket(name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// List buckets
     #[instrument(skip(self))]
     pub fn list_buckets(&self) -> Result<Vec<String>, MangoChainsawError> {
         let mut results = vec![];
-        for raw_name in self.db.tree_names() {
-            let name = std::str::from_utf8(&raw_name)?;
-            results.push(name.to_string());
-        }
-        results = results.into_iter().fold(vec![], |mut acc, r| {
-            if r.contains("::") {
-                if let Some((name, _suffix)) = r.split_once("::") {
-                    acc.push(name.to_string());
-                }
-            }
-            acc
-        });
-        results.sort();
-        results.dedup();
+        for raw_name in self.registry.iter().keys() {
+            results.push(std::str::from_utf8(&raw_name?)?.to_string());
+        }
         Ok(results)
     }
 
+    /// Remove expired documents from every bucket, returning how many were removed
+    #[instrument(skip(self))]
+    pub fn reap_expired(&self) -> Result<usize, MangoChainsawError> {
+        let mut total = 0;
+        for name in self.list_buckets()? {
+            total += self.get_bucket(&name)?.reap_expired()?;
+        }
+        Ok(total)
+    }
+
+    /// Remove the chunks nothing refers to anymore from every bucket, once they have gone
+    /// unreferenced for [`FREED_CHUNK_GRACE`]. Returns the number of blobs removed.
+    #[instrument(skip(self))]
+    pub fn collect_chunks(&self) -> Result<usize, MangoChainsawError> {
+        let mut total = 0;
+        for name in self.list_buckets()? {
+            total += self.get_bucket(&name)?.collect_chunks(FREED_CHUNK_GRACE)?;
+        }
+        Ok(total)
+    }
+
     /// Drop a bucket
     #[instrument(skip(self))]
     pub fn drop_bucket(&self, name: &str) -> Result<(), MangoChainsawError> {
-        let b = MangoChainsawBucket::new(self, name)?;
+        let b = self.get_bucket(name)?;
         b.drop_bucket()?;
         Ok(())
     }
 
-    /// Serialize a thing to be stored as a document
+    /// Serialize internal metadata, such as labels and settings, which is always flexbuffers
     #[instrument(skip(o))]
     pub(crate) fn ser<T>(o: T) -> Result<IVec, MangoChainsawError>
     where
         T: Serialize,
     {
-        let mut ser = FlexbufferSerializer::new();
-        o.serialize(&mut ser)?;
+        let out = Flexbuffers::encode(&o)?;
         debug!("serialize success");
-        Ok(IVec::from(ser.take_buffer()))
+        Ok(IVec::from(out))
     }
 
-    /// Deserialize bytes from the backend into a document
+    /// Deserialize internal metadata written by [`MangoChainsaw::ser`]
     #[instrument(skip(b))]
     pub(crate) fn de<T>(b: IVec) -> Result<T, MangoChainsawError>
     where
         T: DeserializeOwned,
     {
-        let rdr = flexbuffers::Reader::get_root(b.as_ref())?;
+        let out = Flexbuffers::decode(&b)?;
         debug!("deserialize success");
-        Ok(T::deserialize(rdr)?)
+        Ok(out)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::MangoChainsawConfig;
-    use crate::label::Label;
+    use crate::codec::{encode_body, CodecKind};
+    use crate::config::{IdStrategy, MangoChainsawConfig};
+    use crate::label::{Label, LabelValue};
+    use crate::large::CHUNK_SIZE;
+    use crate::page::Cursor;
+    use crate::query::Query;
+    use crate::quota::{QuotaLimit, Usage};
+    use crate::schema::{BodySchema, BodyViolation, LabelSchema, LabelViolation, ValueRule};
+    use crate::settings::{BucketSettings, Quota};
+    use crate::storage::SledBackend;
     use crate::{mclabel, mclabels};
     use serde::Deserialize;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::time::SystemTime;
     use std::time::UNIX_EPOCH;
     use tracing_subscriber::EnvFilter;
@@ -134,18 +499,23 @@ mod tests {
                 .as_secs();
             Self {
                 x: now,
-                y: now % 2 == 0,
+                y: now.is_multiple_of(2),
                 z: format!("{now}"),
             }
         }
     }
 
     fn init_tracing() {
-        tracing_subscriber::fmt()
+        let _ = tracing_subscriber::fmt()
             .pretty()
             .with_ansi(true)
             .with_env_filter(EnvFilter::from_default_env())
-            .init();
+            .try_init();
+    }
+
+    fn test_db() -> Result<MangoChainsaw, MangoChainsawError> {
+        init_tracing();
+        MangoChainsaw::new(MangoChainsawConfig::default())
     }
 
     #[test]
@@ -166,7 +536,7 @@ mod tests {
             "tls.howmuchmeat.cat" => "status: up"
         );
 
-        let bucket = db.get_bucket("testing_objects")?;
+        let bucket = db.get_or_create_bucket("testing_objects")?;
         let id = bucket.insert(&object, labels)?;
         println!("{id}");
 
@@ -194,4 +564,1702 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_replace_update() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("replace_objects")?;
+        let object = Testobj::new();
+        let id = bucket.insert(&object, mclabels!("object_type" => "test"))?;
+
+        let mut replacement = object.clone();
+        replacement.z = "replaced".to_string();
+        let previous = bucket.replace(id, replacement.clone())?;
+        assert_eq!(previous, Some(object));
+        assert_eq!(bucket.get::<Testobj>(id)?, Some(replacement.clone()));
+
+        let previous = bucket.update(id, |mut o: Testobj| {
+            o.x += 1;
+            o
+        })?;
+        assert_eq!(previous, Some(replacement.clone()));
+        assert_eq!(
+            bucket.get::<Testobj>(id)?.map(|o| o.x),
+            Some(replacement.x + 1)
+        );
+
+        bucket.replace_with_labels(id, replacement, mclabels!("object_type" => "replaced"))?;
+        assert!(bucket
+            .search_inclusive(mclabels!("object_type" => "test"))?
+            .is_empty());
+        assert_eq!(
+            bucket.search_inclusive(mclabels!("object_type" => "replaced"))?,
+            vec![id]
+        );
+
+        let missing = bucket.replace(Uuid::nil(), Testobj::new())?;
+        assert!(missing.is_none());
+        assert!(bucket.get::<Testobj>(Uuid::nil())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_revisions_compare_and_swap() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("revision_objects")?;
+        let object = Testobj::new();
+        let id = bucket.insert(&object, mclabels!("object_type" => "test"))?;
+        assert_eq!(bucket.revision(id)?, Some(1));
+
+        bucket.replace(id, object.clone())?;
+        assert_eq!(bucket.revision(id)?, Some(2));
+
+        let rev = bucket.compare_and_swap(id, 2, &object)?;
+        assert_eq!(rev, 3);
+        assert_eq!(
+            bucket.get_with_revision::<Testobj>(id)?,
+            Some((object.clone(), 3))
+        );
+
+        match bucket.compare_and_swap(id, 2, &object) {
+            Err(MangoChainsawError::Conflict {
+                expected, found, ..
+            }) => {
+                assert_eq!((expected, found), (2, Some(3)))
+            }
+            other => panic!("expected a conflict, got {other:?}"),
+        }
+        assert!(matches!(
+            bucket.compare_and_swap(Uuid::nil(), 1, &object),
+            Err(MangoChainsawError::Conflict { found: None, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_history() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("history_objects")?;
+        bucket.set_settings(BucketSettings {
+            keep_history: true,
+            max_versions: Some(2),
+            ..Default::default()
+        })?;
+
+        let first = Testobj::new();
+        let id = bucket.insert(&first, mclabels!("object_type" => "first"))?;
+        let mut second = first.clone();
+        second.z = "second".to_string();
+        bucket.replace_with_labels(id, second.clone(), mclabels!("object_type" => "second"))?;
+        assert_eq!(bucket.list_versions(id)?, vec![1, 2]);
+        assert_eq!(bucket.get_version::<Testobj>(id, 1)?, Some(first.clone()));
+
+        let rev = bucket.restore(id, 1)?;
+        assert_eq!(rev, Some(3));
+        assert_eq!(bucket.get::<Testobj>(id)?, Some(first.clone()));
+        assert_eq!(
+            bucket.search_inclusive(mclabels!("object_type" => "first"))?,
+            vec![id]
+        );
+
+        // Only the two newest archived versions are retained
+        bucket.replace(id, second.clone())?;
+        assert_eq!(bucket.list_versions(id)?, vec![2, 3, 4]);
+
+        bucket.delete::<Testobj>(id)?;
+        assert_eq!(bucket.list_versions(id)?, vec![3, 4]);
+        assert_eq!(bucket.restore(id, 4)?, Some(5));
+        assert_eq!(bucket.get::<Testobj>(id)?, Some(second.clone()));
+
+        // Restored versions have to fit the quota as it is now
+        let settings = bucket.settings()?;
+        for quota in [
+            Quota {
+                max_document_size: Some(4),
+                ..Default::default()
+            },
+            Quota {
+                max_labels_per_document: Some(0),
+                ..Default::default()
+            },
+        ] {
+            bucket.set_settings(BucketSettings {
+                quota,
+                ..settings.clone()
+            })?;
+            assert!(matches!(
+                bucket.restore(id, 3),
+                Err(MangoChainsawError::QuotaExceeded { .. })
+            ));
+        }
+        bucket.set_settings(settings)?;
+        assert_eq!(bucket.get::<Testobj>(id)?, Some(second.clone()));
+
+        // A deleted document gets its key back, unless another document has taken it
+        let keyed = bucket.insert_with_key("k", &first, vec![])?;
+        bucket.delete::<Testobj>(keyed)?;
+        assert_eq!(bucket.key_id("k")?, None);
+        bucket.restore(keyed, 1)?;
+        assert_eq!(bucket.key_id("k")?, Some(keyed));
+        bucket.delete::<Testobj>(keyed)?;
+        let taken = bucket.insert_with_key("k", &second, vec![])?;
+        assert!(matches!(
+            bucket.restore(keyed, 1),
+            Err(MangoChainsawError::DuplicateKey(_))
+        ));
+        assert_eq!(bucket.key_id("k")?, Some(taken));
+        assert!(bucket.get::<Testobj>(keyed)?.is_none());
+
+        // An expired document comes back without its old expiry
+        let expiring = bucket.insert_with_ttl(&first, vec![], Duration::from_millis(50))?;
+        bucket.replace(expiring, second.clone())?;
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(bucket.get::<Testobj>(expiring)?.is_none());
+        bucket.restore(expiring, 1)?;
+        assert_eq!(bucket.ttl(expiring)?, None);
+        assert_eq!(bucket.reap_expired()?, 0);
+        assert_eq!(bucket.get::<Testobj>(expiring)?, Some(first));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl_expiry() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("ttl_objects")?;
+        let object = Testobj::new();
+        let keep = bucket.insert(&object, mclabels!("object_type" => "test"))?;
+        let gone = bucket.insert_with_ttl(
+            &object,
+            mclabels!("object_type" => "test", "cache" => "yes"),
+            Duration::from_millis(50),
+        )?;
+        assert!(bucket.ttl(gone)?.is_some());
+        assert_eq!(
+            bucket
+                .search_inclusive(mclabels!("object_type" => "test"))?
+                .len(),
+            2
+        );
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(bucket.get::<Testobj>(gone)?.is_none());
+        assert_eq!(
+            bucket.search_inclusive(mclabels!("object_type" => "test"))?,
+            vec![keep]
+        );
+
+        // Until it is reaped, an expired document can't be found by label or brought back
+        assert!(bucket.label_name_search("cache")?.is_empty());
+        assert!(bucket.label_value_search("yes")?.is_empty());
+        assert_eq!(
+            bucket.label_name_search("object_type")?,
+            mclabels!("object_type" => "test")
+        );
+        assert!(bucket.replace(gone, Testobj::new())?.is_none());
+        assert!(bucket.update(gone, |obj: Testobj| obj)?.is_none());
+        assert!(matches!(
+            bucket.compare_and_swap(gone, 1, Testobj::new()),
+            Err(MangoChainsawError::Conflict { found: None, .. })
+        ));
+        assert!(bucket.get::<Testobj>(gone)?.is_none());
+
+        assert_eq!(db.reap_expired()?, 1);
+        assert!(bucket.label_name_search("cache")?.is_empty());
+        assert_eq!(bucket.get::<Testobj>(keep)?, Some(object));
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_delete_many() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("batch_objects")?;
+        let batch: Vec<(Testobj, Vec<Label>)> = (0..100)
+            .map(|i| {
+                let labels = mclabels!(
+                    "object_type" => "test",
+                    "parity" => if i % 2 == 0 { "even" } else { "odd" }
+                );
+                (Testobj::new(), labels)
+            })
+            .collect();
+        let ids = bucket.insert_many(batch)?;
+        assert_eq!(ids.len(), 100);
+        assert_eq!(
+            bucket
+                .search_inclusive(mclabels!("parity" => "even"))?
+                .len(),
+            50
+        );
+
+        let evens: Vec<Uuid> = ids.iter().step_by(2).copied().collect();
+        let deleted = bucket.delete_many::<Testobj>(evens.clone())?;
+        assert!(deleted.iter().all(|(_, doc)| doc.is_some()));
+        assert!(bucket.get_label(mclabel!("parity" => "even"))?.is_none());
+        assert_eq!(
+            bucket
+                .search_inclusive(mclabels!("object_type" => "test"))?
+                .len(),
+            50
+        );
+        assert!(bucket.get::<Testobj>(evens[0])?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitmap_set_algebra() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("bitmap_objects")?;
+        let batch: Vec<(Testobj, Vec<Label>)> = (0..12)
+            .map(|i| {
+                let mut labels = mclabels!("object_type" => "test");
+                if i % 2 == 0 {
+                    labels.push(mclabel!("two" => "true"));
+                }
+                if i % 3 == 0 {
+                    labels.push(mclabel!("three" => "true"));
+                }
+                (Testobj::new(), labels)
+            })
+            .collect();
+        let ids = bucket.insert_many(batch)?;
+        let pick = |is: &[usize]| -> Vec<Uuid> {
+            let mut picked: Vec<Uuid> = is.iter().map(|i| ids[*i]).collect();
+            picked.sort();
+            picked
+        };
+
+        let two = mclabel!("two" => "true");
+        let three = mclabel!("three" => "true");
+        assert_eq!(
+            bucket.search_inclusive(vec![two.clone(), three.clone()])?,
+            pick(&[0, 6])
+        );
+        assert_eq!(
+            bucket.search_any(vec![two.clone(), three.clone()])?,
+            pick(&[0, 2, 3, 4, 6, 8, 9, 10])
+        );
+        assert_eq!(
+            bucket.search_excluding(vec![two.clone()], vec![three.clone()])?,
+            pick(&[2, 4, 8, 10])
+        );
+        assert_eq!(
+            bucket.search_excluding(vec![], vec![two.clone(), three.clone()])?,
+            pick(&[1, 5, 7, 11])
+        );
+
+        // Deletes, relabels and restores all keep the bitmaps in step
+        bucket.delete::<Testobj>(ids[0])?;
+        bucket.replace_with_labels(ids[1], Testobj::new(), vec![two.clone()])?;
+        bucket.remove_document_labels(ids[6], vec![three.clone()])?;
+        assert_eq!(
+            bucket.search_inclusive(vec![two.clone()])?,
+            pick(&[1, 2, 4, 6, 8, 10])
+        );
+        assert_eq!(bucket.search_inclusive(vec![three.clone()])?, pick(&[3, 9]));
+        assert_eq!(
+            bucket.search_excluding(vec![], vec![two, three])?,
+            pick(&[5, 7, 11])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_query() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("query_objects")?;
+        let files = [
+            ("rust_code", "src/main.rs"),
+            ("rust_code", "target/build/out.rs"),
+            ("toml_config", "Cargo.toml"),
+            ("cargo_lock", "Cargo.lock"),
+        ];
+        let batch: Vec<(Testobj, Vec<Label>)> = files
+            .iter()
+            .map(|(filetype, path)| {
+                (
+                    Testobj::new(),
+                    mclabels!("filetype" => filetype, "path" => path),
+                )
+            })
+            .collect();
+        let ids = bucket.insert_many(batch)?;
+        bucket.add_document_labels(ids[0], mclabels!("owner" => "alice"))?;
+        let query = |text: &str| -> Result<Vec<Uuid>, MangoChainsawError> {
+            bucket.query(&Query::parse(text)?)
+        };
+        let pick = |is: &[usize]| -> Vec<Uuid> {
+            let mut picked: Vec<Uuid> = is.iter().map(|i| ids[*i]).collect();
+            picked.sort();
+            picked
+        };
+
+        assert_eq!(
+            query("filetype=rust_code AND NOT path^=target/")?,
+            pick(&[0])
+        );
+        assert_eq!(query("path^=Cargo.")?, pick(&[2, 3]));
+        assert_eq!(
+            query("filetype IN (toml_config, cargo_lock) OR owner")?,
+            pick(&[0, 2, 3])
+        );
+        assert_eq!(query("NOT owner")?, pick(&[1, 2, 3]));
+        assert_eq!(query("NOT (filetype=rust_code OR owner)")?, pick(&[2, 3]));
+
+        // Missing labels match nothing rather than being skipped
+        assert_eq!(query("filetype=rust_code AND filetype=python")?, vec![]);
+        assert_eq!(query("missing")?, vec![]);
+        assert_eq!(
+            bucket.search_inclusive(mclabels!("filetype" => "rust_code", "owner" => "bob"))?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_search() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("range_objects")?;
+        let batch: Vec<(Testobj, Vec<Label>)> = [
+            ("-20", "2024-01-01T00:00:00Z", "apple"),
+            ("5", "2024-02-01T00:00:00Z", "banana"),
+            ("1048576", "2024-03-01T12:00:00Z", "cherry"),
+            ("4194304", "2024-04-01T00:00:00Z", "date"),
+        ]
+        .iter()
+        .map(|(size, when, name)| {
+            let labels = mclabels!("document_size" => size, "when" => when, "name" => name);
+            (Testobj::new(), labels)
+        })
+        .collect();
+        let ids = bucket.insert_many(batch)?;
+        bucket.add_document_labels(ids[0], mclabels!("document_size" => "1.5"))?;
+        let pick = |is: &[usize]| -> Vec<Uuid> {
+            let mut picked: Vec<Uuid> = is.iter().map(|i| ids[*i]).collect();
+            picked.sort();
+            picked
+        };
+
+        let mb = LabelValue::Int(1 << 20);
+        assert_eq!(
+            bucket.range_search("document_size", mb.clone()..)?,
+            pick(&[2, 3])
+        );
+        assert_eq!(
+            bucket.range_search("document_size", ..mb.clone())?,
+            pick(&[0, 1])
+        );
+        assert_eq!(
+            bucket.range_search("document_size", LabelValue::Int(-20)..=mb.clone())?,
+            pick(&[0, 1, 2])
+        );
+        assert_eq!(
+            bucket.range_search("document_size", LabelValue::Float(1.0)..)?,
+            pick(&[0, 1, 2, 3])
+        );
+        assert_eq!(
+            bucket.range_search("document_size", ..)?,
+            pick(&[0, 1, 2, 3])
+        );
+        assert_eq!(
+            bucket.range_search("document_size", LabelValue::Int(0)..LabelValue::Float(9.0))?,
+            pick(&[0, 1])
+        );
+        assert_eq!(
+            bucket.query(&Query::parse("document_size>1.5")?)?,
+            pick(&[1, 2, 3])
+        );
+        assert_eq!(
+            bucket.range_search("document_size", LabelValue::Float(5.0)..=LabelValue::Int(5))?,
+            pick(&[1])
+        );
+
+        let t1 = LabelValue::infer("2024-02-01T00:00:00Z");
+        let t2 = LabelValue::infer("2024-03-15T00:00:00Z");
+        assert_eq!(bucket.range_search("when", t1..t2)?, pick(&[1, 2]));
+        assert_eq!(
+            bucket.range_search("name", LabelValue::from("b")..LabelValue::from("d"))?,
+            pick(&[1, 2])
+        );
+        assert_eq!(
+            bucket.query(&Query::parse(
+                "document_size>5 AND when<=2024-04-01T00:00:00Z AND NOT name=date"
+            )?)?,
+            pick(&[2])
+        );
+
+        // Relabelled and deleted documents leave the range index
+        bucket.remove_document_labels(ids[0], mclabels!("document_size" => "1.5"))?;
+        bucket.delete::<Testobj>(ids[3])?;
+        assert_eq!(bucket.range_search("document_size", mb..)?, pick(&[2]));
+        assert_eq!(
+            bucket.range_search("document_size", LabelValue::Float(1.0)..)?,
+            pick(&[1, 2])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_and_query_pages() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("paged_objects")?;
+        let batch: Vec<(Testobj, Vec<Label>)> = (0..25)
+            .map(|i| {
+                let parity = if i % 2 == 0 { "even" } else { "odd" };
+                (Testobj::new(), mclabels!("parity" => parity))
+            })
+            .collect();
+        let ids = bucket.insert_many(batch)?;
+        bucket.delete::<Testobj>(ids[4])?;
+
+        let mut scanned = vec![];
+        let mut cursor: Option<Cursor> = None;
+        let mut pages = 0;
+        loop {
+            let page = bucket.scan(cursor.as_ref(), 10)?;
+            assert!(page.ids.len() <= 10);
+            scanned.extend(page.ids);
+            pages += 1;
+            match page.next {
+                // Cursors survive a round trip through their token form
+                Some(next) => cursor = Some(next.to_string().parse()?),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        scanned.sort();
+        let mut expected: Vec<Uuid> = ids.iter().filter(|id| **id != ids[4]).copied().collect();
+        expected.sort();
+        assert_eq!(scanned, expected);
+
+        let evens = Query::parse("parity=even")?;
+        let first = bucket.query_page(&evens, None, 5)?;
+        assert_eq!(first.ids, vec![ids[0], ids[2], ids[6], ids[8], ids[10]]);
+        let cursor = first.next.expect("more evens");
+        let second = bucket.query_page(&evens, Some(&cursor), 100)?;
+        assert_eq!(second.ids.len(), 7);
+        assert_eq!(second.next, None);
+        // A page that ends on the last match has nothing after it
+        assert_eq!(bucket.query_page(&evens, Some(&cursor), 7)?, second);
+
+        assert!(matches!(
+            bucket.scan(Some(&cursor), 10),
+            Err(MangoChainsawError::InvalidCursor(_))
+        ));
+        assert!(matches!(
+            "zz".parse::<Cursor>(),
+            Err(MangoChainsawError::InvalidCursor(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_creation_order() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("ordered_objects")?;
+        let pause = || std::thread::sleep(Duration::from_millis(5));
+
+        let first = bucket.insert(Testobj::new(), vec![])?;
+        pause();
+        let t0 = SystemTime::now();
+        let middle = bucket.insert_many(vec![(Testobj::new(), vec![]); 3])?;
+        let t1 = SystemTime::now();
+        pause();
+        let last = bucket.insert(Testobj::new(), vec![])?;
+
+        assert_eq!(bucket.scan_created_between(t0, t1)?, middle);
+        assert_eq!(bucket.scan_created_between(t1, t0)?, vec![]);
+        assert_eq!(bucket.newest(2)?, vec![last, middle[2]]);
+
+        let mut everything = vec![first];
+        everything.extend(&middle);
+        everything.push(last);
+        assert_eq!(bucket.scan(None, 10)?.ids, everything);
+        assert_eq!(
+            bucket.scan_created_between(UNIX_EPOCH, t1)?,
+            everything[..4]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_id_strategies() -> Result<(), MangoChainsawError> {
+        init_tracing();
+        for strategy in [IdStrategy::V7, IdStrategy::Ulid] {
+            let db = MangoChainsaw::new(MangoChainsawConfig {
+                id_strategy: strategy,
+                ..Default::default()
+            })?;
+            let bucket = db.get_or_create_bucket("generated_objects")?;
+            let t0 = SystemTime::now();
+            let ids = bucket.insert_many(vec![(Testobj::new(), vec![]); 3])?;
+            std::thread::sleep(Duration::from_millis(5));
+            let t1 = SystemTime::now();
+            if strategy == IdStrategy::V7 {
+                assert!(ids.iter().all(|id| id.get_version_num() == 7));
+            }
+            assert_eq!(bucket.scan(None, 10)?.ids, ids);
+            assert_eq!(bucket.scan_created_between(t0, t1)?, ids);
+            assert_eq!(bucket.id_strategy()?, strategy);
+
+            // Buckets keep generating and scanning ids their own way when the config changes
+            let reopened = MangoChainsaw::with_storage(
+                MangoChainsawConfig {
+                    reaper_interval: 0,
+                    ..Default::default()
+                },
+                db.storage.clone(),
+            )?;
+            let bucket = reopened.get_bucket("generated_objects")?;
+            assert_eq!(bucket.id_strategy()?, strategy);
+            let later = bucket.insert(Testobj::new(), vec![])?;
+            std::thread::sleep(Duration::from_millis(5));
+            let t2 = SystemTime::now();
+            assert_eq!(bucket.scan_created_between(t1, t2)?, vec![later]);
+            assert_eq!(bucket.newest(1)?, vec![later]);
+            assert!(matches!(
+                bucket.set_settings(BucketSettings {
+                    id_strategy: Some(IdStrategy::V6),
+                    ..bucket.settings()?
+                }),
+                Err(MangoChainsawError::IdStrategyFixed(_))
+            ));
+            let v6 = reopened.get_or_create_bucket("v6_objects")?;
+            assert_eq!(v6.id_strategy()?, IdStrategy::V6);
+        }
+
+        let db = MangoChainsaw::new(MangoChainsawConfig {
+            id_strategy: IdStrategy::Provided,
+            ..Default::default()
+        })?;
+        let bucket = db.get_or_create_bucket("mirrored_objects")?;
+        let label = mclabel!("source" => "upstream");
+        assert!(matches!(
+            bucket.insert(Testobj::new(), vec![label.clone()]),
+            Err(MangoChainsawError::IdRequired)
+        ));
+
+        let id = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        let object = Testobj::new();
+        bucket.insert_with_id(id, &object, vec![label.clone()])?;
+        assert_eq!(bucket.get::<Testobj>(id)?, Some(object));
+        let next_ordinal = || {
+            db.get_tree("mirrored_objects::settings")?
+                .get(b"next_ordinal")
+        };
+        let ordinals = next_ordinal()?;
+        match bucket.insert_with_id(id, Testobj::new(), vec![label.clone(), label.clone()]) {
+            Err(MangoChainsawError::DuplicateId(dup)) => assert_eq!(dup, id),
+            other => panic!("expected a duplicate id error, got {other:?}"),
+        }
+        // A rejected insert doesn't use up ordinals
+        assert_eq!(next_ordinal()?, ordinals);
+        assert_eq!(bucket.search_inclusive(vec![label])?, vec![id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyed_documents() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("keyed_objects")?;
+        let label = mclabel!("object_type" => "keyed");
+        let (a, b) = (Testobj::new(), Testobj::new());
+
+        let id = bucket.insert_with_key("reports/2024/q1", &a, vec![label.clone()])?;
+        bucket.put("reports/2024/q2", &a, vec![])?;
+        bucket.put(b"reports/2025/q1", &a, vec![])?;
+        bucket.put("summary", &a, vec![])?;
+        assert!(matches!(
+            bucket.insert_with_key("reports/2024/q1", &b, vec![]),
+            Err(MangoChainsawError::DuplicateKey(_))
+        ));
+
+        assert_eq!(bucket.key_id("reports/2024/q1")?, Some(id));
+        assert_eq!(bucket.id_key(id)?, Some(b"reports/2024/q1".to_vec()));
+        assert_eq!(bucket.search_inclusive(vec![label.clone()])?, vec![id]);
+
+        // Putting over an existing key replaces the document and its labels in place
+        assert_eq!(bucket.put("reports/2024/q1", &b, vec![])?, id);
+        assert_eq!(bucket.get_by_key::<_, Testobj>("reports/2024/q1")?, Some(b));
+        assert_eq!(bucket.search_inclusive(vec![label])?, vec![]);
+
+        let page = bucket.scan_keys("reports/", None, 2)?;
+        assert_eq!(
+            page.keys,
+            vec![b"reports/2024/q1".to_vec(), b"reports/2024/q2".to_vec()]
+        );
+        let page = bucket.scan_keys("reports/", page.next.as_ref(), 2)?;
+        assert_eq!(page.keys, vec![b"reports/2025/q1".to_vec()]);
+        assert_eq!(page.next, None);
+
+        assert_eq!(
+            bucket.delete_by_key::<_, Testobj>("summary")?,
+            Some(a.clone())
+        );
+        assert_eq!(bucket.key_id("summary")?, None);
+        bucket.insert_with_key("summary", &a, vec![])?;
+        assert_eq!(bucket.scan_keys("", None, 10)?.keys.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sled_storage() -> Result<(), MangoChainsawError> {
+        init_tracing();
+        let config = MangoChainsawConfig::default();
+        let storage = Arc::new(SledBackend::open(&config)?);
+        let db = MangoChainsaw::with_storage(config, storage)?;
+        let bucket = db.get_or_create_bucket("sled_objects")?;
+        let label = mclabel!("object_type" => "sled");
+
+        let ids = bucket.insert_many(vec![(Testobj::new(), vec![label.clone()]); 3])?;
+        let keyed = bucket.insert_with_key("k", Testobj::new(), vec![label.clone()])?;
+        assert_eq!(bucket.search_inclusive(vec![label.clone()])?.len(), 4);
+        bucket.delete::<Testobj>(ids[1])?;
+        assert_eq!(
+            bucket.query(&Query::from(&label))?,
+            vec![ids[0], ids[2], keyed]
+        );
+        assert_eq!(db.list_buckets()?, vec!["sled_objects".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_codecs() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("codec_objects")?;
+        let flex = bucket.insert(Testobj::new(), vec![])?;
+
+        for codec in [CodecKind::Json, CodecKind::Cbor, CodecKind::MessagePack] {
+            bucket.set_settings(BucketSettings {
+                codec,
+                keep_history: true,
+                ..Default::default()
+            })?;
+            let object = Testobj::new();
+            let id = bucket.insert(&object, vec![])?;
+            let raw = db
+                .get_tree("codec_objects::doc")?
+                .get(id.as_bytes())?
+                .unwrap();
+            assert_eq!(&raw[8..], &encode_body(codec, &object)?[..]);
+            assert_eq!(bucket.get::<Testobj>(id)?, Some(object));
+        }
+
+        // Documents are read with the codec that wrote them, and rewritten with the current one
+        let object = bucket.get::<Testobj>(flex)?.unwrap();
+        bucket.update(flex, |mut o: Testobj| {
+            o.y = !o.y;
+            o
+        })?;
+        assert_eq!(
+            bucket.get_version::<Testobj>(flex, 1)?,
+            Some(object.clone())
+        );
+        let raw = db
+            .get_tree("codec_objects::doc")?
+            .get(flex.as_bytes())?
+            .unwrap();
+        assert_eq!(raw[8], CodecKind::MessagePack.tag());
+
+        bucket.set_settings(BucketSettings {
+            codec: CodecKind::Raw,
+            ..Default::default()
+        })?;
+        assert!(bucket.insert(Testobj::new(), vec![]).is_err());
+        let id = bucket.insert("plain text", vec![])?;
+        assert_eq!(bucket.get::<String>(id)?.as_deref(), Some("plain text"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_documents() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("raw_objects")?;
+        let label = mclabel!("object_type" => "raw");
+
+        let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let id = bucket.insert_raw(&big, vec![label.clone()])?;
+        assert_eq!(bucket.get_raw(id)?.as_deref(), Some(&big[..]));
+        assert_eq!(bucket.get::<Vec<u8>>(id)?, Some(big.clone()));
+        let stream = bucket.stream_raw(id)?.unwrap();
+        assert_eq!(
+            (stream.len(), stream.codec()),
+            (big.len() as u64, CodecKind::Raw)
+        );
+        let chunks = stream.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.concat(), big);
+
+        assert_eq!(bucket.replace_raw(id, b"small", None)?, Some(2));
+        assert!(matches!(
+            bucket.compare_and_swap_raw(id, 1, b"stale", None),
+            Err(MangoChainsawError::Conflict { found: Some(2), .. })
+        ));
+        assert_eq!(
+            bucket.compare_and_swap_raw(id, 2, b"fresh", Some(vec![]))?,
+            3
+        );
+        assert_eq!(
+            bucket.get_raw_with_revision(id)?,
+            Some((IVec::from("fresh"), 3))
+        );
+        assert!(bucket.query(&Query::from(&label))?.is_empty());
+
+        let keyed = bucket.put_raw("k", b"one", vec![label.clone()])?;
+        assert_eq!(bucket.put_raw("k", b"two", vec![label])?, keyed);
+        assert_eq!(bucket.get_by_key::<_, String>("k")?.as_deref(), Some("two"));
+        assert_eq!(bucket.delete_raw(keyed)?, Some(IVec::from("two")));
+        assert_eq!(bucket.get_raw(keyed)?, None);
+
+        // Serialized documents come back as their encoded bytes
+        bucket.set_settings(BucketSettings {
+            codec: CodecKind::Json,
+            ..Default::default()
+        })?;
+        let id = bucket.insert(vec!["a", "b"], vec![])?;
+        assert_eq!(bucket.get_raw(id)?, Some(IVec::from(r#"["a","b"]"#)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_documents() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("large_objects")?;
+        let label = mclabel!("object_type" => "large");
+        let num_chunks =
+            || -> Result<usize, MangoChainsawError> { Ok(bucket.stat()?["num_chunks"]) };
+        // Freed chunks go once a second sweep finds them still unreferenced
+        let sweep = || -> Result<usize, MangoChainsawError> {
+            Ok(bucket.collect_chunks(Duration::ZERO)? + bucket.collect_chunks(Duration::ZERO)?)
+        };
+
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut writer = bucket.write_large(vec![label.clone()]);
+        for piece in big.chunks(10_000) {
+            writer.write_all(piece)?;
+        }
+        let (id, rev) = writer.finish()?.unwrap();
+        assert_eq!(rev, 1);
+        assert_eq!(num_chunks()?, 3);
+        assert_eq!(bucket.query(&Query::from(&label))?, vec![id]);
+
+        let stream = bucket.stream_raw(id)?.unwrap();
+        assert!(stream.is_chunked());
+        assert_eq!(stream.len(), big.len() as u64);
+        let slices = stream.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(slices.len(), 3);
+        assert_eq!(slices.concat(), big);
+        assert_eq!(bucket.get::<Vec<u8>>(id)?, Some(big.clone()));
+
+        // Ranges and seeks cross chunk boundaries
+        let start = CHUNK_SIZE as u64 - 10;
+        let mut part = vec![];
+        let mut stream = bucket.stream_raw(id)?.unwrap().range(start..start + 20);
+        stream.read_to_end(&mut part)?;
+        assert_eq!(part, big[start as usize..start as usize + 20]);
+        drop(stream);
+        let mut stream = bucket.stream_raw(id)?.unwrap();
+        stream.seek(SeekFrom::End(-5))?;
+        part.clear();
+        stream.read_to_end(&mut part)?;
+        assert_eq!(part, big[big.len() - 5..]);
+        drop(stream);
+
+        // Small documents stay inline, and replacing a large one frees its chunks
+        let mut writer = bucket.replace_large(id, Some(1), None);
+        writer.write_all(b"small now")?;
+        assert_eq!(writer.finish()?, Some((id, 2)));
+        assert_eq!(sweep()?, 1);
+        assert_eq!(num_chunks()?, 0);
+        assert_eq!(bucket.get_raw(id)?, Some(IVec::from("small now")));
+        assert!(!bucket.stream_raw(id)?.unwrap().is_chunked());
+        assert!(matches!(
+            bucket.replace_large(id, Some(1), None).finish(),
+            Err(MangoChainsawError::Conflict { found: Some(2), .. })
+        ));
+
+        // Archived versions keep their chunks until they are pruned
+        bucket.set_settings(BucketSettings {
+            keep_history: true,
+            max_versions: Some(1),
+            ..Default::default()
+        })?;
+        let mut writer = bucket.put_large("big", vec![]);
+        writer.write_all(&big)?;
+        let (keyed, _rev) = writer.finish()?.unwrap();
+        bucket.replace_raw(keyed, b"v2", None)?;
+        assert_eq!(sweep()?, 0);
+        assert_eq!(num_chunks()?, 3);
+        assert_eq!(bucket.get_version::<Vec<u8>>(keyed, 1)?, Some(big.clone()));
+        bucket.restore(keyed, 1)?;
+        bucket.replace_raw(keyed, b"v4", None)?;
+        assert_eq!(sweep()?, 0);
+        assert_eq!(num_chunks()?, 3);
+        bucket.replace_raw(keyed, b"v5", None)?;
+        assert_eq!(sweep()?, 1);
+        assert_eq!(num_chunks()?, 0);
+
+        // Deleting a large document frees its chunks, and so does abandoning a writer
+        bucket.set_settings(BucketSettings::default())?;
+        let mut writer = bucket.write_large(vec![]);
+        writer.write_all(&big)?;
+        let (id, _rev) = writer.finish()?.unwrap();
+        assert!(bucket.remove(id)?);
+        assert_eq!(sweep()?, 1);
+        assert_eq!(num_chunks()?, 0);
+        let mut writer = bucket.write_large(vec![]);
+        writer.write_all(&big)?;
+        assert_eq!(sweep()?, 0);
+        assert_eq!(num_chunks()?, 2);
+        drop(writer);
+        assert_eq!(num_chunks()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_chunks() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("swept_objects")?;
+        let chunks = db.get_tree("swept_objects::chunks")?;
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut writer = bucket.write_large(vec![]);
+        writer.write_all(&big)?;
+        let (id, _rev) = writer.finish()?.unwrap();
+
+        // A stream opened before the document is replaced can still be read to the end
+        let mut stream = bucket.stream_raw(id)?.unwrap();
+        let mut start = vec![0; 10];
+        stream.read_exact(&mut start)?;
+        bucket.replace_raw(id, b"small now", None)?;
+        assert_eq!(bucket.collect_chunks(Duration::ZERO)?, 0);
+        assert_eq!(bucket.collect_chunks(Duration::ZERO)?, 0);
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest)?;
+        assert_eq!([start, rest].concat(), big);
+        drop(stream);
+
+        // Unreferenced chunks are kept for the grace period after a sweep first finds them
+        assert_eq!(bucket.collect_chunks(Duration::from_secs(3600))?, 0);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(bucket.collect_chunks(Duration::ZERO)?, 1);
+        assert!(chunks.is_empty());
+
+        // So are chunks a writer left behind without finishing, such as before a crash
+        let blob = db.storage.generate_id()?;
+        chunks.insert(
+            [blob.to_be_bytes(), 0u64.to_be_bytes()].concat(),
+            vec![0; 16],
+        )?;
+        assert_eq!(db.collect_chunks()?, 0);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(bucket.collect_chunks(Duration::ZERO)?, 1);
+        assert!(chunks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_metadata() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("metadata_objects")?;
+        bucket.set_settings(BucketSettings {
+            keep_history: true,
+            codec: CodecKind::Json,
+            ..Default::default()
+        })?;
+
+        let id = bucket.insert(Testobj::new(), vec![])?;
+        let meta = bucket.metadata(id)?.unwrap();
+        let json = bucket.get_raw(id)?.unwrap();
+        assert_eq!(meta.revision, 1);
+        assert_eq!(meta.size, json.len() as u64);
+        assert_eq!(meta.created, meta.modified);
+        assert_eq!(meta.media_type(), "application/json");
+        assert_eq!(meta.checksum.len(), 64);
+
+        // Writes keep the creation time and content type, and update the rest
+        assert!(bucket.set_content_type(id, Some("text/plain"))?);
+        bucket.replace_raw(id, b"hello", None)?;
+        let replaced = bucket.metadata(id)?.unwrap();
+        assert_eq!(replaced.created, meta.created);
+        assert!(replaced.modified >= meta.modified);
+        assert_eq!(replaced.revision, 2);
+        assert_eq!(replaced.size, 5);
+        assert_eq!(
+            replaced.checksum,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(replaced.media_type(), "text/plain");
+        bucket.restore(id, 1)?;
+        let restored = bucket.metadata(id)?.unwrap();
+        assert_eq!((restored.revision, restored.size), (3, meta.size));
+        assert_eq!(restored.checksum, meta.checksum);
+
+        // Large documents get the checksum of all their bytes, and the content type they
+        // were written with
+        let big: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        let mut writer = bucket.write_large(vec![]).with_content_type("image/png");
+        writer.write_all(&big)?;
+        let (large, _rev) = writer.finish()?.unwrap();
+        let meta = bucket.metadata(large)?.unwrap();
+        let mut small = bucket.write_large(vec![]);
+        small.write_all(&big[..10])?;
+        let (small, _rev) = small.finish()?.unwrap();
+        assert_eq!(meta.size, big.len() as u64);
+        assert_eq!(meta.media_type(), "image/png");
+        assert_eq!(meta.codec, CodecKind::Raw);
+        assert_eq!(bucket.metadata(small)?.unwrap().size, 10);
+        assert_ne!(bucket.metadata(small)?.unwrap().checksum, meta.checksum);
+
+        // Writing a document again without a content type clears the one it had
+        let mut rewrite = bucket.replace_large(large, None, None);
+        rewrite.write_all(&big[..10])?;
+        rewrite.finish()?.unwrap();
+        let rewritten = bucket.metadata(large)?.unwrap();
+        assert_eq!(rewritten.content_type, None);
+        assert_eq!(rewritten.media_type(), "application/octet-stream");
+
+        assert!(bucket.remove(large)?);
+        assert_eq!(bucket.metadata(large)?, None);
+        assert!(!bucket.set_content_type(large, None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("dedup_objects")?;
+        bucket.set_settings(BucketSettings {
+            dedup: true,
+            ..Default::default()
+        })?;
+        let stat = |key: &str| -> Result<usize, MangoChainsawError> { Ok(bucket.stat()?[key]) };
+
+        let object = Testobj::new();
+        let a = bucket.insert(object.clone(), vec![])?;
+        let b = bucket.insert(object.clone(), vec![])?;
+        let c = bucket.insert_raw(b"something else", vec![])?;
+        assert_eq!(stat("num_blobs")?, 2);
+        assert!(stat("physical_bytes")? < stat("logical_bytes")?);
+        assert_eq!(bucket.get::<Testobj>(b)?, Some(object.clone()));
+
+        // The shared body stays until the last document pointing to it goes
+        assert_eq!(bucket.delete::<Testobj>(a)?, Some(object.clone()));
+        assert_eq!(stat("num_blobs")?, 2);
+        assert_eq!(stat("physical_bytes")?, stat("logical_bytes")?);
+        assert!(bucket.remove(b)?);
+        assert_eq!(stat("num_blobs")?, 1);
+        assert_eq!(bucket.replace_raw(c, b"v2", None)?, Some(2));
+        assert_eq!(stat("num_blobs")?, 1);
+        assert_eq!(bucket.get_raw(c)?, Some(IVec::from("v2")));
+
+        // Archived versions hold their own reference
+        bucket.set_settings(BucketSettings {
+            dedup: true,
+            keep_history: true,
+            max_versions: Some(1),
+            ..Default::default()
+        })?;
+        assert_eq!(bucket.replace_raw(c, b"v2", None)?, Some(3));
+        assert_eq!(bucket.replace_raw(c, b"v3", None)?, Some(4));
+        assert_eq!(stat("num_blobs")?, 2);
+        assert_eq!(bucket.restore(c, 3)?, Some(5));
+        assert_eq!(bucket.get_raw(c)?, Some(IVec::from("v2")));
+        assert_eq!(bucket.replace_raw(c, b"v2", None)?, Some(6));
+        assert_eq!(stat("num_blobs")?, 1);
+
+        // Identical large documents share one set of chunks
+        let big: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        bucket.set_settings(BucketSettings {
+            dedup: true,
+            ..Default::default()
+        })?;
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let mut writer = bucket.write_large(vec![]);
+            writer.write_all(&big)?;
+            ids.push(writer.finish()?.unwrap().0);
+        }
+        assert_eq!(stat("num_chunks")?, 2);
+        assert_eq!(bucket.get_raw(ids[1])?, Some(IVec::from(big.clone())));
+        bucket.remove(ids[0])?;
+        assert_eq!(stat("num_chunks")?, 2);
+        bucket.remove(ids[1])?;
+        bucket.collect_chunks(Duration::ZERO)?;
+        assert_eq!(bucket.collect_chunks(Duration::ZERO)?, 1);
+        assert_eq!(stat("num_chunks")?, 0);
+
+        // Deleting a document leaves its archived version, and the body that holds
+        assert!(bucket.remove(c)?);
+        assert_eq!(stat("num_blobs")?, 1);
+        assert_eq!(bucket.get_version::<Vec<u8>>(c, 5)?, Some(b"v2".to_vec()));
+
+        // A damaged reference count fails the write instead of panicking
+        bucket.insert_raw(b"shared", vec![])?;
+        let refs = db.get_tree("dedup_objects::blob_refs")?;
+        for key in refs.iter().keys() {
+            refs.insert(key?, &[0u8; 3])?;
+        }
+        assert!(matches!(
+            bucket.insert_raw(b"shared", vec![]),
+            Err(MangoChainsawError::CorruptCounter(3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_registry() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        assert!(matches!(
+            db.get_bucket("registered_objects"),
+            Err(MangoChainsawError::BucketNotFound(_))
+        ));
+        assert!(!db.bucket_exists("registered_objects")?);
+
+        let settings = BucketSettings {
+            codec: CodecKind::Json,
+            default_ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let before = SystemTime::now();
+        let bucket = db.create_bucket("registered_objects", settings.clone())?;
+        // Buckets take the configured id strategy unless they are given one
+        let settings = BucketSettings {
+            id_strategy: Some(IdStrategy::V6),
+            ..settings
+        };
+        assert_eq!(bucket.settings()?, settings);
+        let info = db.bucket_info("registered_objects")?.unwrap();
+        assert!(info.created >= before);
+        assert_eq!(info.settings, settings);
+        assert!(matches!(
+            db.create_bucket("registered_objects", BucketSettings::default()),
+            Err(MangoChainsawError::BucketExists(_))
+        ));
+        assert_eq!(
+            db.get_or_create_bucket("registered_objects")?.settings()?,
+            settings
+        );
+
+        for name in ["", "__mc5", "a::b", "with space", &"x".repeat(129)] {
+            assert!(matches!(
+                db.create_bucket(name, BucketSettings::default()),
+                Err(MangoChainsawError::InvalidBucketName { .. })
+            ));
+        }
+        db.create_bucket("other-objects.v2", BucketSettings::default())?;
+        assert_eq!(
+            db.list_buckets()?,
+            vec![
+                "other-objects.v2".to_string(),
+                "registered_objects".to_string()
+            ]
+        );
+
+        db.drop_bucket("registered_objects")?;
+        assert!(!db.bucket_exists("registered_objects")?);
+        assert_eq!(db.list_buckets()?, vec!["other-objects.v2".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_copy_bucket() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let settings = BucketSettings {
+            keep_history: true,
+            ..Default::default()
+        };
+        let bucket = db.create_bucket("source_objects", settings)?;
+        let settings = bucket.settings()?;
+        let label = mclabel!("object_type" => "copied");
+        let first = Testobj::new();
+        let mut second = first.clone();
+        second.z = "second".to_string();
+        let a = bucket.insert(&first, vec![label.clone()])?;
+        let b = bucket.insert_with_key("b", &first, vec![label.clone()])?;
+        let c = bucket.insert_with_ttl(&first, vec![label.clone()], Duration::from_secs(60))?;
+        bucket.replace(b, second.clone())?;
+        std::thread::sleep(Duration::from_millis(5));
+
+        // A copy with new ids carries everything over to them, and is created when they are
+        let copy = db.copy_bucket("source_objects", "copied_objects", false)?;
+        assert_eq!(copy.settings()?, settings);
+        let ids = copy.query(&Query::from(&label))?;
+        assert_eq!(ids.len(), 3);
+        assert!(ids.iter().all(|id| ![a, b, c].contains(id)));
+        assert_eq!(copy.search_inclusive(vec![label.clone()])?, ids);
+        let copied_b = copy.key_id("b")?.unwrap();
+        assert_eq!(copied_b, ids[1]);
+        assert_eq!(copy.get::<Testobj>(copied_b)?, Some(second.clone()));
+        assert_eq!(
+            copy.get_version::<Testobj>(copied_b, 1)?,
+            Some(first.clone())
+        );
+        assert!(copy.ttl(ids[2])?.is_some());
+        for expiry in db.get_tree("copied_objects::expiry")?.iter() {
+            let (key, val) = expiry?;
+            assert_eq!(&key[8..], &val[..]);
+            assert_eq!(Uuid::from_slice(&val).unwrap(), ids[2]);
+        }
+        assert_eq!(copy.metadata(copied_b)?.unwrap().revision, 2);
+        assert!(copy.metadata(copied_b)?.unwrap().created > bucket.metadata(b)?.unwrap().created);
+        assert!(copy.get::<Testobj>(b)?.is_none());
+
+        // The source is untouched, and the copy is independent of it
+        copy.delete::<Testobj>(ids[0])?;
+        assert_eq!(bucket.query(&Query::from(&label))?, vec![a, b, c]);
+
+        let kept = db.copy_bucket("source_objects", "kept_objects", true)?;
+        assert_eq!(kept.query(&Query::from(&label))?, vec![a, b, c]);
+        assert_eq!(kept.key_id("b")?, Some(b));
+        assert!(matches!(
+            db.copy_bucket("source_objects", "kept_objects", true),
+            Err(MangoChainsawError::BucketExists(_))
+        ));
+
+        let created = db.bucket_info("source_objects")?.unwrap().created;
+        let renamed = db.rename_bucket("source_objects", "renamed_objects")?;
+        assert!(!db.bucket_exists("source_objects")?);
+        assert_eq!(db.bucket_info("renamed_objects")?.unwrap().created, created);
+        assert_eq!(renamed.query(&Query::from(&label))?, vec![a, b, c]);
+        assert_eq!(renamed.get::<Testobj>(b)?, Some(second));
+        assert_eq!(
+            db.list_buckets()?,
+            vec!["copied_objects", "kept_objects", "renamed_objects"]
+        );
+        assert!(matches!(
+            db.rename_bucket("source_objects", "other_objects"),
+            Err(MangoChainsawError::BucketNotFound(_))
+        ));
+        assert!(matches!(
+            bucket.insert(&first, vec![]),
+            Err(MangoChainsawError::BucketNotFound(_))
+        ));
+
+        // An interrupted rename is undone if it hadn't copied everything, finished if it had
+        for copied in [false, true] {
+            let progress = CopyProgress {
+                from: "renamed_objects".to_string(),
+                rename: true,
+                copied,
+            };
+            let info = db.bucket_info("renamed_objects")?.unwrap();
+            db.register_copy("half_renamed", info, Some(&progress))?;
+            db.get_bucket("renamed_objects")?
+                .copy_trees("half_renamed", true)?;
+            let config = MangoChainsawConfig {
+                reaper_interval: 0,
+                ..Default::default()
+            };
+            let reopened = MangoChainsaw::with_storage(config, db.storage.clone())?;
+            let (kept, dropped) = match copied {
+                false => ("renamed_objects", "half_renamed"),
+                true => ("half_renamed", "renamed_objects"),
+            };
+            assert!(!reopened.bucket_exists(dropped)?);
+            let kept = reopened.get_bucket(kept)?;
+            assert_eq!(kept.query(&Query::from(&label))?, vec![a, b, c]);
+            assert!(reopened.copies.iter().next().is_none());
+        }
+
+        // Caller-provided ids can't be made up for a copy
+        let provided = db.create_bucket(
+            "provided_objects",
+            BucketSettings {
+                id_strategy: Some(IdStrategy::Provided),
+                ..Default::default()
+            },
+        )?;
+        provided.insert_with_id(Uuid::from_u128(1), &first, vec![])?;
+        assert!(matches!(
+            db.copy_bucket("provided_objects", "fresh_objects", false),
+            Err(MangoChainsawError::KeepIdsRequired(_))
+        ));
+        assert!(!db.bucket_exists("fresh_objects")?);
+        let kept = db.copy_bucket("provided_objects", "fresh_objects", true)?;
+        assert!(kept.get::<Testobj>(Uuid::from_u128(1))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_quota() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let quota = Quota {
+            max_documents: Some(3),
+            max_bytes: Some(10),
+            max_document_size: Some(6),
+            max_labels_per_document: Some(2),
+            max_distinct_labels: Some(3),
+        };
+        let bucket = db.create_bucket(
+            "quota_objects",
+            BucketSettings {
+                quota,
+                ..Default::default()
+            },
+        )?;
+        let over = |result: Result<Uuid, MangoChainsawError>, expected: QuotaLimit| matches!(result, Err(MangoChainsawError::QuotaExceeded { limit, .. }) if limit == expected);
+
+        let a = bucket.insert_raw(b"aaaa", mclabels!("k" => "1"))?;
+        assert!(over(
+            bucket.insert_raw(b"too large", vec![]),
+            QuotaLimit::DocumentSize
+        ));
+        assert!(over(
+            bucket.insert_raw(b"b", mclabels!("k" => "1", "l" => "1", "m" => "1")),
+            QuotaLimit::LabelsPerDocument
+        ));
+        let b = bucket.insert_raw(b"bbbb", mclabels!("k" => "2"))?;
+        assert!(over(bucket.insert_raw(b"ccc", vec![]), QuotaLimit::Bytes));
+        let c = bucket.insert_raw(b"cc", mclabels!("k" => "3"))?;
+        assert!(over(bucket.insert_raw(b"", vec![]), QuotaLimit::Documents));
+        assert_eq!(
+            bucket.usage()?,
+            Usage {
+                documents: 3,
+                bytes: 10,
+                labels: 3
+            }
+        );
+
+        // Rejected writes leave everything as it was
+        assert!(matches!(
+            bucket.replace_raw(c, b"cccc", None),
+            Err(MangoChainsawError::QuotaExceeded {
+                limit: QuotaLimit::Bytes,
+                ..
+            })
+        ));
+        assert!(matches!(
+            bucket.add_document_labels(a, mclabels!("k" => "4")),
+            Err(MangoChainsawError::QuotaExceeded {
+                limit: QuotaLimit::DistinctLabels,
+                ..
+            })
+        ));
+        assert!(matches!(
+            bucket.add_document_labels(a, mclabels!("k" => "2", "k" => "3")),
+            Err(MangoChainsawError::QuotaExceeded {
+                limit: QuotaLimit::LabelsPerDocument,
+                ..
+            })
+        ));
+        assert_eq!(bucket.get_document_labels(a)?, Some(mclabels!("k" => "1")));
+        assert_eq!(bucket.usage()?.labels, 3);
+
+        // Labels for documents that don't exist aren't indexed or counted
+        let usage = bucket.usage()?;
+        let missing = Uuid::from_u128(404);
+        assert!(!bucket.add_document_labels(missing, mclabels!("k" => "5", "k" => "6"))?);
+        assert!(!bucket.remove_document_labels(missing, mclabels!("k" => "1"))?);
+        assert_eq!(bucket.usage()?, usage);
+        assert!(bucket.get_label(mclabel!("k" => "5"))?.is_none());
+        assert_eq!(bucket.get_label(mclabel!("k" => "1"))?, Some(vec![a]));
+
+        // Deleting frees up room
+        bucket.delete_raw(b)?;
+        assert_eq!(
+            bucket.usage()?,
+            Usage {
+                documents: 2,
+                bytes: 6,
+                labels: 2
+            }
+        );
+        bucket.add_document_labels(a, mclabels!("k" => "4"))?;
+        bucket.replace_raw(c, b"cccc", None)?;
+        assert_eq!(bucket.usage()?.bytes, 8);
+
+        // Large documents are stopped as soon as they go over the size limit
+        let mut writer = bucket.write_large(vec![]);
+        assert!(writer.write_all(&vec![0; CHUNK_SIZE * 2]).is_err());
+        assert_eq!(bucket.stat()?["num_chunks"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_usage_counted_with_quota() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("uncounted_objects")?;
+        let counts = db.get_tree("uncounted_objects::usage")?;
+        bucket.insert_raw(b"aaaa", mclabels!("k" => "1"))?;
+        bucket.insert_raw(b"bb", mclabels!("k" => "1", "l" => "1"))?;
+        let usage = Usage {
+            documents: 2,
+            bytes: 6,
+            labels: 2,
+        };
+
+        // Without a quota nothing is kept, usage is counted when asked for
+        assert!(counts.is_empty());
+        assert_eq!(bucket.usage()?, usage);
+
+        // Per-document limits don't need counting either
+        bucket.set_settings(BucketSettings {
+            quota: Quota {
+                max_document_size: Some(8),
+                ..Default::default()
+            },
+            ..bucket.settings()?
+        })?;
+        assert!(counts.is_empty());
+
+        // A bucket-wide limit starts from what the bucket already holds
+        bucket.set_settings(BucketSettings {
+            quota: Quota {
+                max_documents: Some(3),
+                ..Default::default()
+            },
+            ..bucket.settings()?
+        })?;
+        assert_eq!(bucket.usage()?, usage);
+        bucket.insert_raw(b"c", vec![])?;
+        assert!(matches!(
+            bucket.insert_raw(b"d", vec![]),
+            Err(MangoChainsawError::QuotaExceeded {
+                limit: QuotaLimit::Documents,
+                ..
+            })
+        ));
+
+        // Dropping it drops the counts
+        bucket.set_settings(BucketSettings {
+            quota: Quota::default(),
+            ..bucket.settings()?
+        })?;
+        assert!(counts.is_empty());
+        bucket.insert_raw(b"d", vec![])?;
+        assert_eq!(bucket.usage()?.documents, 4);
+
+        // A damaged counter is an error rather than a panic
+        bucket.set_settings(BucketSettings {
+            quota: Quota {
+                max_bytes: Some(100),
+                ..Default::default()
+            },
+            ..bucket.settings()?
+        })?;
+        counts.insert(b"", &[1])?;
+        assert!(matches!(
+            bucket.usage(),
+            Err(MangoChainsawError::CorruptCounter(1))
+        ));
+        assert!(matches!(
+            bucket.insert_raw(b"e", vec![]),
+            Err(MangoChainsawError::CorruptCounter(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_schema() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("schema_objects")?;
+        bucket.set_settings(BucketSettings {
+            keep_history: true,
+            ..bucket.settings()?
+        })?;
+        let unchecked = bucket.insert(Testobj::new(), mclabels!("file_type" => "rust"))?;
+        bucket.replace(unchecked, Testobj::new())?;
+
+        let invalid = LabelSchema {
+            values: BTreeMap::from([("filetype".into(), ValueRule::Pattern("(".into()))]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            bucket.set_label_schema(Some(invalid)),
+            Err(MangoChainsawError::InvalidSchema(_))
+        ));
+
+        let schema = LabelSchema {
+            required: vec!["filetype".into()],
+            allowed: Some(vec!["path".into(), "tag".into()]),
+            values: BTreeMap::from([
+                (
+                    "filetype".into(),
+                    ValueRule::OneOf(vec!["rust".into(), "yaml".into()]),
+                ),
+                ("tag".into(), ValueRule::Pattern("[a-z]+".into())),
+            ]),
+            max_value_len: Some(16),
+        };
+        bucket.set_label_schema(Some(schema.clone()))?;
+        assert_eq!(bucket.label_schema()?, Some(schema));
+
+        fn violations<T: std::fmt::Debug>(
+            result: Result<T, MangoChainsawError>,
+        ) -> Vec<LabelViolation> {
+            match result {
+                Err(MangoChainsawError::LabelSchemaViolation(violations)) => violations,
+                other => panic!("expected a schema violation, got {other:?}"),
+            }
+        }
+        assert_eq!(
+            violations(bucket.insert(
+                Testobj::new(),
+                mclabels!("file_type" => "rust", "tag" => "Nope", "path" => "/a/very/long/path")
+            )),
+            vec![
+                LabelViolation::MissingKey {
+                    key: "filetype".into()
+                },
+                LabelViolation::KeyNotAllowed {
+                    key: "file_type".into()
+                },
+                LabelViolation::PatternMismatch {
+                    key: "tag".into(),
+                    value: "Nope".into(),
+                    pattern: "[a-z]+".into()
+                },
+                LabelViolation::ValueTooLong {
+                    key: "path".into(),
+                    len: 17,
+                    max: 16
+                },
+            ]
+        );
+        assert_eq!(
+            violations(bucket.insert(Testobj::new(), mclabels!("filetype" => "toml"))),
+            vec![LabelViolation::NotOneOf {
+                key: "filetype".into(),
+                value: "toml".into()
+            }]
+        );
+
+        let id = bucket.insert(Testobj::new(), mclabels!("filetype" => "rust"))?;
+        violations(bucket.add_document_labels(id, mclabels!("tag" => "UPPER")));
+        violations(bucket.remove_document_labels(id, mclabels!("filetype" => "rust")));
+        violations(bucket.replace_with_labels(id, Testobj::new(), mclabels!("tag" => "x")));
+        bucket.add_document_labels(id, mclabels!("tag" => "lower"))?;
+        assert_eq!(
+            bucket.get_document_labels(id)?,
+            Some(mclabels!("filetype" => "rust", "tag" => "lower"))
+        );
+
+        // Documents from before the schema are left alone, but old versions are checked
+        // before they are restored
+        assert!(bucket.get::<Testobj>(unchecked)?.is_some());
+        violations(bucket.restore(unchecked, 1));
+        assert_eq!(bucket.revision(unchecked)?, Some(2));
+        bucket.set_label_schema(None)?;
+        bucket.insert(Testobj::new(), mclabels!("file_type" => "rust"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_schema() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let schema = BodySchema(json!({
+            "type": "object",
+            "required": ["x", "y", "z"],
+            "properties": {
+                "x": {"type": "integer"},
+                "z": {"type": "string", "maxLength": 10},
+            },
+        }));
+        assert!(matches!(
+            db.create_bucket(
+                "schema_raw",
+                BucketSettings {
+                    codec: CodecKind::Raw,
+                    body_schema: Some(schema.clone()),
+                    ..Default::default()
+                },
+            ),
+            Err(MangoChainsawError::InvalidSchema(_))
+        ));
+        // The binary formats can hold values JSON can't, so only JSON buckets get a schema
+        let bucket = db.get_or_create_bucket("schema_bodies")?;
+        assert!(matches!(
+            bucket.set_body_schema(Some(schema.clone())),
+            Err(MangoChainsawError::InvalidSchema(_))
+        ));
+        bucket.set_settings(BucketSettings {
+            codec: CodecKind::Json,
+            keep_history: true,
+            ..bucket.settings()?
+        })?;
+        assert!(matches!(
+            bucket.set_body_schema(Some(BodySchema(json!({"type": 12})))),
+            Err(MangoChainsawError::InvalidSchema(_))
+        ));
+        bucket.set_body_schema(Some(schema.clone()))?;
+        assert_eq!(bucket.body_schema()?, Some(schema.clone()));
+
+        fn violations<T: std::fmt::Debug>(
+            result: Result<T, MangoChainsawError>,
+        ) -> Vec<BodyViolation> {
+            match result {
+                Err(MangoChainsawError::BodySchemaViolation(violations)) => violations,
+                other => panic!("expected a schema violation, got {other:?}"),
+            }
+        }
+        let mut paths: Vec<String> = violations(bucket.insert(json!({"x": "one"}), vec![]))
+            .into_iter()
+            .map(|v| v.path)
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["", "", "/x"]);
+
+        let id = bucket.insert(Testobj::new(), vec![])?;
+        let long = Testobj {
+            z: "far too long".into(),
+            ..Testobj::new()
+        };
+        assert_eq!(
+            violations(bucket.replace(id, long.clone()))[0].schema_path,
+            "/properties/z/maxLength"
+        );
+        violations(bucket.update(id, |obj: Testobj| Testobj {
+            z: long.z.clone(),
+            ..obj
+        }));
+        bucket.replace(id, Testobj::new())?;
+
+        // Bytes written as they are get read as JSON
+        assert_eq!(violations(bucket.insert_raw("{not json", vec![])).len(), 1);
+        bucket.insert_raw(r#"{"x": 1, "y": true, "z": "fine"}"#, vec![])?;
+        let mut writer = bucket.write_large(vec![]);
+        writer.write_all(br#"{"x": 1, "y": true}"#)?;
+        violations(writer.finish());
+        assert_eq!(bucket.usage()?.documents, 2);
+
+        // Old versions are checked against the schema as it is now
+        bucket.set_body_schema(Some(BodySchema(json!({"required": ["w"]}))))?;
+        violations(bucket.restore(id, 1));
+
+        bucket.set_body_schema(None)?;
+        bucket.insert_raw("{not json", vec![])?;
+        assert_eq!(bucket.restore(id, 1)?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_numeric_range_index() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("numeric_objects")?;
+        let ids = bucket.insert_many(vec![
+            (Testobj::new(), mclabels!("size" => "1.5")),
+            (Testobj::new(), mclabels!("size" => "1048576")),
+        ])?;
+
+        // Format 8 gave floats their own tag, after every integer
+        let range = db.get_tree("numeric_objects::range")?;
+        let stale: Vec<_> = range.iter().keys().collect::<Result<_, _>>()?;
+        for key in stale {
+            range.remove(key)?;
+        }
+        let mut float_key = b"size\xff\x02".to_vec();
+        float_key.extend_from_slice(&(1.5f64.to_bits() | (1 << 63)).to_be_bytes());
+        float_key.extend_from_slice(&0u32.to_be_bytes());
+        range.insert(float_key, &[])?;
+        db.get_tree("numeric_objects::settings")?
+            .insert(b"format", &8u64.to_be_bytes())?;
+
+        let bucket = db.get_bucket("numeric_objects")?;
+        assert_eq!(
+            bucket.range_search("size", LabelValue::Int(1)..)?,
+            vec![ids[0], ids[1]]
+        );
+        assert_eq!(
+            bucket.range_search("size", ..LabelValue::Int(2))?,
+            vec![ids[0]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_legacy_server_upload() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let id = db.next_id()?;
+        let upload = b"uploaded before bodies were stored as they are".to_vec();
+
+        // The original server stored uploads as a serialized Vec<u8>
+        let idb = MangoChainsaw::ser(id.as_u64_pair())?;
+        db.get_tree("legacy_uploads::doc")?
+            .insert(&idb, MangoChainsaw::ser(&upload)?)?;
+        db.get_tree("legacy_uploads::labels")?
+            .insert(&idb, MangoChainsaw::ser(Vec::<Label>::new())?)?;
+
+        let bucket = db.get_bucket("legacy_uploads")?;
+        let (mut stream, rev) = bucket.stream_bytes_with_revision(id)?.unwrap();
+        let mut bytes = vec![];
+        stream.read_to_end(&mut bytes)?;
+        assert_eq!((bytes, rev), (upload.clone(), 1));
+        assert_ne!(bucket.get_raw(id)?.unwrap().to_vec(), upload);
+
+        // Uploads stored as they are, and documents that aren't byte strings, are left alone
+        let raw = bucket.insert_raw(&upload[..8], vec![])?;
+        let (mut stream, _rev) = bucket.stream_bytes_with_revision(raw)?.unwrap();
+        let mut bytes = vec![];
+        stream.read_to_end(&mut bytes)?;
+        assert_eq!(bytes, upload[..8]);
+        let typed = bucket.insert(Testobj::new(), vec![])?;
+        let (stream, _rev) = bucket.stream_bytes_with_revision(typed)?.unwrap();
+        assert_eq!(stream.codec(), CodecKind::Flexbuffers);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_legacy_posting_lists() -> Result<(), MangoChainsawError> {
+        let db = test_db()?;
+        let object = Testobj::new();
+        let (a, b) = (db.next_id()?, db.next_id()?);
+        let label = mclabel!("object_type" => "legacy");
+
+        // Write a bucket the way the original format did
+        let docs = db.get_tree("legacy_objects::doc")?;
+        let kev = db.get_tree("legacy_objects::kev")?;
+        let vek = db.get_tree("legacy_objects::vek")?;
+        let docs_labels = db.get_tree("legacy_objects::labels")?;
+        for id in [a, b] {
+            let idb = MangoChainsaw::ser(id.as_u64_pair())?;
+            docs.insert(&idb, MangoChainsaw::ser(&object)?)?;
+            docs_labels.insert(&idb, MangoChainsaw::ser(vec![label.clone()])?)?;
+        }
+        let ids = MangoChainsaw::ser(vec![a.as_u64_pair(), b.as_u64_pair()])?;
+        kev.insert(label.as_bytes(), ids.clone())?;
+        vek.insert(label.as_bytes_rev(), ids)?;
+        let settings = BucketSettings {
+            keep_history: true,
+            ..Default::default()
+        };
+        db.get_tree("legacy_objects::settings")?
+            .insert(b"settings", MangoChainsaw::ser(&settings)?)?;
+
+        let bucket = db.get_bucket("legacy_objects")?;
+        let settings = BucketSettings {
+            id_strategy: Some(IdStrategy::V6),
+            ..settings
+        };
+        assert_eq!(bucket.settings()?, settings);
+        assert_eq!(db.list_buckets()?, vec!["legacy_objects".to_string()]);
+        let mut found = bucket.search_inclusive(vec![label.clone()])?;
+        found.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(bucket.label_value_search("legacy")?, vec![label.clone()]);
+        assert_eq!(
+            bucket.get_with_revision::<Testobj>(a)?,
+            Some((object.clone(), 1))
+        );
+        assert_eq!(bucket.scan(None, 10)?.ids, vec![a, b]);
+        let meta = bucket.metadata(a)?.unwrap();
+        assert_eq!((meta.revision, meta.codec), (1, CodecKind::Flexbuffers));
+        assert_eq!(meta.size, bucket.get_raw(a)?.unwrap().len() as u64);
+        assert_eq!((bucket.usage()?.documents, bucket.usage()?.labels), (2, 1));
+
+        // A migration that stopped after tagging bodies carries on without tagging them again
+        db.get_tree("legacy_objects::settings")?
+            .insert(b"format", &5u64.to_be_bytes())?;
+        let bucket = db.get_bucket("legacy_objects")?;
+        assert_eq!(bucket.get::<Testobj>(b)?, Some(object));
+
+        bucket.delete::<Testobj>(a)?;
+        assert_eq!(bucket.get_label(label)?, Some(vec![b]));
+        Ok(())
+    }
 }
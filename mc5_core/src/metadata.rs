@@ -0,0 +1,44 @@
+use crate::codec::CodecKind;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// System metadata kept for every document, updated by every write
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    /// When the document was inserted
+    pub created: SystemTime,
+
+    /// When the document was last written, including by a restore
+    pub modified: SystemTime,
+
+    /// Current revision of the document
+    pub revision: u64,
+
+    /// Number of stored bytes, as [`get_raw`](crate::bucket::MangoChainsawBucket::get_raw)
+    /// returns them
+    pub size: u64,
+
+    /// SHA-256 of the stored bytes, in lowercase hex
+    pub checksum: String,
+
+    /// Codec the document was written with
+    pub codec: CodecKind,
+
+    /// Media type the document was written with, if one was given
+    pub content_type: Option<String>,
+}
+
+impl DocumentMetadata {
+    /// The media type to serve the document as: the one it was written with,
+    /// or else the one its codec writes
+    pub fn media_type(&self) -> &str {
+        self.content_type
+            .as_deref()
+            .unwrap_or(self.codec.media_type())
+    }
+}
+
+/// Format bytes as lowercase hex
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,93 @@
+use crate::errors::MangoChainsawError;
+use std::fmt::Display;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// An opaque position to continue a scan or search from.
+///
+/// Cursors round-trip through strings, so they can be handed to clients as continuation tokens.
+/// A cursor is only meaningful to the kind of listing that produced it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    kind: CursorKind,
+    position: Vec<u8>,
+}
+
+/// What a cursor's position refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CursorKind {
+    /// The last key visited in the documents tree
+    Document = 1,
+    /// The last document ordinal visited in a search
+    Ordinal = 2,
+    /// The last key visited in a scan over document keys
+    Key = 3,
+}
+
+impl Cursor {
+    pub(crate) fn new(kind: CursorKind, position: &[u8]) -> Self {
+        Self {
+            kind,
+            position: position.to_vec(),
+        }
+    }
+
+    /// Get the position of a cursor, checking it came from the right kind of listing
+    pub(crate) fn position(&self, kind: CursorKind) -> Result<&[u8], MangoChainsawError> {
+        if self.kind != kind {
+            return Err(MangoChainsawError::InvalidCursor(format!(
+                "expected a {kind:?} cursor, found a {:?} cursor",
+                self.kind
+            )));
+        }
+        Ok(&self.position)
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x}", self.kind as u8)?;
+        for b in &self.position {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = MangoChainsawError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MangoChainsawError::InvalidCursor(s.to_string());
+        if !s.is_ascii() || !s.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let mut bytes = Vec::with_capacity(s.len() / 2);
+        for i in (0..s.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid())?);
+        }
+        let kind = match bytes.first() {
+            Some(1) => CursorKind::Document,
+            Some(2) => CursorKind::Ordinal,
+            Some(3) => CursorKind::Key,
+            _ => return Err(invalid()),
+        };
+        Ok(Self::new(kind, &bytes[1..]))
+    }
+}
+
+/// One page of document ids from a scan or search
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Page {
+    pub ids: Vec<Uuid>,
+    /// Where to continue from, or None if this is the last page
+    pub next: Option<Cursor>,
+}
+
+/// One page of document keys from a scan over keys
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPage {
+    pub keys: Vec<Vec<u8>>,
+    /// Where to continue from, or None if this is the last page
+    pub next: Option<Cursor>,
+}
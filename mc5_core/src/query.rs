@@ -0,0 +1,676 @@
+use crate::label::{Label, LabelValue};
+use crate::{bucket::MangoChainsawBucket, errors::MangoChainsawError};
+use roaring::RoaringBitmap;
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// A boolean query over document labels.
+///
+/// Queries are strict: a label that doesn't exist matches no documents.
+///
+/// The text form combines terms with `AND`, `OR`, `NOT` and parentheses,
+/// with `AND` binding tighter than `OR`:
+///
+/// - `key` has a label with this key, whatever its value
+/// - `key=value` has exactly this label
+/// - `key^=prefix` has a label with this key whose value starts with `prefix`
+/// - `key IN (a, b, c)` has a label with this key and any of the values
+/// - `key>value`, `key>=value`, `key<value` and `key<=value` compare typed values,
+///   see [`LabelValue`]
+///
+/// Keys and values may be double quoted to include spaces or punctuation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    /// Documents matching every sub-query. An empty `And` matches every document.
+    And(Vec<Query>),
+    /// Documents matching any sub-query. An empty `Or` matches no documents.
+    Or(Vec<Query>),
+    /// Documents not matching the sub-query
+    Not(Box<Query>),
+    /// Documents with any label with this key
+    HasKey(String),
+    /// Documents with this key and value
+    Eq(String, String),
+    /// Documents with this key and any of the values
+    In(String, Vec<String>),
+    /// Documents with this key and a value starting with the prefix
+    Prefix(String, String),
+    /// Documents with this key and a typed value within the bounds
+    Range(String, Bound<LabelValue>, Bound<LabelValue>),
+}
+
+impl Query {
+    /// Match documents with exactly this key and value
+    pub fn eq(key: &str, value: &str) -> Self {
+        Self::Eq(key.to_string(), value.to_string())
+    }
+
+    /// Match documents with every one of the labels
+    pub fn all_of(labels: &[Label]) -> Self {
+        Self::And(labels.iter().map(Self::from).collect())
+    }
+
+    /// Match documents with any of the labels
+    pub fn any_of(labels: &[Label]) -> Self {
+        Self::Or(labels.iter().map(Self::from).collect())
+    }
+
+    /// Parse a query from its text form
+    pub fn parse(s: &str) -> Result<Self, MangoChainsawError> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            end: s.len(),
+        };
+        let query = parser.or_expr()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some((offset, token)) => Err(invalid(*offset, format!("unexpected {token:?}"))),
+        }
+    }
+
+    /// Parse a Kubernetes-style label selector.
+    ///
+    /// A selector is a comma separated list of requirements that must all match:
+    /// `key`, `!key`, `key=value`, `key==value`, `key!=value`, `key in (a,b)` and `key notin (a,b)`.
+    /// As in Kubernetes, `!=` and `notin` also match documents without the key,
+    /// and an empty selector matches every document.
+    pub fn parse_selector(s: &str) -> Result<Self, MangoChainsawError> {
+        if s.trim().is_empty() {
+            return Ok(Query::And(vec![]));
+        }
+        let mut requirements = vec![];
+        for (offset, requirement) in split_requirements(s)? {
+            requirements.push(parse_requirement(offset, requirement)?);
+        }
+        Ok(match requirements.len() {
+            1 => requirements.remove(0),
+            _ => Query::And(requirements),
+        })
+    }
+
+    /// Evaluate the query against a bucket's label bitmaps, giving the matching ordinals
+    pub(crate) fn evaluate(
+        &self,
+        bucket: &MangoChainsawBucket,
+    ) -> Result<RoaringBitmap, MangoChainsawError> {
+        match self {
+            Query::And(queries) => {
+                // Intersect the positive terms first, so NOT only has to subtract from them
+                // rather than from every document in the bucket
+                let (negative, positive): (Vec<_>, Vec<_>) =
+                    queries.iter().partition(|q| matches!(q, Query::Not(_)));
+                let mut matches = match positive.split_first() {
+                    Some((first, rest)) => {
+                        let mut matches = first.evaluate(bucket)?;
+                        for query in rest {
+                            if matches.is_empty() {
+                                break;
+                            }
+                            matches &= query.evaluate(bucket)?;
+                        }
+                        matches
+                    }
+                    None => bucket.all_ordinals()?,
+                };
+                for query in negative {
+                    if matches.is_empty() {
+                        break;
+                    }
+                    if let Query::Not(inner) = query {
+                        matches -= inner.evaluate(bucket)?;
+                    }
+                }
+                Ok(matches)
+            }
+            Query::Or(queries) => {
+                let mut matches = RoaringBitmap::new();
+                for query in queries {
+                    matches |= query.evaluate(bucket)?;
+                }
+                Ok(matches)
+            }
+            Query::Not(query) => Ok(bucket.all_ordinals()? - query.evaluate(bucket)?),
+            Query::HasKey(key) => bucket.prefix_bitmap(&format!("{key}=")),
+            Query::Eq(key, value) => bucket.label_bitmap(&Label::new(key, value)),
+            Query::In(key, values) => {
+                let mut matches = RoaringBitmap::new();
+                for value in values {
+                    matches |= bucket.label_bitmap(&Label::new(key, value))?;
+                }
+                Ok(matches)
+            }
+            Query::Prefix(key, prefix) => bucket.prefix_bitmap(&format!("{key}={prefix}")),
+            Query::Range(key, start, end) => bucket.range_bitmap(key, start.as_ref(), end.as_ref()),
+        }
+    }
+}
+
+impl From<&Label> for Query {
+    fn from(label: &Label) -> Self {
+        Self::eq(label.key(), label.value())
+    }
+}
+
+impl std::ops::Not for Query {
+    type Output = Query;
+
+    /// Match documents that don't match this query
+    fn not(self) -> Self::Output {
+        Self::Not(Box::new(self))
+    }
+}
+
+impl FromStr for Query {
+    type Err = MangoChainsawError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn invalid(offset: usize, message: String) -> MangoChainsawError {
+    MangoChainsawError::InvalidQuery { offset, message }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    LParen,
+    RParen,
+    Comma,
+    Equals,
+    PrefixEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
+    Word(String),
+    Quoted(String),
+}
+
+impl Token {
+    /// Whether this token is the given keyword. Keywords are case insensitive and never quoted.
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// Characters that end an unquoted word
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | ',' | '=' | '<' | '>' | '"')
+}
+
+/// Split query text into tokens, each with its byte offset
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, MangoChainsawError> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(offset, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' | '=' => {
+                chars.next();
+                tokens.push((
+                    offset,
+                    match c {
+                        '(' => Token::LParen,
+                        ')' => Token::RParen,
+                        ',' => Token::Comma,
+                        _ => Token::Equals,
+                    },
+                ));
+            }
+            '^' if s[offset..].starts_with("^=") => {
+                chars.next();
+                chars.next();
+                tokens.push((offset, Token::PrefixEquals));
+            }
+            '<' | '>' => {
+                chars.next();
+                let or_equal = matches!(chars.peek(), Some((_, '=')));
+                if or_equal {
+                    chars.next();
+                }
+                tokens.push((
+                    offset,
+                    match (c, or_equal) {
+                        ('<', false) => Token::Less,
+                        ('<', true) => Token::LessEquals,
+                        ('>', false) => Token::Greater,
+                        _ => Token::GreaterEquals,
+                    },
+                ));
+            }
+            '"' => {
+                chars.next();
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => quoted.push(c),
+                            None => return Err(invalid(offset, "unterminated string".into())),
+                        },
+                        Some((_, c)) => quoted.push(c),
+                        None => return Err(invalid(offset, "unterminated string".into())),
+                    }
+                }
+                tokens.push((offset, Token::Quoted(quoted)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(i, c)) = chars.peek() {
+                    if is_delimiter(c) || s[i..].starts_with("^=") {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((offset, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the query tokens.
+///
+/// ```text
+/// or_expr  := and_expr ("OR" and_expr)*
+/// and_expr := unary ("AND" unary)*
+/// unary    := "NOT" unary | "(" or_expr ")" | term
+/// term     := key (("=" | "^=" | "<" | "<=" | ">" | ">=") value
+///                  | "IN" "(" value ("," value)* ")")?
+/// ```
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(usize, Token)> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Consume the next token if it is the given keyword
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some((_, token)) if token.is_keyword(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), MangoChainsawError> {
+        match self.next() {
+            Some((_, token)) if token == expected => Ok(()),
+            Some((offset, token)) => Err(invalid(
+                offset,
+                format!("expected {expected:?}, found {token:?}"),
+            )),
+            None => Err(invalid(self.end, format!("expected {expected:?}"))),
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Query, MangoChainsawError> {
+        let mut queries = vec![self.and_expr()?];
+        while self.keyword("OR") {
+            queries.push(self.and_expr()?);
+        }
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::Or(queries),
+        })
+    }
+
+    fn and_expr(&mut self) -> Result<Query, MangoChainsawError> {
+        let mut queries = vec![self.unary()?];
+        while self.keyword("AND") {
+            queries.push(self.unary()?);
+        }
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::And(queries),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Query, MangoChainsawError> {
+        if self.keyword("NOT") {
+            return Ok(!self.unary()?);
+        }
+        if let Some((_, Token::LParen)) = self.peek() {
+            self.pos += 1;
+            let query = self.or_expr()?;
+            self.expect(Token::RParen)?;
+            return Ok(query);
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Query, MangoChainsawError> {
+        let key = match self.next() {
+            Some((offset, token @ Token::Word(_)))
+                if ["AND", "OR", "NOT", "IN"]
+                    .iter()
+                    .any(|keyword| token.is_keyword(keyword)) =>
+            {
+                return Err(invalid(
+                    offset,
+                    format!("expected a label key, found {token:?}"),
+                ));
+            }
+            Some((_, Token::Word(key) | Token::Quoted(key))) => key,
+            Some((offset, token)) => {
+                return Err(invalid(
+                    offset,
+                    format!("expected a label key, found {token:?}"),
+                ));
+            }
+            None => return Err(invalid(self.end, "expected a label key".into())),
+        };
+
+        match self.peek() {
+            Some((_, Token::Equals)) => {
+                self.pos += 1;
+                Ok(Query::Eq(key, self.value()?))
+            }
+            Some((_, Token::PrefixEquals)) => {
+                self.pos += 1;
+                Ok(Query::Prefix(key, self.value()?))
+            }
+            Some((_, token @ (Token::Less | Token::LessEquals))) => {
+                let token = token.clone();
+                self.pos += 1;
+                let value = LabelValue::infer(&self.value()?);
+                let end = match token {
+                    Token::Less => Bound::Excluded(value),
+                    _ => Bound::Included(value),
+                };
+                Ok(Query::Range(key, Bound::Unbounded, end))
+            }
+            Some((_, token @ (Token::Greater | Token::GreaterEquals))) => {
+                let token = token.clone();
+                self.pos += 1;
+                let value = LabelValue::infer(&self.value()?);
+                let start = match token {
+                    Token::Greater => Bound::Excluded(value),
+                    _ => Bound::Included(value),
+                };
+                Ok(Query::Range(key, start, Bound::Unbounded))
+            }
+            Some((_, token)) if token.is_keyword("IN") => {
+                self.pos += 1;
+                self.expect(Token::LParen)?;
+                let mut values = vec![self.value()?];
+                while let Some((_, Token::Comma)) = self.peek() {
+                    self.pos += 1;
+                    values.push(self.value()?);
+                }
+                self.expect(Token::RParen)?;
+                Ok(Query::In(key, values))
+            }
+            _ => Ok(Query::HasKey(key)),
+        }
+    }
+
+    fn value(&mut self) -> Result<String, MangoChainsawError> {
+        match self.next() {
+            Some((_, Token::Word(value) | Token::Quoted(value))) => Ok(value),
+            Some((offset, token)) => Err(invalid(
+                offset,
+                format!("expected a label value, found {token:?}"),
+            )),
+            None => Err(invalid(self.end, "expected a label value".into())),
+        }
+    }
+}
+
+/// Split a selector on the commas between requirements, each with its byte offset
+fn split_requirements(s: &str) -> Result<Vec<(usize, &str)>, MangoChainsawError> {
+    let mut requirements = vec![];
+    let (mut start, mut depth) = (0, 0usize);
+    for (offset, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| invalid(offset, "unbalanced ')'".into()))?;
+            }
+            ',' if depth == 0 => {
+                requirements.push((start, &s[start..offset]));
+                start = offset + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        return Err(invalid(s.len(), "expected ')'".into()));
+    }
+    requirements.push((start, &s[start..]));
+    Ok(requirements)
+}
+
+/// Characters that can't appear in an unquoted selector key or value
+fn is_selector_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '!' | '=' | '(' | ')' | ',')
+}
+
+/// Parse a single selector requirement starting at `offset`
+fn parse_requirement(offset: usize, text: &str) -> Result<Query, MangoChainsawError> {
+    let offset = offset + text.len() - text.trim_start().len();
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(invalid(offset, "empty requirement".into()));
+    }
+    if let Some(key) = text.strip_prefix('!') {
+        let key = selector_word(offset + 1, key.trim_start(), "key")?;
+        return Ok(!Query::HasKey(key));
+    }
+
+    let key_len = text.find(is_selector_delimiter).unwrap_or(text.len());
+    let key = selector_word(offset, &text[..key_len], "key")?;
+    let rest = text[key_len..].trim_start();
+    let offset = offset + text.len() - rest.len();
+    if rest.is_empty() {
+        return Ok(Query::HasKey(key));
+    }
+
+    if let Some(value) = rest.strip_prefix("!=") {
+        let value = selector_value(offset + 2, value)?;
+        return Ok(!Query::Eq(key, value));
+    }
+    if let Some(value) = rest.strip_prefix("==").or_else(|| rest.strip_prefix('=')) {
+        let op_len = rest.len() - value.len();
+        return Ok(Query::Eq(key, selector_value(offset + op_len, value)?));
+    }
+    for (operator, negate) in [("notin", true), ("in", false)] {
+        let Some(set) = rest.strip_prefix(operator) else {
+            continue;
+        };
+        if !set.starts_with(|c: char| c.is_whitespace() || c == '(') {
+            continue;
+        }
+        let values = selector_set(offset + operator.len(), set)?;
+        return Ok(match negate {
+            true => !Query::In(key, values),
+            false => Query::In(key, values),
+        });
+    }
+    Err(invalid(
+        offset,
+        format!("expected an operator after {key:?}, found {rest:?}"),
+    ))
+}
+
+/// Parse a value, which may be empty, after an equality operator
+fn selector_value(offset: usize, text: &str) -> Result<String, MangoChainsawError> {
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    selector_word(
+        offset + text.len() - text.trim_start().len(),
+        trimmed,
+        "value",
+    )
+}
+
+/// Parse a parenthesised, comma separated set of values
+fn selector_set(offset: usize, text: &str) -> Result<Vec<String>, MangoChainsawError> {
+    let offset = offset + text.len() - text.trim_start().len();
+    let Some(inner) = text
+        .trim()
+        .strip_prefix('(')
+        .and_then(|t| t.strip_suffix(')'))
+    else {
+        return Err(invalid(
+            offset,
+            "expected a set of values like (a,b)".into(),
+        ));
+    };
+    let mut values = vec![];
+    let mut start = offset + 1;
+    for value in inner.split(',') {
+        let value_offset = start + value.len() - value.trim_start().len();
+        values.push(selector_word(value_offset, value.trim(), "value")?);
+        start += value.len() + 1;
+    }
+    Ok(values)
+}
+
+/// Check that a key or value is a single non-empty word
+fn selector_word(offset: usize, word: &str, what: &str) -> Result<String, MangoChainsawError> {
+    if word.is_empty() {
+        return Err(invalid(offset, format!("expected a label {what}")));
+    }
+    if let Some(i) = word.find(is_selector_delimiter) {
+        return Err(invalid(
+            offset + i,
+            format!("unexpected {:?} in label {what}", &word[i..]),
+        ));
+    }
+    Ok(word.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<(), MangoChainsawError> {
+        assert_eq!(
+            Query::parse("filetype=rust_code AND NOT path^=target/")?,
+            Query::And(vec![
+                Query::eq("filetype", "rust_code"),
+                !Query::Prefix("path".into(), "target/".into()),
+            ])
+        );
+        assert_eq!(
+            Query::parse("a=1 OR b=2 and c")?,
+            Query::Or(vec![
+                Query::eq("a", "1"),
+                Query::And(vec![Query::eq("b", "2"), Query::HasKey("c".into())]),
+            ])
+        );
+        assert_eq!(
+            Query::parse("(a=1 OR b=2) AND env in (prod, \"us east\")")?,
+            Query::And(vec![
+                Query::Or(vec![Query::eq("a", "1"), Query::eq("b", "2")]),
+                Query::In("env".into(), vec!["prod".into(), "us east".into()]),
+            ])
+        );
+        assert_eq!(
+            Query::parse("\"status code\"=\"a \\\"quoted\\\" (value)\"")?,
+            Query::eq("status code", "a \"quoted\" (value)")
+        );
+        assert_eq!(Query::parse("x=a^b")?, Query::eq("x", "a^b"));
+        assert_eq!(
+            Query::parse("size>=1024 AND when<2024-05-01T00:00:00Z")?,
+            Query::And(vec![
+                Query::Range(
+                    "size".into(),
+                    Bound::Included(LabelValue::Int(1024)),
+                    Bound::Unbounded
+                ),
+                Query::Range(
+                    "when".into(),
+                    Bound::Unbounded,
+                    Bound::Excluded(LabelValue::infer("2024-05-01T00:00:00Z"))
+                ),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (text, expected_offset) in [
+            ("", 0),
+            ("a=", 2),
+            ("a=1 AND", 7),
+            ("(a=1", 4),
+            ("a=1 b=2", 4),
+            ("NOT AND", 4),
+            ("a IN ()", 6),
+            ("a=\"open", 2),
+        ] {
+            match Query::parse(text) {
+                Err(MangoChainsawError::InvalidQuery { offset, .. }) => {
+                    assert_eq!(offset, expected_offset, "{text}")
+                }
+                other => panic!("{text} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_selector() -> Result<(), MangoChainsawError> {
+        assert_eq!(
+            Query::parse_selector("env in (prod,stage), tier!=db, !deprecated, has_owner")?,
+            Query::And(vec![
+                Query::In("env".into(), vec!["prod".into(), "stage".into()]),
+                !Query::eq("tier", "db"),
+                !Query::HasKey("deprecated".into()),
+                Query::HasKey("has_owner".into()),
+            ])
+        );
+        assert_eq!(
+            Query::parse_selector("app.kubernetes.io/name==web,zone notin(a, b)")?,
+            Query::And(vec![
+                Query::eq("app.kubernetes.io/name", "web"),
+                !Query::In("zone".into(), vec!["a".into(), "b".into()]),
+            ])
+        );
+        assert_eq!(Query::parse_selector("index=")?, Query::eq("index", ""));
+        assert_eq!(Query::parse_selector(" ")?, Query::And(vec![]));
+
+        for (text, expected_offset) in [
+            ("a,,b", 2),
+            ("env in prod", 7),
+            ("env in (prod", 12),
+            ("tier=db x", 7),
+            ("a b", 2),
+            ("!", 1),
+            ("env in (a,,b)", 10),
+        ] {
+            match Query::parse_selector(text) {
+                Err(MangoChainsawError::InvalidQuery { offset, .. }) => {
+                    assert_eq!(offset, expected_offset, "{text}")
+                }
+                other => panic!("{text} parsed as {other:?}"),
+            }
+        }
+        Ok(())
+    }
+}
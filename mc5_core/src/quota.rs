@@ -0,0 +1,226 @@
+use crate::errors::MangoChainsawError;
+use crate::settings::Quota;
+use crate::storage::{Batch, TransactionalTree, Tree};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult};
+use std::fmt;
+
+/// Key of the bucket's totals in the `{name}::usage` tree. Every other key is a label,
+/// holding the number of documents that carry it. Real labels are never empty,
+/// so this can't collide with one.
+const TOTALS: &[u8] = b"";
+
+/// A limit in a bucket's [`Quota`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaLimit {
+    Documents,
+    Bytes,
+    DocumentSize,
+    LabelsPerDocument,
+    DistinctLabels,
+}
+
+impl QuotaLimit {
+    /// Whether the limit applies to each document on its own, rather than the whole bucket
+    pub fn per_document(self) -> bool {
+        matches!(self, Self::DocumentSize | Self::LabelsPerDocument)
+    }
+}
+
+impl fmt::Display for QuotaLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Documents => "documents",
+            Self::Bytes => "bytes",
+            Self::DocumentSize => "document size",
+            Self::LabelsPerDocument => "labels per document",
+            Self::DistinctLabels => "distinct labels",
+        })
+    }
+}
+
+/// What a bucket holds, as counted against its quota
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Number of documents, not counting archived versions
+    pub documents: u64,
+
+    /// Total size of the documents' bytes, not counting archived versions
+    pub bytes: u64,
+
+    /// Number of distinct labels on the documents
+    pub labels: u64,
+}
+
+impl Usage {
+    fn encode(&self) -> [u8; 24] {
+        let mut raw = [0; 24];
+        raw[..8].copy_from_slice(&self.documents.to_be_bytes());
+        raw[8..16].copy_from_slice(&self.bytes.to_be_bytes());
+        raw[16..].copy_from_slice(&self.labels.to_be_bytes());
+        raw
+    }
+
+    fn decode(raw: &[u8]) -> Result<Self, MangoChainsawError> {
+        if raw.len() != 24 {
+            return Err(MangoChainsawError::CorruptCounter(raw.len()));
+        }
+        Ok(Self {
+            documents: decode_count(&raw[..8])?,
+            bytes: decode_count(&raw[8..16])?,
+            labels: decode_count(&raw[16..])?,
+        })
+    }
+}
+
+fn decode_count(raw: &[u8]) -> Result<u64, MangoChainsawError> {
+    let bytes = raw
+        .try_into()
+        .map_err(|_| MangoChainsawError::CorruptCounter(raw.len()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn exceeded(bucket: &str, limit: QuotaLimit, max: u64) -> MangoChainsawError {
+    MangoChainsawError::QuotaExceeded {
+        bucket: bucket.to_string(),
+        limit,
+        max,
+    }
+}
+
+/// Check a document's size against the bucket's quota
+pub(crate) fn check_size(quota: &Quota, bucket: &str, size: u64) -> Result<(), MangoChainsawError> {
+    match quota.max_document_size {
+        Some(max) if size > max => Err(exceeded(bucket, QuotaLimit::DocumentSize, max)),
+        _ => Ok(()),
+    }
+}
+
+/// Check the number of labels on a document against the bucket's quota
+pub(crate) fn check_label_count(
+    quota: &Quota,
+    bucket: &str,
+    labels: usize,
+) -> Result<(), MangoChainsawError> {
+    match quota.max_labels_per_document {
+        Some(max) if labels as u64 > max => {
+            Err(exceeded(bucket, QuotaLimit::LabelsPerDocument, max))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Get a bucket's usage, as kept while it has a quota that needs counting
+pub(crate) fn usage(tree: &Tree) -> Result<Usage, MangoChainsawError> {
+    match tree.get(TOTALS)? {
+        Some(raw) => Usage::decode(&raw),
+        None => Ok(Usage::default()),
+    }
+}
+
+fn totals(
+    usage: &dyn TransactionalTree,
+) -> ConflictableTransactionResult<Usage, MangoChainsawError> {
+    match usage.get(TOTALS)? {
+        Some(raw) => Usage::decode(&raw).map_err(ConflictableTransactionError::Abort),
+        None => Ok(Usage::default()),
+    }
+}
+
+/// Add documents and bytes to a bucket's usage, or take them away with negative amounts.
+/// Aborts if an addition takes the bucket over its quota. Nothing is counted while the
+/// quota has no limit on the bucket as a whole, so writes don't all contend for the totals.
+pub(crate) fn charge(
+    usage: &dyn TransactionalTree,
+    quota: &Quota,
+    bucket: &str,
+    documents: i64,
+    bytes: i64,
+) -> ConflictableTransactionResult<(), MangoChainsawError> {
+    if !quota.counts_usage() {
+        return Ok(());
+    }
+    let mut totals = totals(usage)?;
+    totals.documents = totals.documents.saturating_add_signed(documents);
+    totals.bytes = totals.bytes.saturating_add_signed(bytes);
+    match (quota.max_documents, quota.max_bytes) {
+        (Some(max), _) if documents > 0 && totals.documents > max => Err(
+            ConflictableTransactionError::Abort(exceeded(bucket, QuotaLimit::Documents, max)),
+        ),
+        (_, Some(max)) if bytes > 0 && totals.bytes > max => Err(
+            ConflictableTransactionError::Abort(exceeded(bucket, QuotaLimit::Bytes, max)),
+        ),
+        _ => {
+            usage.insert(TOTALS, &totals.encode())?;
+            Ok(())
+        }
+    }
+}
+
+/// Count documents gaining a label, or losing it with a negative amount,
+/// keeping the bucket's number of distinct labels up to date.
+/// Returns whether the label is new to the bucket.
+pub(crate) fn count_label(
+    usage: &dyn TransactionalTree,
+    label: &[u8],
+    documents: i64,
+) -> ConflictableTransactionResult<bool, MangoChainsawError> {
+    if documents == 0 {
+        return Ok(false);
+    }
+    let before = match usage.get(label)? {
+        Some(raw) => decode_count(&raw).map_err(ConflictableTransactionError::Abort)?,
+        None => 0,
+    };
+    let after = before.saturating_add_signed(documents);
+    if after == 0 {
+        usage.remove(label)?;
+    } else {
+        usage.insert(label, &after.to_be_bytes())?;
+    }
+    let created = before == 0 && after > 0;
+    if created || (before > 0 && after == 0) {
+        let mut totals = totals(usage)?;
+        totals.labels = match created {
+            true => totals.labels + 1,
+            false => totals.labels.saturating_sub(1),
+        };
+        usage.insert(TOTALS, &totals.encode())?;
+    }
+    Ok(created)
+}
+
+/// Abort if the bucket has more distinct labels than its quota allows
+pub(crate) fn check_labels(
+    usage: &dyn TransactionalTree,
+    quota: &Quota,
+    bucket: &str,
+) -> ConflictableTransactionResult<(), MangoChainsawError> {
+    match quota.max_distinct_labels {
+        Some(max) if totals(usage)?.labels > max => Err(ConflictableTransactionError::Abort(
+            exceeded(bucket, QuotaLimit::DistinctLabels, max),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Rebuild a bucket's usage from scratch, as a migration or a newly set quota does
+pub(crate) fn recount(
+    tree: &Tree,
+    totals: Usage,
+    labels: impl IntoIterator<Item = (Vec<u8>, u64)>,
+) -> Result<(), MangoChainsawError> {
+    let mut batch = Batch::default();
+    let mut distinct = 0;
+    for (label, count) in labels {
+        batch.insert(label, &count.to_be_bytes());
+        distinct += 1;
+    }
+    let totals = Usage {
+        labels: distinct,
+        ..totals
+    };
+    batch.insert(TOTALS, &totals.encode());
+    tree.clear()?;
+    tree.apply_batch(batch)
+}
@@ -0,0 +1,135 @@
+use crate::errors::MangoChainsawError;
+use crate::label::{Label, LabelValue};
+use crate::storage::{TransactionalTree, Tree};
+use roaring::RoaringBitmap;
+use sled::transaction::UnabortableTransactionError;
+use std::ops::Bound;
+
+/// Separates the label key from the typed value in range keys.
+/// Label keys are UTF-8, which never contains this byte.
+const RANGE_SEPARATOR: u8 = 0xFF;
+
+/// Length of the ordinal at the end of every range key
+const ORDINAL_LEN: usize = 4;
+
+/// The prefix shared by every range key for a label key
+fn key_prefix(key: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(key.len() + 1);
+    prefix.extend_from_slice(key.as_bytes());
+    prefix.push(RANGE_SEPARATOR);
+    prefix
+}
+
+/// The prefix shared by every range key for a label key and value
+fn value_prefix(key: &str, value: &LabelValue) -> Vec<u8> {
+    let mut prefix = key_prefix(key);
+    prefix.extend_from_slice(&value.to_ordered_bytes());
+    prefix
+}
+
+/// Range keys are the label key, a separator, the ordered value encoding and the ordinal,
+/// so a scan between two encoded values visits the ordinals in value order
+pub(crate) fn range_key(label: &Label, ordinal: u32) -> Vec<u8> {
+    let mut key = value_prefix(label.key(), &label.typed_value());
+    key.extend_from_slice(&ordinal.to_be_bytes());
+    key
+}
+
+/// Add a label to the range index for each ordinal
+pub(crate) fn insert(
+    t: &dyn TransactionalTree,
+    label: &Label,
+    ordinals: &[u32],
+) -> Result<(), UnabortableTransactionError> {
+    for ordinal in ordinals {
+        t.insert(range_key(label, *ordinal), &[])?;
+    }
+    Ok(())
+}
+
+/// Remove a label from the range index for each ordinal
+pub(crate) fn remove(
+    t: &dyn TransactionalTree,
+    label: &Label,
+    ordinals: &[u32],
+) -> Result<(), UnabortableTransactionError> {
+    for ordinal in ordinals {
+        t.remove(range_key(label, *ordinal))?;
+    }
+    Ok(())
+}
+
+/// Load the ordinals of every document with a value for `key` within the bounds.
+/// An unbounded side only reaches values of the other bound's type,
+/// and bounds of different types match nothing.
+pub(crate) fn load(
+    tree: &Tree,
+    key: &str,
+    start: Bound<&LabelValue>,
+    end: Bound<&LabelValue>,
+) -> Result<RoaringBitmap, MangoChainsawError> {
+    let prefix = key_prefix(key);
+    let with_tag = |tag: u8| {
+        let mut bound = prefix.clone();
+        bound.push(tag);
+        bound
+    };
+    let past_ordinals = |value: &LabelValue| {
+        let mut bound = value_prefix(key, value);
+        bound.extend_from_slice(&[0xFF; ORDINAL_LEN]);
+        bound
+    };
+
+    let tags = (bound_tag(start), bound_tag(end));
+    let range = match tags {
+        (None, None) => (
+            Bound::Included(prefix.clone()),
+            Bound::Excluded(with_tag(u8::MAX)),
+        ),
+        (Some(lo), Some(hi)) if lo != hi => return Ok(RoaringBitmap::new()),
+        (lo, hi) => {
+            let tag = lo.or(hi).unwrap_or_default();
+            let start = match start {
+                Bound::Included(v) => Bound::Included(value_prefix(key, v)),
+                Bound::Excluded(v) => Bound::Excluded(past_ordinals(v)),
+                Bound::Unbounded => Bound::Included(with_tag(tag)),
+            };
+            let end = match end {
+                Bound::Included(v) => Bound::Included(past_ordinals(v)),
+                Bound::Excluded(v) => Bound::Excluded(value_prefix(key, v)),
+                Bound::Unbounded => Bound::Excluded(with_tag(tag + 1)),
+            };
+            (start, end)
+        }
+    };
+    if is_empty_range(&range) {
+        return Ok(RoaringBitmap::new());
+    }
+
+    let mut bitmap = RoaringBitmap::new();
+    for result in tree.range(range).keys() {
+        let key = result?;
+        let mut ordinal = [0u8; ORDINAL_LEN];
+        ordinal.copy_from_slice(&key[key.len() - ORDINAL_LEN..]);
+        bitmap.insert(u32::from_be_bytes(ordinal));
+    }
+    Ok(bitmap)
+}
+
+fn bound_tag(bound: Bound<&LabelValue>) -> Option<u8> {
+    match bound {
+        Bound::Included(v) | Bound::Excluded(v) => Some(v.tag()),
+        Bound::Unbounded => None,
+    }
+}
+
+/// Whether a pair of key bounds can't contain anything, which sled doesn't allow in a range
+fn is_empty_range((start, end): &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    match (start, end) {
+        (Bound::Included(lo), Bound::Included(hi)) => lo > hi,
+        (Bound::Included(lo) | Bound::Excluded(lo), Bound::Included(hi) | Bound::Excluded(hi)) => {
+            lo >= hi
+        }
+        _ => false,
+    }
+}
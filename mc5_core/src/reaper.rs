@@ -0,0 +1,55 @@
+use crate::{errors::MangoChainsawError, mango::MangoChainsaw};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{error, info, instrument};
+
+/// Background thread that periodically removes expired documents and unreferenced chunks
+/// from every bucket.
+/// Dropping the reaper stops the thread and waits for it to release the db.
+#[derive(Debug)]
+pub(crate) struct Reaper {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    /// Start reaping `db` every `interval`
+    #[instrument(skip(db))]
+    pub(crate) fn spawn(db: MangoChainsaw, interval: Duration) -> Result<Self, MangoChainsawError> {
+        let (stop, stopped) = channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("mc5-reaper".to_string())
+            .spawn(move || {
+                info!("Reaper started");
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(e) = db.reap_expired() {
+                        error!(error = format!("{e}"), "Failed to reap expired documents");
+                    }
+                    if let Err(e) = db.collect_chunks() {
+                        error!(
+                            error = format!("{e}"),
+                            "Failed to collect unreferenced chunks"
+                        );
+                    }
+                }
+                info!("Reaper stopped");
+            })?;
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up and ends its loop
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Reaper thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::errors::MangoChainsawError;
+use crate::settings::BucketSettings;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Name of the tree that records every bucket. Bucket names can't start with `__`,
+/// so this never clashes with a bucket's trees.
+pub(crate) const REGISTRY_TREE: &str = "__mc5::buckets";
+
+/// Name of the tree that records copies and renames in progress, keyed by the bucket being
+/// written, so that opening the database can clean up after one that was interrupted
+pub(crate) const COPIES_TREE: &str = "__mc5::copies";
+
+/// Longest allowed bucket name, in bytes
+pub const MAX_BUCKET_NAME_LEN: usize = 128;
+
+/// A bucket's entry in the registry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketInfo {
+    /// When the bucket was created, or first registered if it predates the registry
+    pub created: SystemTime,
+
+    /// The bucket's settings
+    pub settings: BucketSettings,
+}
+
+/// A copy or rename in progress, recorded under the name of the bucket it writes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CopyProgress {
+    /// The bucket being copied or renamed
+    pub from: String,
+
+    /// Whether `from` goes away once it has been copied
+    pub rename: bool,
+
+    /// Whether every tree has been copied, so a rename only has to drop `from` to finish
+    pub copied: bool,
+}
+
+/// Check that a name can be used for a new bucket. Names are 1 to [`MAX_BUCKET_NAME_LEN`]
+/// ASCII letters, digits, `-`, `_` and `.`, and can't start with `__`, which is reserved.
+pub fn validate_bucket_name(name: &str) -> Result<(), MangoChainsawError> {
+    let invalid = |reason: &str| {
+        Err(MangoChainsawError::InvalidBucketName {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        })
+    };
+    if name.is_empty() {
+        return invalid("it is empty");
+    }
+    if name.len() > MAX_BUCKET_NAME_LEN {
+        return invalid(&format!("it is longer than {MAX_BUCKET_NAME_LEN} bytes"));
+    }
+    if name.starts_with("__") {
+        return invalid("names starting with __ are reserved");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        return invalid(&format!("{c:?} is not allowed"));
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use crate::codec::CodecKind;
+use crate::errors::MangoChainsawError;
+use crate::label::Label;
+use jsonschema::Validator;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Rules for the labels on a bucket's documents
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LabelSchema {
+    /// Keys every document needs at least one label for
+    pub required: Vec<String>,
+
+    /// Keys labels can use, besides the required ones. `None` allows any key.
+    pub allowed: Option<Vec<String>>,
+
+    /// Rules for the values of particular keys
+    pub values: BTreeMap<String, ValueRule>,
+
+    /// Longest allowed label value, in bytes
+    pub max_value_len: Option<usize>,
+}
+
+/// What the values of a label key have to look like
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueRule {
+    /// A regular expression that must match the whole value
+    Pattern(String),
+
+    /// The only values allowed
+    OneOf(Vec<String>),
+}
+
+/// One way a document's labels break the bucket's [`LabelSchema`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "violation", rename_all = "snake_case")]
+pub enum LabelViolation {
+    MissingKey {
+        key: String,
+    },
+    KeyNotAllowed {
+        key: String,
+    },
+    PatternMismatch {
+        key: String,
+        value: String,
+        pattern: String,
+    },
+    NotOneOf {
+        key: String,
+        value: String,
+    },
+    ValueTooLong {
+        key: String,
+        len: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for LabelViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingKey { key } => write!(f, "missing required label {key}"),
+            Self::KeyNotAllowed { key } => write!(f, "label key {key} is not allowed"),
+            Self::PatternMismatch {
+                key,
+                value,
+                pattern,
+            } => write!(f, "{key}={value} does not match {pattern}"),
+            Self::NotOneOf { key, value } => write!(f, "{key}={value} is not an allowed value"),
+            Self::ValueTooLong { key, len, max } => {
+                write!(f, "value of {key} is {len} bytes, over the limit of {max}")
+            }
+        }
+    }
+}
+
+/// Join violations into one line, for error messages
+pub(crate) fn describe<V: fmt::Display>(violations: &[V]) -> String {
+    violations
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl LabelSchema {
+    /// Compile the schema's patterns, ready to check labels against.
+    /// Fails with [`MangoChainsawError::InvalidSchema`] if a pattern isn't a valid regex.
+    pub fn compile(&self) -> Result<LabelValidator, MangoChainsawError> {
+        let mut patterns = HashMap::new();
+        for (key, rule) in &self.values {
+            if let ValueRule::Pattern(pattern) = rule {
+                let regex = Regex::new(&format!("^(?:{pattern})$")).map_err(|e| {
+                    MangoChainsawError::InvalidSchema(format!("pattern for {key}: {e}"))
+                })?;
+                patterns.insert(key.clone(), regex);
+            }
+        }
+        Ok(LabelValidator {
+            schema: self.clone(),
+            patterns,
+        })
+    }
+}
+
+/// A [`LabelSchema`] with its patterns compiled
+#[derive(Debug)]
+pub struct LabelValidator {
+    schema: LabelSchema,
+    patterns: HashMap<String, Regex>,
+}
+
+impl LabelValidator {
+    /// List the ways a document's full set of labels breaks the schema
+    pub fn violations(&self, labels: &[Label]) -> Vec<LabelViolation> {
+        let schema = &self.schema;
+        let mut violations = vec![];
+        for key in &schema.required {
+            if !labels.iter().any(|l| l.key() == key) {
+                violations.push(LabelViolation::MissingKey { key: key.clone() });
+            }
+        }
+        for label in labels {
+            let (key, value) = (label.key(), label.value());
+            if let Some(allowed) = &schema.allowed {
+                if !allowed.iter().chain(&schema.required).any(|k| k == key) {
+                    violations.push(LabelViolation::KeyNotAllowed { key: key.into() });
+                    continue;
+                }
+            }
+            if let Some(max) = schema.max_value_len {
+                if value.len() > max {
+                    violations.push(LabelViolation::ValueTooLong {
+                        key: key.into(),
+                        len: value.len(),
+                        max,
+                    });
+                }
+            }
+            match schema.values.get(key) {
+                Some(ValueRule::Pattern(pattern)) if !self.patterns[key].is_match(value) => {
+                    violations.push(LabelViolation::PatternMismatch {
+                        key: key.into(),
+                        value: value.into(),
+                        pattern: pattern.clone(),
+                    })
+                }
+                Some(ValueRule::OneOf(values)) if !values.iter().any(|v| v == value) => violations
+                    .push(LabelViolation::NotOneOf {
+                        key: key.into(),
+                        value: value.into(),
+                    }),
+                _ => {}
+            }
+        }
+        violations
+    }
+
+    /// Check a document's full set of labels against the schema
+    pub fn validate(&self, labels: &[Label]) -> Result<(), MangoChainsawError> {
+        match self.violations(labels) {
+            violations if violations.is_empty() => Ok(()),
+            violations => Err(MangoChainsawError::LabelSchemaViolation(violations)),
+        }
+    }
+}
+
+/// A [JSON Schema](https://json-schema.org) the bodies of a bucket's documents have to match
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BodySchema(pub Value);
+
+/// One way a document's body breaks the bucket's [`BodySchema`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BodyViolation {
+    /// JSON pointer to the part of the body that failed, empty for the whole body
+    pub path: String,
+
+    /// JSON pointer to the schema keyword it failed
+    pub schema_path: String,
+
+    pub message: String,
+}
+
+impl fmt::Display for BodyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.is_empty() {
+            true => f.write_str(&self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+impl BodySchema {
+    /// Compile the schema, ready to check bodies against.
+    /// Fails with [`MangoChainsawError::InvalidSchema`] if it isn't a valid JSON Schema.
+    pub fn compile(&self) -> Result<BodyValidator, MangoChainsawError> {
+        jsonschema::validator_for(&self.0)
+            .map(BodyValidator)
+            .map_err(|e| MangoChainsawError::InvalidSchema(format!("body schema: {e}")))
+    }
+}
+
+/// A compiled [`BodySchema`]
+#[derive(Debug)]
+pub struct BodyValidator(Validator);
+
+impl BodyValidator {
+    /// List the ways a document's body breaks the schema
+    pub fn violations(&self, body: &Value) -> Vec<BodyViolation> {
+        self.0
+            .iter_errors(body)
+            .map(|e| BodyViolation {
+                path: e.instance_path().to_string(),
+                schema_path: e.schema_path().to_string(),
+                message: e.to_string(),
+            })
+            .collect()
+    }
+
+    /// Check a document's body against the schema
+    pub fn validate(&self, body: &Value) -> Result<(), MangoChainsawError> {
+        match self.violations(body) {
+            violations if violations.is_empty() => Ok(()),
+            violations => Err(MangoChainsawError::BodySchemaViolation(violations)),
+        }
+    }
+
+    /// Check a body encoded with `codec` against the schema.
+    /// A body the codec can't read fails as a violation of the whole body.
+    pub fn validate_encoded(
+        &self,
+        codec: CodecKind,
+        bytes: &[u8],
+    ) -> Result<(), MangoChainsawError> {
+        match codec.decode::<Value>(bytes) {
+            Ok(body) => self.validate(&body),
+            Err(e) => Err(MangoChainsawError::BodySchemaViolation(vec![
+                BodyViolation {
+                    path: String::new(),
+                    schema_path: String::new(),
+                    message: format!("body can't be read as {codec:?}: {e}"),
+                },
+            ])),
+        }
+    }
+}
+
+/// A bucket's schemas, compiled
+#[derive(Debug, Default)]
+pub(crate) struct Validators {
+    label_schema: Option<LabelSchema>,
+    body_schema: Option<BodySchema>,
+    pub(crate) labels: Option<LabelValidator>,
+    pub(crate) body: Option<BodyValidator>,
+}
+
+impl Validators {
+    fn compile(
+        label_schema: Option<&LabelSchema>,
+        body_schema: Option<&BodySchema>,
+    ) -> Result<Self, MangoChainsawError> {
+        Ok(Self {
+            label_schema: label_schema.cloned(),
+            body_schema: body_schema.cloned(),
+            labels: label_schema.map(|s| s.compile()).transpose()?,
+            body: body_schema.map(|s| s.compile()).transpose()?,
+        })
+    }
+
+    fn compiled_from(
+        &self,
+        label_schema: Option<&LabelSchema>,
+        body_schema: Option<&BodySchema>,
+    ) -> bool {
+        self.label_schema.as_ref() == label_schema && self.body_schema.as_ref() == body_schema
+    }
+}
+
+/// Compiled schemas by bucket, so that writes don't compile their bucket's schemas every time.
+/// An entry is only used while the bucket's schemas are the ones it was compiled from.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SchemaCache(Arc<Mutex<HashMap<String, Arc<Validators>>>>);
+
+impl SchemaCache {
+    /// Get the compiled schemas of a bucket, compiling them if they changed
+    pub(crate) fn get(
+        &self,
+        bucket: &str,
+        label_schema: Option<&LabelSchema>,
+        body_schema: Option<&BodySchema>,
+    ) -> Result<Arc<Validators>, MangoChainsawError> {
+        let mut cache = self
+            .0
+            .lock()
+            .map_err(|e| MangoChainsawError::Etc(format!("schema cache poisoned: {e}")))?;
+        match cache.get(bucket) {
+            Some(validators) if validators.compiled_from(label_schema, body_schema) => {
+                Ok(validators.clone())
+            }
+            _ => {
+                let validators = Arc::new(Validators::compile(label_schema, body_schema)?);
+                cache.insert(bucket.to_string(), validators.clone());
+                Ok(validators)
+            }
+        }
+    }
+}
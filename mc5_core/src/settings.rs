@@ -0,0 +1,93 @@
+use crate::codec::CodecKind;
+use crate::config::IdStrategy;
+use crate::errors::MangoChainsawError;
+use crate::schema::{BodySchema, LabelSchema};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Per-bucket settings, persisted alongside the bucket's trees
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BucketSettings {
+    /// Keep prior versions of documents when they are replaced or deleted
+    pub keep_history: bool,
+
+    /// Maximum number of prior versions kept per document. `None` keeps all of them.
+    pub max_versions: Option<usize>,
+
+    /// Time-to-live applied to documents inserted without an explicit one
+    pub default_ttl: Option<Duration>,
+
+    /// Codec new documents are written with. Changing it leaves existing documents as they are,
+    /// since every document is read with the codec that wrote it.
+    pub codec: CodecKind,
+
+    /// Store each distinct body once, shared by every document with the same content.
+    /// Turning it off only affects new writes; shared bodies stay shared.
+    pub dedup: bool,
+
+    /// Limits on what the bucket can hold. Writes that would go over them are rejected.
+    pub quota: Quota,
+
+    /// Rules the labels of new and relabelled documents have to follow.
+    /// Documents already in the bucket aren't checked when it changes.
+    pub label_schema: Option<LabelSchema>,
+
+    /// JSON Schema the bodies of new and replaced documents have to match.
+    /// Needs a JSON-compatible codec; bytes written as they are get read with the bucket's codec.
+    /// Documents already in the bucket aren't checked when it changes.
+    pub body_schema: Option<BodySchema>,
+
+    /// How ids are generated for the bucket's documents. Left out, a new bucket takes the
+    /// database's configured strategy. It can't change afterwards, since scans by creation
+    /// time read it back out of the ids.
+    pub id_strategy: Option<IdStrategy>,
+}
+
+impl BucketSettings {
+    /// Check that the settings can be used, such as that the schemas compile
+    pub fn validate(&self) -> Result<(), MangoChainsawError> {
+        if let Some(schema) = &self.label_schema {
+            schema.compile()?;
+        }
+        if let Some(schema) = &self.body_schema {
+            if !self.codec.is_json_compatible() {
+                return Err(MangoChainsawError::InvalidSchema(format!(
+                    "a body schema needs a JSON-compatible codec, not {:?}",
+                    self.codec
+                )));
+            }
+            schema.compile()?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-bucket limits. `None` leaves that limit off.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    /// Maximum number of documents, not counting archived versions
+    pub max_documents: Option<u64>,
+
+    /// Maximum total size of the documents' bytes, not counting archived versions
+    pub max_bytes: Option<u64>,
+
+    /// Maximum size of a single document's bytes
+    pub max_document_size: Option<u64>,
+
+    /// Maximum number of labels on a single document
+    pub max_labels_per_document: Option<u64>,
+
+    /// Maximum number of distinct labels across the bucket's documents
+    pub max_distinct_labels: Option<u64>,
+}
+
+impl Quota {
+    /// Whether a limit applies to the bucket as a whole, so its usage has to be counted
+    pub(crate) fn counts_usage(&self) -> bool {
+        self.max_documents.is_some()
+            || self.max_bytes.is_some()
+            || self.max_distinct_labels.is_some()
+    }
+}
@@ -0,0 +1,398 @@
+use crate::errors::MangoChainsawError;
+use sled::transaction::{
+    ConflictableTransactionResult, TransactionResult, UnabortableTransactionError,
+};
+use sled::IVec;
+use std::any::Any;
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+mod memory;
+mod sled_backend;
+
+pub use memory::MemoryBackend;
+pub use sled_backend::SledBackend;
+
+/// The body of a transaction, run against a view of each of its trees
+pub type TransactionFn<'a> =
+    dyn Fn(&[&dyn TransactionalTree]) -> ConflictableTransactionResult<(), MangoChainsawError> + 'a;
+
+/// A store of named, ordered key-value trees that buckets are built on
+pub trait StorageBackend: Debug + Send + Sync {
+    /// Open a tree, creating it if it doesn't exist
+    fn open_tree(&self, name: &str) -> Result<Tree, MangoChainsawError>;
+
+    /// Remove a tree and everything in it. Returns false if there was no such tree.
+    fn drop_tree(&self, name: &str) -> Result<bool, MangoChainsawError>;
+
+    /// Get the names of every tree
+    fn tree_names(&self) -> Vec<IVec>;
+
+    /// Get a unique, monotonically increasing id
+    fn generate_id(&self) -> Result<u64, MangoChainsawError>;
+
+    /// Run `f` against transactional views of `trees`, applying all of its writes or none.
+    /// Prefer the typed `transaction` on `dyn StorageBackend`.
+    fn transact(
+        &self,
+        trees: &[&Tree],
+        f: &TransactionFn,
+    ) -> TransactionResult<(), MangoChainsawError>;
+}
+
+impl dyn StorageBackend {
+    /// Run `f` in a transaction over a fixed set of trees, which it receives in the same order.
+    /// `f` may be called more than once if the backend retries the transaction.
+    pub fn transaction<const N: usize, R>(
+        &self,
+        trees: [&Tree; N],
+        f: impl Fn([&dyn TransactionalTree; N]) -> ConflictableTransactionResult<R, MangoChainsawError>,
+    ) -> Result<R, MangoChainsawError> {
+        let output = std::cell::RefCell::new(None);
+        self.transact(&trees, &|views| {
+            let views: [&dyn TransactionalTree; N] = views
+                .try_into()
+                .expect("backend passed the wrong number of trees to a transaction");
+            *output.borrow_mut() = Some(f(views)?);
+            Ok(())
+        })
+        .map_err(MangoChainsawError::from_tx)?;
+        Ok(output
+            .into_inner()
+            .expect("transaction committed without producing an output"))
+    }
+}
+
+/// One ordered key-value tree of a [`StorageBackend`]
+pub trait StorageTree: Debug + Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>, MangoChainsawError>;
+
+    fn insert(&self, key: &[u8], value: IVec) -> Result<Option<IVec>, MangoChainsawError>;
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>, MangoChainsawError>;
+
+    /// Iterate over the entries with keys between `start` and `end`, in key order
+    fn range(&self, start: Bound<IVec>, end: Bound<IVec>) -> Iter;
+
+    /// Atomically replace the value of `key` with a function of its current value,
+    /// returning the value it replaced. Returning None from `f` removes the key.
+    fn fetch_and_update(
+        &self,
+        key: &[u8],
+        f: &mut dyn FnMut(Option<&[u8]>) -> Option<IVec>,
+    ) -> Result<Option<IVec>, MangoChainsawError>;
+
+    /// Apply every write in a batch atomically
+    fn apply_batch(&self, batch: Batch) -> Result<(), MangoChainsawError>;
+
+    fn clear(&self) -> Result<(), MangoChainsawError>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A CRC32 over every key and value, for spotting differences between trees
+    fn checksum(&self) -> Result<u32, MangoChainsawError>;
+
+    /// Lets a backend get its own tree type back when running a transaction
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// The operations available on a tree inside a transaction
+pub trait TransactionalTree {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError>;
+
+    fn insert_bytes(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> Result<Option<IVec>, UnabortableTransactionError>;
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError>;
+}
+
+/// Sled-style generic helpers, so transactions read the same on every backend
+impl dyn TransactionalTree + '_ {
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, UnabortableTransactionError> {
+        self.get_bytes(key.as_ref())
+    }
+
+    pub fn insert<K, V>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<Option<IVec>, UnabortableTransactionError>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        self.insert_bytes(key.as_ref(), value.into())
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<IVec>, UnabortableTransactionError> {
+        self.remove_bytes(key.as_ref())
+    }
+}
+
+/// A shared handle to a tree, with sled-style generic helpers over [`StorageTree`]
+#[derive(Clone, Debug)]
+pub struct Tree(Arc<dyn StorageTree>);
+
+impl Tree {
+    pub fn new(tree: Arc<dyn StorageTree>) -> Self {
+        Self(tree)
+    }
+
+    /// Get the backend's own tree
+    pub fn inner(&self) -> &dyn StorageTree {
+        self.0.as_ref()
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, MangoChainsawError> {
+        self.0.get(key.as_ref())
+    }
+
+    pub fn insert<K, V>(&self, key: K, value: V) -> Result<Option<IVec>, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        self.0.insert(key.as_ref(), value.into())
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, MangoChainsawError> {
+        self.0.remove(key.as_ref())
+    }
+
+    pub fn range<K, R>(&self, range: R) -> Iter
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(k) => Bound::Included(IVec::from(k.as_ref())),
+            Bound::Excluded(k) => Bound::Excluded(IVec::from(k.as_ref())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.0
+            .range(owned(range.start_bound()), owned(range.end_bound()))
+    }
+
+    /// Iterate over every entry whose key starts with `prefix`
+    pub fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Iter {
+        let prefix = prefix.as_ref();
+        let end = match prefix_successor(prefix) {
+            Some(end) => Bound::Excluded(IVec::from(end)),
+            None => Bound::Unbounded,
+        };
+        self.0.range(Bound::Included(IVec::from(prefix)), end)
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.0.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Get the entry with the greatest key
+    pub fn last(&self) -> Result<Option<(IVec, IVec)>, MangoChainsawError> {
+        self.iter().next_back().transpose()
+    }
+
+    pub fn fetch_and_update<K, V, F>(
+        &self,
+        key: K,
+        mut f: F,
+    ) -> Result<Option<IVec>, MangoChainsawError>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+    {
+        self.0
+            .fetch_and_update(key.as_ref(), &mut |old| f(old).map(Into::into))
+    }
+
+    pub fn apply_batch(&self, batch: Batch) -> Result<(), MangoChainsawError> {
+        self.0.apply_batch(batch)
+    }
+
+    pub fn clear(&self) -> Result<(), MangoChainsawError> {
+        self.0.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn checksum(&self) -> Result<u32, MangoChainsawError> {
+        self.0.checksum()
+    }
+}
+
+/// The smallest key greater than every key that starts with `prefix`,
+/// or None if there is no such key
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// A set of writes to apply to a tree atomically
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    pub(crate) writes: Vec<(IVec, Option<IVec>)>,
+}
+
+impl Batch {
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<IVec>,
+        V: Into<IVec>,
+    {
+        self.writes.push((key.into(), Some(value.into())));
+    }
+
+    pub fn remove<K: Into<IVec>>(&mut self, key: K) {
+        self.writes.push((key.into(), None));
+    }
+}
+
+type Entry = Result<(IVec, IVec), MangoChainsawError>;
+
+/// An iterator over the entries of a tree, from either end
+pub struct Iter(Box<dyn DoubleEndedIterator<Item = Entry>>);
+
+impl Iter {
+    pub fn new(inner: impl DoubleEndedIterator<Item = Entry> + 'static) -> Self {
+        Self(Box::new(inner))
+    }
+
+    pub fn keys(self) -> impl DoubleEndedIterator<Item = Result<IVec, MangoChainsawError>> {
+        self.map(|entry| entry.map(|(key, _value)| key))
+    }
+
+    pub fn values(self) -> impl DoubleEndedIterator<Item = Result<IVec, MangoChainsawError>> {
+        self.map(|entry| entry.map(|(_key, value)| value))
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MangoChainsawConfig;
+    use sled::transaction::ConflictableTransactionError;
+
+    fn backends() -> Result<Vec<Arc<dyn StorageBackend>>, MangoChainsawError> {
+        Ok(vec![
+            Arc::new(MemoryBackend::new()),
+            Arc::new(SledBackend::open(&MangoChainsawConfig::default())?),
+        ])
+    }
+
+    fn keys(iter: impl Iterator<Item = Entry>) -> Result<Vec<String>, MangoChainsawError> {
+        iter.map(|entry| Ok(String::from_utf8_lossy(&entry?.0).into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_backends_agree() -> Result<(), MangoChainsawError> {
+        for storage in backends()? {
+            let (a, b) = (storage.open_tree("t::a")?, storage.open_tree("t::b")?);
+            for key in ["ab", "abc", "b", "a", "ab\u{ff}"] {
+                a.insert(key, key.as_bytes())?;
+            }
+            assert_eq!(keys(a.scan_prefix("ab"))?, vec!["ab", "abc", "ab\u{ff}"]);
+            assert_eq!(keys(a.range("ab".."b"))?, vec!["ab", "abc", "ab\u{ff}"]);
+            assert_eq!(keys(a.range("b".."a"))?, Vec::<String>::new());
+            assert_eq!(
+                keys(a.iter().rev())?,
+                vec!["b", "ab\u{ff}", "abc", "ab", "a"]
+            );
+            assert_eq!(a.last()?.map(|(k, _v)| k), Some(IVec::from("b")));
+
+            let old = a.fetch_and_update("a", |old| old.map(|v| [v, b"!"].concat()))?;
+            assert_eq!(old, Some(IVec::from("a")));
+            assert_eq!(a.get("a")?, Some(IVec::from("a!")));
+
+            // An aborted transaction leaves nothing behind
+            let result: Result<(), _> = storage.transaction([&a, &b], |[a, b]| {
+                a.remove("b")?;
+                b.insert("x", "1")?;
+                Err(ConflictableTransactionError::Abort(
+                    MangoChainsawError::Etc("abort".into()),
+                ))
+            });
+            assert!(matches!(result, Err(MangoChainsawError::Etc(_))));
+            assert_eq!((a.len(), b.len()), (5, 0));
+
+            // A transaction sees its own writes and commits all of them
+            let seen = storage.transaction([&a, &b], |[a, b]| {
+                a.remove("b")?;
+                b.insert("x", "1")?;
+                Ok((a.get("b")?, b.get("x")?))
+            })?;
+            assert_eq!(seen, (None, Some(IVec::from("1"))));
+            assert_eq!((a.len(), b.len()), (4, 1));
+
+            let names: Vec<IVec> = storage.tree_names();
+            assert!(names.contains(&IVec::from("t::b")));
+            assert!(storage.drop_tree("t::b")?);
+            assert!(!storage.tree_names().contains(&IVec::from("t::b")));
+            assert!(storage.generate_id()? < storage.generate_id()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_writes_wait_for_transactions() -> Result<(), MangoChainsawError> {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let tree = storage.open_tree("t::a")?;
+        tree.insert("n", "1")?;
+
+        // A write outside the transaction can't slip in between its read and its commit
+        let writer = std::cell::RefCell::new(None);
+        storage.transaction([&tree], |[t]| {
+            let n = t.get("n")?;
+            let tree = tree.clone();
+            writer.borrow_mut().get_or_insert_with(|| {
+                std::thread::spawn(move || {
+                    tree.fetch_and_update("n", |n| n.map(|n| [n, b"+"].concat()))
+                })
+            });
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            t.insert("n", [&n.unwrap()[..], b"0"].concat())?;
+            Ok(())
+        })?;
+        writer.into_inner().unwrap().join().unwrap()?;
+        assert_eq!(tree.get("n")?, Some(IVec::from("10+")));
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+use super::{
+    Batch, Entry, Iter, StorageBackend, StorageTree, TransactionFn, TransactionalTree, Tree,
+};
+use crate::errors::MangoChainsawError;
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionResult, UnabortableTransactionError,
+};
+use sled::IVec;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type Entries = BTreeMap<IVec, IVec>;
+
+/// Storage in `BTreeMap`s that lives only as long as the process.
+/// Transactions and writes outside them run one at a time, so they never conflict.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    trees: RwLock<BTreeMap<String, Arc<MemoryTree>>>,
+    next_id: AtomicU64,
+    writes: Arc<Mutex<()>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open_tree(&self, name: &str) -> Result<Tree, MangoChainsawError> {
+        let mut trees = self.trees.write().unwrap_or_else(PoisonError::into_inner);
+        let tree = trees
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(MemoryTree {
+                    entries: Arc::default(),
+                    writes: self.writes.clone(),
+                })
+            })
+            .clone();
+        Ok(Tree::new(tree))
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<bool, MangoChainsawError> {
+        let mut trees = self.trees.write().unwrap_or_else(PoisonError::into_inner);
+        match trees.remove(name) {
+            Some(tree) => {
+                // Handles that are still open see the tree as empty, as they do with sled
+                tree.clear()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn tree_names(&self) -> Vec<IVec> {
+        let trees = self.trees.read().unwrap_or_else(PoisonError::into_inner);
+        trees.keys().map(|name| IVec::from(name.as_str())).collect()
+    }
+
+    fn generate_id(&self) -> Result<u64, MangoChainsawError> {
+        Ok(self.next_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn transact(
+        &self,
+        trees: &[&Tree],
+        f: &TransactionFn,
+    ) -> TransactionResult<(), MangoChainsawError> {
+        let _running = self.writes.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut views = Vec::with_capacity(trees.len());
+        for tree in trees {
+            let tree = tree
+                .inner()
+                .as_any()
+                .downcast_ref::<MemoryTree>()
+                .ok_or_else(|| {
+                    sled::Error::Unsupported("tree is not from a memory backend".into())
+                })?;
+            views.push(MemoryTransactionalTree {
+                tree,
+                writes: RefCell::new(BTreeMap::new()),
+            });
+        }
+        let view_refs: Vec<&dyn TransactionalTree> = views
+            .iter()
+            .map(|view| view as &dyn TransactionalTree)
+            .collect();
+
+        match f(&view_refs) {
+            Ok(()) => {}
+            Err(ConflictableTransactionError::Abort(e)) => return Err(TransactionError::Abort(e)),
+            Err(ConflictableTransactionError::Storage(e)) => {
+                return Err(TransactionError::Storage(e))
+            }
+            Err(_) => {
+                return Err(TransactionError::Storage(sled::Error::ReportableBug(
+                    "conflict in a memory transaction".into(),
+                )))
+            }
+        }
+
+        // Lock every tree before writing any of them, always in the same order,
+        // so readers never see part of a transaction
+        views.sort_by_key(|view| view.tree as *const MemoryTree);
+        let mut locked: Vec<_> = views
+            .iter()
+            .map(|view| (view.tree.write(), &view.writes))
+            .collect();
+        for (entries, writes) in locked.iter_mut() {
+            for (key, value) in writes.take() {
+                match value {
+                    Some(value) => entries.insert(key, value),
+                    None => entries.remove(&key),
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One tree of a [`MemoryBackend`]
+#[derive(Debug)]
+pub struct MemoryTree {
+    entries: Arc<RwLock<Entries>>,
+
+    /// Shared with the backend, so a write outside a transaction
+    /// can't land between a transaction's reads and its commit
+    writes: Arc<Mutex<()>>,
+}
+
+impl MemoryTree {
+    fn read(&self) -> RwLockReadGuard<'_, Entries> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Entries> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write outside a transaction, once no transaction is running
+    fn write_alone<R>(&self, f: impl FnOnce(&mut Entries) -> R) -> R {
+        let _running = self.writes.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut self.write())
+    }
+}
+
+impl StorageTree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(self.read().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: IVec) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(self.write_alone(|entries| entries.insert(IVec::from(key), value)))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(self.write_alone(|entries| entries.remove(key)))
+    }
+
+    fn range(&self, start: Bound<IVec>, end: Bound<IVec>) -> Iter {
+        Iter::new(MemoryIter {
+            entries: self.entries.clone(),
+            start,
+            end,
+        })
+    }
+
+    fn fetch_and_update(
+        &self,
+        key: &[u8],
+        f: &mut dyn FnMut(Option<&[u8]>) -> Option<IVec>,
+    ) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(self.write_alone(|entries| {
+            let old = entries.get(key).cloned();
+            match f(old.as_deref()) {
+                Some(new) => entries.insert(IVec::from(key), new),
+                None => entries.remove(key),
+            };
+            old
+        }))
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), MangoChainsawError> {
+        self.write_alone(|entries| {
+            for (key, value) in batch.writes {
+                match value {
+                    Some(value) => entries.insert(key, value),
+                    None => entries.remove(&key),
+                };
+            }
+        });
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), MangoChainsawError> {
+        self.write_alone(Entries::clear);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    fn checksum(&self) -> Result<u32, MangoChainsawError> {
+        let mut hasher = crc32fast::Hasher::new();
+        for (key, value) in self.read().iter() {
+            hasher.update(key);
+            hasher.update(value);
+        }
+        Ok(hasher.finalize())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A tree inside a memory transaction. Writes are buffered until the transaction commits.
+struct MemoryTransactionalTree<'a> {
+    tree: &'a MemoryTree,
+    writes: RefCell<BTreeMap<IVec, Option<IVec>>>,
+}
+
+impl TransactionalTree for MemoryTransactionalTree<'_> {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError> {
+        match self.writes.borrow().get(key) {
+            Some(written) => Ok(written.clone()),
+            None => Ok(self.tree.read().get(key).cloned()),
+        }
+    }
+
+    fn insert_bytes(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> Result<Option<IVec>, UnabortableTransactionError> {
+        let old = self.get_bytes(key)?;
+        self.writes
+            .borrow_mut()
+            .insert(IVec::from(key), Some(value));
+        Ok(old)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError> {
+        let old = self.get_bytes(key)?;
+        self.writes.borrow_mut().insert(IVec::from(key), None);
+        Ok(old)
+    }
+}
+
+/// Iterates a memory tree without holding its lock between items,
+/// by narrowing its bounds past each entry it returns
+struct MemoryIter {
+    entries: Arc<RwLock<Entries>>,
+    start: Bound<IVec>,
+    end: Bound<IVec>,
+}
+
+impl MemoryIter {
+    /// `BTreeMap::range` panics on these, where an iterator should just be empty
+    fn is_exhausted(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    fn step(&mut self, from_back: bool) -> Option<Entry> {
+        if self.is_exhausted() {
+            return None;
+        }
+        let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+        let mut range = entries.range((self.start.clone(), self.end.clone()));
+        let (key, value) = match from_back {
+            false => range.next(),
+            true => range.next_back(),
+        }?;
+        let (key, value) = (key.clone(), value.clone());
+        drop(entries);
+
+        match from_back {
+            false => self.start = Bound::Excluded(key.clone()),
+            true => self.end = Bound::Excluded(key.clone()),
+        }
+        Some(Ok((key, value)))
+    }
+}
+
+impl Iterator for MemoryIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+impl DoubleEndedIterator for MemoryIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
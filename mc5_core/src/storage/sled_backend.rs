@@ -0,0 +1,142 @@
+use super::{Batch, Iter, StorageBackend, StorageTree, TransactionFn, Tree};
+use crate::config::MangoChainsawConfig;
+use crate::errors::MangoChainsawError;
+use sled::transaction::{TransactionResult, Transactional, UnabortableTransactionError};
+use sled::IVec;
+use std::any::Any;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// Storage in a sled database
+#[derive(Clone, Debug)]
+pub struct SledBackend {
+    db: sled::Db,
+}
+
+impl SledBackend {
+    /// Open the sled database described by a config
+    pub fn open(config: &MangoChainsawConfig) -> Result<Self, MangoChainsawError> {
+        Ok(Self::from(config.to_sled_config().open()?))
+    }
+
+    /// Get a tree of this backend back from a handle
+    fn sled_tree(tree: &Tree) -> Result<&sled::Tree, sled::Error> {
+        tree.inner()
+            .as_any()
+            .downcast_ref::<sled::Tree>()
+            .ok_or_else(|| sled::Error::Unsupported("tree is not from a sled backend".into()))
+    }
+}
+
+impl From<sled::Db> for SledBackend {
+    fn from(db: sled::Db) -> Self {
+        Self { db }
+    }
+}
+
+impl StorageBackend for SledBackend {
+    fn open_tree(&self, name: &str) -> Result<Tree, MangoChainsawError> {
+        Ok(Tree::new(Arc::new(self.db.open_tree(name)?)))
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<bool, MangoChainsawError> {
+        Ok(self.db.drop_tree(name)?)
+    }
+
+    fn tree_names(&self) -> Vec<IVec> {
+        self.db.tree_names()
+    }
+
+    fn generate_id(&self) -> Result<u64, MangoChainsawError> {
+        Ok(self.db.generate_id()?)
+    }
+
+    fn transact(
+        &self,
+        trees: &[&Tree],
+        f: &TransactionFn,
+    ) -> TransactionResult<(), MangoChainsawError> {
+        let mut sled_trees = Vec::with_capacity(trees.len());
+        for tree in trees {
+            sled_trees.push(Self::sled_tree(tree)?);
+        }
+        sled_trees.as_slice().transaction(|views| {
+            let views: Vec<&dyn super::TransactionalTree> = views
+                .iter()
+                .map(|view| view as &dyn super::TransactionalTree)
+                .collect();
+            f(&views)
+        })
+    }
+}
+
+impl StorageTree for sled::Tree {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(sled::Tree::get(self, key)?)
+    }
+
+    fn insert(&self, key: &[u8], value: IVec) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(sled::Tree::insert(self, key, value)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(sled::Tree::remove(self, key)?)
+    }
+
+    fn range(&self, start: Bound<IVec>, end: Bound<IVec>) -> Iter {
+        Iter::new(sled::Tree::range(self, (start, end)).map(|entry| Ok(entry?)))
+    }
+
+    fn fetch_and_update(
+        &self,
+        key: &[u8],
+        f: &mut dyn FnMut(Option<&[u8]>) -> Option<IVec>,
+    ) -> Result<Option<IVec>, MangoChainsawError> {
+        Ok(sled::Tree::fetch_and_update(self, key, f)?)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), MangoChainsawError> {
+        let mut sled_batch = sled::Batch::default();
+        for (key, value) in batch.writes {
+            match value {
+                Some(value) => sled_batch.insert(key, value),
+                None => sled_batch.remove(key),
+            }
+        }
+        Ok(sled::Tree::apply_batch(self, sled_batch)?)
+    }
+
+    fn clear(&self) -> Result<(), MangoChainsawError> {
+        Ok(sled::Tree::clear(self)?)
+    }
+
+    fn len(&self) -> usize {
+        sled::Tree::len(self)
+    }
+
+    fn checksum(&self) -> Result<u32, MangoChainsawError> {
+        Ok(sled::Tree::checksum(self)?)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl super::TransactionalTree for sled::transaction::TransactionalTree {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError> {
+        self.get(key)
+    }
+
+    fn insert_bytes(
+        &self,
+        key: &[u8],
+        value: IVec,
+    ) -> Result<Option<IVec>, UnabortableTransactionError> {
+        self.insert(key, value)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError> {
+        self.remove(key)
+    }
+}
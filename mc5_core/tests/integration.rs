@@ -1,6 +1,9 @@
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(windows)]
+use std::os::windows::fs::MetadataExt;
 use std::{
     hash::{DefaultHasher, Hash, Hasher},
-    os::windows::fs::MetadataExt,
     path::PathBuf,
     str::FromStr,
 };
@@ -46,7 +49,10 @@ impl TryFrom<DirEntry> for TestFile {
             (contents, hasher.finish())
         };
         let path = entry.path().to_path_buf();
+        #[cfg(windows)]
         let attrs = entry.metadata()?.file_attributes();
+        #[cfg(unix)]
+        let attrs = entry.metadata()?.mode();
         let size = contents.len() as u64;
         let mut is_code = false;
         let mut filetype = "something_else".to_string();
@@ -122,7 +128,7 @@ fn setup() -> Result<MangoChainsaw> {
 fn build_dataset(backend: MangoChainsaw) -> Result<()> {
     let path = PathBuf::from_str("../")?.canonicalize()?;
 
-    let test_bucket = backend.get_bucket("testing")?;
+    let test_bucket = backend.get_or_create_bucket("testing")?;
 
     // Walk the repo adding all files into the bucket.
     let wd = WalkDir::new(path)
@@ -130,22 +136,23 @@ fn build_dataset(backend: MangoChainsaw) -> Result<()> {
         .same_file_system(true)
         .max_depth(10);
 
-    let mut total = 0;
+    let mut batch = vec![];
     for entry in wd {
         let entry = entry?;
-        let path = entry.path().to_str().unwrap();
-        if entry.path().is_dir() || path.contains("target\\") || path.contains(".git\\") {
+        let skip = entry
+            .path()
+            .components()
+            .any(|c| c.as_os_str() == "target" || c.as_os_str() == ".git");
+        if entry.path().is_dir() || skip {
             continue;
         }
         let tf: TestFile = entry.try_into()?;
-        let (labels, doc) = (tf.to_labels(), tf.data);
-        let id = test_bucket.insert(doc, labels)?;
-        
-        info!(id = id.to_string(), "Inserted document {total}");
-        total += 1;
+        let labels = tf.to_labels();
+        batch.push((tf.data, labels));
     }
 
-    info!("Inserted {total} documents");
+    let ids = test_bucket.insert_many(batch)?;
+    info!("Inserted {} documents", ids.len());
 
     Ok(())
 }
@@ -1,6 +1,7 @@
 use figment::{
     providers::{Format, YamlExtended}, value::{Dict, Map}, Figment, Metadata, Profile, Provider
 };
+use mc5_core::errors::MangoChainsawError;
 use serde::{Deserialize, Serialize};
 use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, str::FromStr};
 
@@ -44,29 +45,29 @@ impl Provider for Config {
     }
 }
 
-impl Into<sled::Config> for Config {
-    fn into(self) -> sled::Config {
-        let config = sled::Config::new()
-            .mode(match self.backend_mode {
+impl From<Config> for sled::Config {
+    fn from(config: Config) -> Self {
+        sled::Config::new()
+            .mode(match config.backend_mode {
                 BackendMode::Fast => sled::Mode::HighThroughput,
                 BackendMode::Small => sled::Mode::LowSpace,
             })
-            .temporary(self.temporary)
-            .idgen_persist_interval(self.idgen_interval)
-            .path(self.data_path)
-            .use_compression(self.compression_factor > 0)
-            .compression_factor(self.compression_factor);
-        config
+            .temporary(config.temporary)
+            .idgen_persist_interval(config.idgen_interval)
+            .path(config.data_path)
+            .use_compression(config.compression_factor > 0)
+            .compression_factor(config.compression_factor)
     }
 }
 
 impl Config {
     /// Load config profile
-    pub fn load<P: AsRef<Path>>(path: P, profile: &str) -> Result<Self, figment::Error> {
+    pub fn load<P: AsRef<Path>>(path: P, profile: &str) -> Result<Self, MangoChainsawError> {
         Figment::new()
             .merge(YamlExtended::file(path).nested())
             .select(profile)
             .extract()
+            .map_err(MangoChainsawError::from)
             
     }
 }
\ No newline at end of file
@@ -1,15 +1,27 @@
-use anyhow::anyhow;
 use axum::body::{Body, Bytes};
-use axum::extract::{Path, Query, Request, State};
-use axum::http::{HeaderMap, Response, StatusCode};
-use axum::response::{IntoResponse, Json};
-use axum::routing::{delete, get};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, post};
 use axum::Router;
+use futures::StreamExt;
+use mc5_core::bucket::MangoChainsawBucket;
+use mc5_core::errors::MangoChainsawError;
 use mc5_core::label::Label;
+use mc5_core::large::{LargeObjectWriter, RawStream};
 use mc5_core::mango::MangoChainsaw;
 use mc5_core::mclabel;
-use std::collections::{HashMap, HashSet};
+use mc5_core::metadata::DocumentMetadata;
+use mc5_core::page::{Cursor, KeyPage, Page};
+use mc5_core::query::Query as LabelQuery;
+use mc5_core::schema::{BodySchema, LabelSchema};
+use mc5_core::settings::BucketSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
 use std::str::FromStr;
+use tokio::sync::mpsc;
 use tracing::{info, instrument};
 use uuid::Uuid;
 
@@ -19,22 +31,50 @@ pub struct MangoChainsawServer {}
 impl MangoChainsawServer {
     #[instrument(skip(backend))]
     pub async fn run(backend: MangoChainsaw) -> Result<(), anyhow::Error> {
-        let app = Router::new()
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:1420").await?;
+        axum::serve(listener, Self::router(backend)).await?;
+
+        Ok(())
+    }
+
+    /// Build the server's routes over a store
+    pub fn router(backend: MangoChainsaw) -> Router {
+        Router::new()
             .route("/buckets", get(Self::list_buckets))
             .route(
                 "/buckets/:bucket",
                 get(Self::stat_bucket)
+                    .put(Self::create_bucket)
                     .post(Self::insert_document)
                     .delete(Self::drop_bucket),
             )
-            .route("/buckets/:bucket/:id", get(Self::get_document))
+            .route("/buckets/:bucket/rename", post(Self::rename_bucket))
+            .route("/buckets/:bucket/copy", post(Self::copy_bucket))
+            .route(
+                "/buckets/:bucket/schema",
+                get(Self::get_label_schema).put(Self::put_label_schema),
+            )
+            .route(
+                "/buckets/:bucket/body_schema",
+                get(Self::get_body_schema).put(Self::put_body_schema),
+            )
+            .route("/buckets/:bucket/docs", get(Self::list_documents))
+            .route("/buckets/:bucket/keys", get(Self::list_keys))
+            .route(
+                "/buckets/:bucket/keys/*key",
+                get(Self::get_keyed_document)
+                    .head(Self::head_keyed_document)
+                    .put(Self::put_keyed_document)
+                    .delete(Self::delete_keyed_document),
+            )
+            .route(
+                "/buckets/:bucket/:id",
+                get(Self::get_document)
+                    .head(Self::head_document)
+                    .put(Self::replace_document),
+            )
             .route("/query/:bucket", get(Self::find_documents))
-            .with_state(backend);
-
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:1420").await?;
-        axum::serve(listener, app).await?;
-
-        Ok(())
+            .with_state(backend)
     }
 
     #[instrument(skip(backend), ret)]
@@ -45,6 +85,93 @@ impl MangoChainsawServer {
         Ok((StatusCode::OK, Json(backend.list_buckets()?)))
     }
 
+    /// Create a bucket, with the settings in a JSON body or the defaults if there is none
+    #[instrument(skip(backend))]
+    async fn create_bucket(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        settings: Result<Json<BucketSettings>, JsonRejection>,
+    ) -> Result<Response, ServerError> {
+        let settings = match settings {
+            Ok(Json(settings)) => settings,
+            Err(JsonRejection::MissingJsonContentType(_)) => BucketSettings::default(),
+            Err(rejection) => return Ok(rejection.into_response()),
+        };
+        info!("Creating bucket");
+        backend.create_bucket(&bucket, settings)?;
+        Ok((StatusCode::CREATED, Json(backend.bucket_info(&bucket)?)).into_response())
+    }
+
+    /// Rename a bucket to `to=`
+    #[instrument(skip(backend))]
+    async fn rename_bucket(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Query(target): Query<TargetParams>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        info!("Renaming bucket");
+        backend.rename_bucket(&bucket, &target.to)?;
+        Ok((StatusCode::OK, Json(backend.bucket_info(&target.to)?)))
+    }
+
+    /// Copy a bucket to `to=`, giving the copies new ids unless `keep_ids=true`
+    #[instrument(skip(backend))]
+    async fn copy_bucket(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Query(target): Query<TargetParams>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        info!("Copying bucket");
+        backend.copy_bucket(&bucket, &target.to, target.keep_ids)?;
+        Ok((StatusCode::CREATED, Json(backend.bucket_info(&target.to)?)))
+    }
+
+    /// Get the rules a bucket's labels have to follow, or null if it has none
+    #[instrument(skip(backend))]
+    async fn get_label_schema(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        Ok((StatusCode::OK, Json(bucket.label_schema()?)))
+    }
+
+    /// Replace the rules a bucket's labels have to follow, or remove them with null
+    #[instrument(skip(backend))]
+    async fn put_label_schema(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Json(schema): Json<Option<LabelSchema>>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        info!("Updating label schema");
+        let bucket = backend.get_bucket(&bucket)?;
+        bucket.set_label_schema(schema)?;
+        Ok((StatusCode::OK, Json(bucket.label_schema()?)))
+    }
+
+    /// Get the JSON Schema a bucket's document bodies have to match, or null if it has none
+    #[instrument(skip(backend))]
+    async fn get_body_schema(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        Ok((StatusCode::OK, Json(bucket.body_schema()?)))
+    }
+
+    /// Replace the JSON Schema a bucket's document bodies have to match, or remove it with null
+    #[instrument(skip(backend))]
+    async fn put_body_schema(
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Json(schema): Json<Option<BodySchema>>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        info!("Updating body schema");
+        let bucket = backend.get_bucket(&bucket)?;
+        bucket.set_body_schema(schema)?;
+        Ok((StatusCode::OK, Json(bucket.body_schema()?)))
+    }
+
     #[instrument(skip(backend))]
     async fn drop_bucket(
         headers: HeaderMap,
@@ -72,15 +199,17 @@ impl MangoChainsawServer {
         Path(bucket): Path<String>,
         State(backend): State<MangoChainsaw>,
         Query(params): Query<HashMap<String, String>>,
-        body: Bytes,
+        body: Body,
     ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
         let bucket = backend.get_bucket(&bucket)?;
         let labels = params
             .into_iter()
             .map(|(k, v)| mclabel!(&k => &v))
             .collect();
-        let id = bucket.insert(body.to_vec(), labels)?;
-        Ok((StatusCode::OK, id.as_bytes().to_vec()))
+        match upload(bucket.write_large(labels), &headers, body).await? {
+            Some((id, _rev)) => Ok((StatusCode::OK, id.as_bytes().to_vec())),
+            None => Ok((StatusCode::NOT_FOUND, vec![])),
+        }
     }
 
     #[instrument(skip(backend))]
@@ -88,24 +217,489 @@ impl MangoChainsawServer {
         headers: HeaderMap,
         Path((bucket, id)): Path<(String, String)>,
         State(backend): State<MangoChainsaw>,
-    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+    ) -> Result<Response, ServerError> {
         let bucket = backend.get_bucket(&bucket)?;
         let id = Uuid::from_str(&id)?;
-        if let Some(doc) = bucket.get::<Vec<u8>>(id)? {
-            Ok((StatusCode::OK, doc))
-        } else {
-            Ok((StatusCode::NOT_FOUND, vec![]))
+        let meta = bucket.metadata(id)?;
+        match bucket.stream_bytes_with_revision(id)? {
+            Some((stream, rev)) => download(stream, rev, meta.as_ref(), &headers),
+            None => Ok(StatusCode::NOT_FOUND.into_response()),
         }
     }
 
-    #[instrument(skip(backend), ret)]
-    async fn find_documents(headers: HeaderMap, Path(bucket): Path<String>, State(backend): State<MangoChainsaw>, Query(params): Query<HashMap<String, String>>) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+    /// Describe a document in headers, without sending it
+    #[instrument(skip(backend))]
+    async fn head_document(
+        Path((bucket, id)): Path<(String, String)>,
+        State(backend): State<MangoChainsaw>,
+    ) -> Result<Response, ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let id = Uuid::from_str(&id)?;
+        match bucket.metadata(id)? {
+            Some(meta) => Ok((StatusCode::OK, describe(&meta)?).into_response()),
+            None => Ok(StatusCode::NOT_FOUND.into_response()),
+        }
+    }
+
+    #[instrument(skip(backend, body))]
+    async fn replace_document(
+        headers: HeaderMap,
+        Path((bucket, id)): Path<(String, String)>,
+        State(backend): State<MangoChainsaw>,
+        Query(params): Query<HashMap<String, String>>,
+        body: Body,
+    ) -> Result<(StatusCode, HeaderMap, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let id = Uuid::from_str(&id)?;
+        let labels: Vec<Label> = params
+            .into_iter()
+            .map(|(k, v)| mclabel!(&k => &v))
+            .collect();
+
+        let labels = (!labels.is_empty()).then_some(labels);
+
+        // The write is made conditional on the revision that matched, so a document that
+        // changes in the meantime still fails the precondition
+        let expected = match if_match(&headers) {
+            Some(condition) => match bucket.revision(id)? {
+                Some(rev) if condition.matches(rev) => Some(rev),
+                _ => {
+                    info!("If-Match doesn't match the current revision");
+                    return Ok((StatusCode::PRECONDITION_FAILED, HeaderMap::new(), vec![]));
+                }
+            },
+            None => None,
+        };
+
+        match upload(bucket.replace_large(id, expected, labels), &headers, body).await? {
+            Some((id, rev)) => Ok((StatusCode::OK, etag(rev)?, id.as_bytes().to_vec())),
+            None => Ok((StatusCode::NOT_FOUND, HeaderMap::new(), vec![])),
+        }
+    }
+
+    /// List the ids of the documents in a bucket, a page at a time
+    #[instrument(skip(backend))]
+    async fn list_documents(
+        headers: HeaderMap,
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Query(params): Query<PageParams>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let page = bucket.scan(params.cursor()?.as_ref(), params.limit())?;
+        Ok((StatusCode::OK, Json(PageResponse::from(page))))
+    }
+
+    /// List the keys in a bucket that start with `prefix=`, a page at a time
+    #[instrument(skip(backend))]
+    async fn list_keys(
+        headers: HeaderMap,
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Query(prefix): Query<PrefixParams>,
+        Query(params): Query<PageParams>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let prefix = prefix.prefix.unwrap_or_default();
+        let page = bucket.scan_keys(&prefix, params.cursor()?.as_ref(), params.limit())?;
+        Ok((StatusCode::OK, Json(KeyPageResponse::from(page))))
+    }
+
+    #[instrument(skip(backend))]
+    async fn get_keyed_document(
+        headers: HeaderMap,
+        Path((bucket, key)): Path<(String, String)>,
+        State(backend): State<MangoChainsaw>,
+    ) -> Result<Response, ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let Some(id) = bucket.key_id(&key)? else {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        };
+        let meta = bucket.metadata(id)?;
+        match bucket.stream_bytes_with_revision(id)? {
+            Some((stream, rev)) => download(stream, rev, meta.as_ref(), &headers),
+            None => Ok(StatusCode::NOT_FOUND.into_response()),
+        }
+    }
+
+    /// Describe the document stored under a key in headers, without sending it
+    #[instrument(skip(backend))]
+    async fn head_keyed_document(
+        Path((bucket, key)): Path<(String, String)>,
+        State(backend): State<MangoChainsaw>,
+    ) -> Result<Response, ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let meta = match bucket.key_id(&key)? {
+            Some(id) => bucket.metadata(id)?,
+            None => None,
+        };
+        match meta {
+            Some(meta) => Ok((StatusCode::OK, describe(&meta)?).into_response()),
+            None => Ok(StatusCode::NOT_FOUND.into_response()),
+        }
+    }
+
+    /// Store a document under a key, replacing the document and labels already there
+    #[instrument(skip(backend, body))]
+    async fn put_keyed_document(
+        headers: HeaderMap,
+        Path((bucket, key)): Path<(String, String)>,
+        State(backend): State<MangoChainsaw>,
+        Query(params): Query<HashMap<String, String>>,
+        body: Body,
+    ) -> Result<(StatusCode, HeaderMap, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        let labels = params
+            .into_iter()
+            .map(|(k, v)| mclabel!(&k => &v))
+            .collect();
+        match upload(bucket.put_large(&key, labels), &headers, body).await? {
+            Some((id, rev)) => Ok((StatusCode::OK, etag(rev)?, id.as_bytes().to_vec())),
+            None => Ok((StatusCode::NOT_FOUND, HeaderMap::new(), vec![])),
+        }
+    }
+
+    #[instrument(skip(backend))]
+    async fn delete_keyed_document(
+        headers: HeaderMap,
+        Path((bucket, key)): Path<(String, String)>,
+        State(backend): State<MangoChainsaw>,
+    ) -> Result<(StatusCode, impl IntoResponse), ServerError> {
+        let bucket = backend.get_bucket(&bucket)?;
+        match bucket.key_id(&key)? {
+            Some(id) if bucket.remove(id)? => Ok((StatusCode::OK, key)),
+            _ => Ok((StatusCode::NOT_FOUND, String::new())),
+        }
+    }
+
+    /// Find documents by label. Plain `key=value` params must all match, and each
+    /// `selector=` param is a Kubernetes-style label selector that must match too.
+    /// With `limit=` or `after=`, the result is a page of ids and a token for the next page
+    /// rather than every id. Those two keys are reserved for paging, so labels named
+    /// `limit` or `after` have to be matched with `selector=`; a bucket that has such labels
+    /// rejects the plain params as ambiguous. A query without any labels finds nothing.
+    #[instrument(skip(backend))]
+    async fn find_documents(
+        headers: HeaderMap,
+        Path(bucket): Path<String>,
+        State(backend): State<MangoChainsaw>,
+        Query(params): Query<Vec<(String, String)>>,
+    ) -> Result<Response, ServerError> {
         let bucket = backend.get_bucket(&bucket)?;
-        let labels: Vec<Label> = params.into_iter().map(|(k, v)| mclabel!(&k => &v)).collect();
-        let ids: Vec<String> = bucket.search_inclusive(labels)?
-            .into_iter().map(|id| id.to_string()).collect();
-        Ok((StatusCode::OK, Json(ids)))
+        let mut queries = vec![];
+        let mut page_params = PageParams::default();
+        for (k, v) in params {
+            match k.as_str() {
+                "selector" => queries.push(LabelQuery::parse_selector(&v)?),
+                "limit" | "after" if has_label(&bucket, &k)? => {
+                    return Err(MangoChainsawError::InvalidQuery {
+                        offset: 0,
+                        message: format!(
+                            "{k}= is reserved for paging, match the {k} label with selector="
+                        ),
+                    }
+                    .into());
+                }
+                "limit" => page_params.limit = Some(v.parse()?),
+                "after" => page_params.after = Some(v),
+                _ => queries.push(LabelQuery::eq(&k, &v)),
+            }
+        }
+        let query = LabelQuery::And(queries);
+        let paged = page_params.limit.is_some() || page_params.after.is_some();
+
+        // A query without any labels finds nothing, whether or not it is paged
+        if query == LabelQuery::And(vec![]) {
+            if !paged {
+                return Ok((StatusCode::OK, Json(Vec::<String>::new())).into_response());
+            }
+            page_params.cursor()?;
+            let page = PageResponse {
+                ids: vec![],
+                next: None,
+            };
+            return Ok((StatusCode::OK, Json(page)).into_response());
+        }
+        if paged {
+            let page = bucket.query_page(
+                &query,
+                page_params.cursor()?.as_ref(),
+                page_params.limit(),
+            )?;
+            return Ok((StatusCode::OK, Json(PageResponse::from(page))).into_response());
+        }
+        let ids: Vec<String> = bucket
+            .query(&query)?
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect();
+        Ok((StatusCode::OK, Json(ids)).into_response())
+    }
+}
+
+/// Whether any document in the bucket has a label named `key`
+fn has_label(bucket: &MangoChainsawBucket, key: &str) -> Result<bool, MangoChainsawError> {
+    let labels = bucket.label_name_search(key)?;
+    Ok(labels.iter().any(|label| label.key() == key))
+}
+
+/// Page size used when a listing doesn't ask for one
+const DEFAULT_PAGE_LIMIT: usize = 100;
+
+/// Largest page a listing can ask for
+const MAX_PAGE_LIMIT: usize = 10_000;
+
+/// Query params for paginated listings
+#[derive(Debug, Default, Deserialize)]
+struct PageParams {
+    limit: Option<usize>,
+    after: Option<String>,
+}
+
+impl PageParams {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT)
+    }
+
+    fn cursor(&self) -> Result<Option<Cursor>, ServerError> {
+        match &self.after {
+            Some(after) => Ok(Some(after.parse()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Query params for listing keys
+#[derive(Debug, Default, Deserialize)]
+struct PrefixParams {
+    prefix: Option<String>,
+}
+
+/// Query params for renaming and copying buckets
+#[derive(Debug, Deserialize)]
+struct TargetParams {
+    to: String,
+    #[serde(default)]
+    keep_ids: bool,
+}
+
+/// A page of document keys, and the `after` token for the next page if there is one
+#[derive(Debug, Serialize)]
+struct KeyPageResponse {
+    keys: Vec<String>,
+    next: Option<String>,
+}
+
+impl From<KeyPage> for KeyPageResponse {
+    fn from(page: KeyPage) -> Self {
+        Self {
+            keys: page
+                .keys
+                .iter()
+                .map(|key| String::from_utf8_lossy(key).into_owned())
+                .collect(),
+            next: page.next.map(|cursor| cursor.to_string()),
+        }
+    }
+}
+
+/// A page of document ids, and the `after` token for the next page if there is one
+#[derive(Debug, Serialize)]
+struct PageResponse {
+    ids: Vec<String>,
+    next: Option<String>,
+}
+
+impl From<Page> for PageResponse {
+    fn from(page: Page) -> Self {
+        Self {
+            ids: page.ids.into_iter().map(|id| id.to_string()).collect(),
+            next: page.next.map(|cursor| cursor.to_string()),
+        }
+    }
+}
+
+/// Number of request body frames that can wait for the store to catch up
+const UPLOAD_QUEUE: usize = 16;
+
+/// Copy a request body into a writer as it arrives, then store it
+/// along with its `Content-Type`
+async fn upload(
+    mut writer: LargeObjectWriter,
+    request: &HeaderMap,
+    body: Body,
+) -> Result<Option<(Uuid, u64)>, ServerError> {
+    if let Some(content_type) = request.get(header::CONTENT_TYPE) {
+        writer = writer.with_content_type(content_type.to_str()?);
+    }
+    // Writing to the store blocks, so it happens on a blocking thread fed the body's frames.
+    // `None` marks the end of the body; if the frames stop without it, the writer is dropped
+    // and what it wrote is thrown away.
+    let (frames, mut received) = mpsc::channel::<Option<Bytes>>(UPLOAD_QUEUE);
+    let store = tokio::task::spawn_blocking(move || -> Result<_, anyhow::Error> {
+        while let Some(frame) = received.blocking_recv() {
+            match frame {
+                Some(bytes) => writer.write_all(&bytes)?,
+                None => return Ok(writer.finish()?),
+            }
+        }
+        Err(anyhow::anyhow!("upload ended before the body did"))
+    });
+    let mut stream = body.into_data_stream();
+    loop {
+        let frame = match stream.next().await.transpose() {
+            Ok(frame) => frame,
+            Err(e) => {
+                // Let the writer throw away what it wrote before answering
+                drop(frames);
+                let _ = store.await;
+                return Err(e.into());
+            }
+        };
+        let end = frame.is_none();
+        // The writer only stops listening once it has failed, which `store` reports
+        if frames.send(frame).await.is_err() || end {
+            break;
+        }
+    }
+    Ok(store.await??)
+}
+
+/// Stream the bytes of a document, or the part a `Range` header asks for.
+/// The body shares the stored bytes rather than copying them.
+fn download(
+    stream: RawStream,
+    rev: u64,
+    meta: Option<&DocumentMetadata>,
+    request: &HeaderMap,
+) -> Result<Response, ServerError> {
+    let len = stream.len();
+    let mut headers = etag(rev)?;
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(meta) = meta {
+        content_headers(&mut headers, meta)?;
+    }
+    let (status, stream) = match byte_range(request, len) {
+        None => (StatusCode::OK, stream),
+        Some(Some((start, end))) => {
+            let range = format!("bytes {start}-{end}/{len}");
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&range)?);
+            (StatusCode::PARTIAL_CONTENT, stream.range(start..=end))
+        }
+        Some(None) => {
+            let range = format!("bytes */{len}");
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&range)?);
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+    };
+    let remaining = HeaderValue::from(stream.remaining());
+    headers.insert(header::CONTENT_LENGTH, remaining);
+    let slices = stream.map(|slice| slice.map(Bytes::from_owner));
+    let body = Body::from_stream(futures::stream::iter(slices));
+    Ok((status, headers, body).into_response())
+}
+
+/// Read a single `bytes=` range from a `Range` header, as inclusive offsets into a document
+/// of `len` bytes. None means there is no range to honour, so the whole document is sent,
+/// and `Some(None)` means the range is past the end of the document.
+fn byte_range(headers: &HeaderMap, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = headers.get(header::RANGE)?.to_str().ok()?.trim();
+    let spec = spec.strip_prefix("bytes=")?;
+    // Multiple ranges would need a multipart response, and sending everything is allowed
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let n: u64 = suffix.parse().ok()?;
+            (n > 0 && len > 0).then(|| (len.saturating_sub(n), len - 1))
+        }
+        (start, "") => {
+            let start: u64 = start.parse().ok()?;
+            (start < len).then(|| (start, len - 1))
+        }
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if start > end {
+                return None;
+            }
+            (start < len).then(|| (start, end.min(len - 1)))
+        }
+    };
+    Some(range)
+}
+
+/// Describe a document the way a full response would, for a `HEAD` request
+fn describe(meta: &DocumentMetadata) -> Result<HeaderMap, ServerError> {
+    let mut headers = etag(meta.revision)?;
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(meta.size));
+    content_headers(&mut headers, meta)?;
+    Ok(headers)
+}
+
+/// Add the `Content-Type` and `Last-Modified` headers for a document
+fn content_headers(headers: &mut HeaderMap, meta: &DocumentMetadata) -> Result<(), ServerError> {
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(meta.media_type())?,
+    );
+    let modified = httpdate::fmt_http_date(meta.modified);
+    headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(&modified)?);
+    Ok(())
+}
+
+/// Build the `ETag` header for a document revision
+fn etag(rev: u64) -> Result<HeaderMap, ServerError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, HeaderValue::from_str(&format!("\"{rev}\""))?);
+    Ok(headers)
+}
+
+/// The revisions an `If-Match` header accepts
+#[derive(Debug)]
+enum IfMatch {
+    /// `*`, any revision of an existing document
+    Any,
+
+    /// A list of entity tags. Tags that aren't a revision, weak ones included,
+    /// are left out since they can never match.
+    Revisions(Vec<u64>),
+}
+
+impl IfMatch {
+    fn matches(&self, rev: u64) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Revisions(revisions) => revisions.contains(&rev),
+        }
+    }
+}
+
+/// Read the `If-Match` headers of a request, if it has any
+fn if_match(headers: &HeaderMap) -> Option<IfMatch> {
+    let values = headers.get_all(header::IF_MATCH);
+    values.iter().next()?;
+    let mut revisions = vec![];
+    for value in values.iter().filter_map(|v| v.to_str().ok()) {
+        for tag in value.split(',').map(str::trim) {
+            if tag == "*" {
+                return Some(IfMatch::Any);
+            }
+            if let Ok(rev) = tag.trim_matches('"').parse() {
+                revisions.push(rev);
+            }
+        }
     }
+    Some(IfMatch::Revisions(revisions))
+}
+
+/// What was wrong with a document that doesn't match its bucket's schema
+#[derive(Debug, Serialize)]
+struct ViolationResponse<'a, V> {
+    error: String,
+    violations: &'a [V],
 }
 
 #[derive(Debug)]
@@ -113,11 +707,48 @@ struct ServerError(anyhow::Error);
 
 impl IntoResponse for ServerError {
     fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Whoopsie {}", self.0),
-        )
-            .into_response()
+        // Errors from writing a document body arrive wrapped in an io::Error
+        let error = self.0.downcast_ref::<MangoChainsawError>().or_else(|| {
+            self.0
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.get_ref())
+                .and_then(|e| e.downcast_ref::<MangoChainsawError>())
+        });
+        let status = match error {
+            Some(MangoChainsawError::Conflict { .. }) => StatusCode::PRECONDITION_FAILED,
+            Some(MangoChainsawError::InvalidQuery { .. }) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::InvalidCursor(_)) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::DuplicateId(_)) => StatusCode::CONFLICT,
+            Some(MangoChainsawError::DuplicateKey(_)) => StatusCode::CONFLICT,
+            Some(MangoChainsawError::IdRequired) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::InvalidBucketName { .. }) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::BucketNotFound(_)) => StatusCode::NOT_FOUND,
+            Some(MangoChainsawError::BucketExists(_)) => StatusCode::CONFLICT,
+            Some(MangoChainsawError::QuotaExceeded { limit, .. }) if limit.per_document() => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Some(MangoChainsawError::QuotaExceeded { .. }) => StatusCode::INSUFFICIENT_STORAGE,
+            Some(MangoChainsawError::IdStrategyFixed(_)) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::KeepIdsRequired(_)) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::InvalidSchema(_)) => StatusCode::BAD_REQUEST,
+            Some(MangoChainsawError::LabelSchemaViolation(violations)) => {
+                let body = ViolationResponse {
+                    error: self.0.to_string(),
+                    violations,
+                };
+                return (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response();
+            }
+            Some(MangoChainsawError::BodySchemaViolation(violations)) => {
+                let body = ViolationResponse {
+                    error: self.0.to_string(),
+                    violations,
+                };
+                return (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response();
+            }
+            _ if self.0.is::<std::num::ParseIntError>() => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, format!("Whoopsie {}", self.0)).into_response()
     }
 }
 
@@ -129,3 +760,205 @@ where
         Self(err.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use mc5_core::config::MangoChainsawConfig;
+    use mc5_core::large::CHUNK_SIZE;
+    use tower::ServiceExt;
+
+    fn test_db() -> Result<MangoChainsaw, MangoChainsawError> {
+        MangoChainsaw::new(MangoChainsawConfig::default())
+    }
+
+    async fn send(
+        backend: &MangoChainsaw,
+        request: Request<Body>,
+    ) -> Result<(StatusCode, Bytes), anyhow::Error> {
+        let response = MangoChainsawServer::router(backend.clone())
+            .oneshot(request)
+            .await?;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        Ok((status, body))
+    }
+
+    fn get(uri: &str) -> Result<Request<Body>, anyhow::Error> {
+        Ok(Request::get(uri).body(Body::empty())?)
+    }
+
+    #[tokio::test]
+    async fn test_write_needs_created_bucket() -> Result<(), anyhow::Error> {
+        let db = test_db()?;
+        let post = || Request::post("/buckets/typo?kind=note").body(Body::from("hello"));
+        let put = || Request::put("/buckets/typo/keys/a").body(Body::from("hello"));
+        assert_eq!(send(&db, post()?).await?.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&db, put()?).await?.0, StatusCode::NOT_FOUND);
+        assert!(!db.bucket_exists("typo")?);
+
+        let create = Request::put("/buckets/typo").body(Body::empty())?;
+        assert_eq!(send(&db, create).await?.0, StatusCode::CREATED);
+        assert_eq!(send(&db, post()?).await?.0, StatusCode::OK);
+        assert_eq!(send(&db, put()?).await?.0, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_legacy_upload() -> Result<(), anyhow::Error> {
+        let db = test_db()?;
+        // Before uploads were stored as they are, the server inserted them as a Vec<u8>
+        let bucket = db.get_or_create_bucket("uploads")?;
+        let upload = b"plain old upload".to_vec();
+        let id = bucket.insert(upload.clone(), vec![mclabel!("name" => "legacy")])?;
+        bucket.put("legacy.txt", upload.clone(), vec![])?;
+        assert_ne!(bucket.get_raw(id)?.unwrap().to_vec(), upload);
+
+        let (status, body) = send(&db, get(&format!("/buckets/uploads/{id}"))?).await?;
+        assert_eq!((status, body.to_vec()), (StatusCode::OK, upload.clone()));
+        let (status, body) = send(&db, get("/buckets/uploads/keys/legacy.txt")?).await?;
+        assert_eq!((status, body.to_vec()), (StatusCode::OK, upload));
+
+        // Typed documents that aren't byte strings are sent as they are stored
+        let json = db.create_bucket(
+            "typed",
+            BucketSettings {
+                codec: mc5_core::codec::CodecKind::Json,
+                ..Default::default()
+            },
+        )?;
+        let id = json.insert(("pair", 2), vec![])?;
+        let (status, body) = send(&db, get(&format!("/buckets/typed/{id}"))?).await?;
+        assert_eq!(
+            (status, body.to_vec()),
+            (StatusCode::OK, br#"["pair",2]"#.to_vec())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_large() -> Result<(), anyhow::Error> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("uploads")?;
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        let post = Request::post("/buckets/uploads").body(Body::from(big.clone()))?;
+        let (status, id) = send(&db, post).await?;
+        assert_eq!(status, StatusCode::OK);
+        let id = Uuid::from_slice(&id)?;
+        let (status, body) = send(&db, get(&format!("/buckets/uploads/{id}"))?).await?;
+        assert_eq!((status, body.to_vec()), (StatusCode::OK, big.clone()));
+
+        // A body that breaks off leaves nothing behind
+        let frames = futures::stream::iter([
+            Ok(Bytes::from(big)),
+            Err(std::io::Error::other("client went away")),
+        ]);
+        let post = Request::post("/buckets/uploads").body(Body::from_stream(frames))?;
+        assert!(send(&db, post).await?.0.is_server_error());
+        assert_eq!(bucket.stat()?["num_documents"], 1);
+        assert_eq!(bucket.stat()?["num_chunks"], 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_if_match() -> Result<(), anyhow::Error> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("conditional")?;
+        let id = bucket.insert_raw(b"first", vec![])?;
+        let replace = |id: Uuid, condition: Option<&str>| {
+            let request = Request::put(format!("/buckets/conditional/{id}"));
+            let request = match condition {
+                Some(condition) => request.header(header::IF_MATCH, condition),
+                None => request,
+            };
+            request.body(Body::from("next"))
+        };
+
+        assert_eq!(send(&db, replace(id, Some("*"))?).await?.0, StatusCode::OK);
+        let (status, _) = send(&db, replace(id, Some(r#""1", "2""#))?).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bucket.revision(id)?, Some(3));
+        for stale in [r#""2""#, r#"W/"3""#, "three", ""] {
+            let (status, _) = send(&db, replace(id, Some(stale))?).await?;
+            assert_eq!(status, StatusCode::PRECONDITION_FAILED, "{stale}");
+        }
+        let (status, _) = send(&db, replace(id, Some(r#""3""#))?).await?;
+        assert_eq!(status, StatusCode::OK);
+
+        // `*` needs the document to exist, without a condition it is just missing
+        let missing = Uuid::nil();
+        let (status, _) = send(&db, replace(missing, Some("*"))?).await?;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let (status, _) = send(&db, replace(missing, None)?).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_nothing() -> Result<(), anyhow::Error> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("found")?;
+        bucket.insert_raw(b"a", vec![mclabel!("k" => "1")])?;
+        bucket.insert_raw(b"b", vec![mclabel!("k" => "2")])?;
+
+        let (status, body) = send(&db, get("/query/found")?).await?;
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"[]"[..]));
+        let (status, body) = send(&db, get("/query/found?limit=10")?).await?;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &br#"{"ids":[],"next":null}"#[..])
+        );
+        let (status, _) = send(&db, get("/query/found?after=bogus")?).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send(&db, get("/query/found?k=1&limit=10")?).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(br#"{"ids":[""#));
+
+        // A full last page has nothing after it
+        let (status, body) = send(&db, get("/query/found?selector=k&limit=2")?).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.ends_with(br#""next":null}"#));
+
+        // Paging params are ambiguous in a bucket with labels of the same name
+        bucket.insert_raw(b"c", vec![mclabel!("limit" => "5")])?;
+        let (status, _) = send(&db, get("/query/found?limit=5")?).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = send(&db, get("/query/found?selector=limit%3D5")?).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(br#"[""#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_body_schema_violation() -> Result<(), anyhow::Error> {
+        let db = test_db()?;
+        let bucket = db.get_or_create_bucket("checked")?;
+        let schema = || {
+            Request::put("/buckets/checked/body_schema")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    r#"{"type": "object", "properties": {"x": {"type": "integer"}}}"#,
+                ))
+        };
+        // Uploads are read as JSON, so the bucket has to store JSON too
+        assert_eq!(send(&db, schema()?).await?.0, StatusCode::BAD_REQUEST);
+        bucket.set_settings(BucketSettings {
+            codec: mc5_core::codec::CodecKind::Json,
+            ..bucket.settings()?
+        })?;
+        assert_eq!(send(&db, schema()?).await?.0, StatusCode::OK);
+
+        let post = |body: &'static str| Request::post("/buckets/checked").body(Body::from(body));
+        let (status, body) = send(&db, post(r#"{"x": "one"}"#)?).await?;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        assert!(body["error"].is_string());
+        assert_eq!(body["violations"][0]["path"], "/x");
+        assert_eq!(body["violations"][0]["schema_path"], "/properties/x/type");
+        assert_eq!(send(&db, post(r#"{"x": 1}"#)?).await?.0, StatusCode::OK);
+        assert_eq!(bucket.usage()?.documents, 1);
+        Ok(())
+    }
+}